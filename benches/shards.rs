@@ -0,0 +1,61 @@
+//! Write throughput by connection count, with a single shard and with the default
+//! number of shards. Run with `cargo bench --bench shards`.
+//!
+//! Connections only run in parallel on as many cores, and every write still appends to
+//! the one log, so the numbers compare the two shard counts on the machine at hand rather
+//! than show throughput scaling with connections. The core count is printed with them.
+
+use std::path::PathBuf;
+use std::time::Instant;
+
+use tyozo::utils::fs_utils::open_or_create_file;
+use tyozo::{Executor, Locks, Memdb, DEFAULT_SHARDS};
+
+const WRITES_PER_CONNECTION: usize = 20_000;
+
+fn executor(name: &str, shards: usize) -> Executor {
+    let dir: PathBuf = std::env::temp_dir().join(format!("tyozo-bench-{}", name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let log_file = open_or_create_file(dir.join("tyozo.log").to_str().unwrap()).unwrap();
+    let db_file = open_or_create_file(dir.join("tyozo.db").to_str().unwrap()).unwrap();
+
+    Executor::with_shards(log_file, db_file, Memdb::new(), Locks::new(), shards)
+}
+
+fn bench(shards: usize, connections: usize) -> f64 {
+    let executor = executor(&format!("{}-{}", shards, connections), shards);
+
+    let start = Instant::now();
+    let handles = (0..connections)
+        .map(|c| {
+            let mut executor = executor.clone();
+            std::thread::spawn(move || {
+                for i in 0..WRITES_PER_CONNECTION {
+                    executor.exec(format!("set key{}-{} {}", c, i, i)).unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    handles.into_iter().for_each(|h| h.join().unwrap());
+
+    (connections * WRITES_PER_CONNECTION) as f64 / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+    println!("cores: {}", cores);
+    println!("{:>8} {:>12} {:>14}", "shards", "connections", "writes/sec");
+
+    for shards in [1, DEFAULT_SHARDS] {
+        for connections in [1, 2, 4, 8] {
+            println!(
+                "{:>8} {:>12} {:>14.0}",
+                shards,
+                connections,
+                bench(shards, connections)
+            );
+        }
+    }
+}
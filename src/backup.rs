@@ -0,0 +1,98 @@
+use crate::memdb::Memdb;
+
+/// A consistent snapshot of a running server's `Memdb` together with the length
+/// of the write-ahead log at the moment the snapshot was taken.
+///
+/// Every log record before `wal_position` is already reflected in `snapshot`,
+/// so a server seeded from it only has to replay the log from that offset.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Backup {
+    pub wal_position: u64,
+    pub snapshot: Vec<u8>,
+}
+
+impl Backup {
+    pub fn new(wal_position: u64, snapshot: Vec<u8>) -> Backup {
+        Backup {
+            wal_position,
+            snapshot,
+        }
+    }
+
+    /// Encodes the backup as a single protocol line.
+    ///
+    /// # Example
+    /// ```
+    /// use tyozo::Backup;
+    ///
+    /// let backup = Backup::new(42, vec![0, 1, 255]);
+    /// assert_eq!(backup.encode(), "42 0001ff");
+    /// assert_eq!(Backup::decode("42 0001ff"), Ok(backup));
+    /// ```
+    pub fn encode(&self) -> String {
+        let hex = self
+            .snapshot
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+
+        format!("{} {}", self.wal_position, hex)
+    }
+
+    pub fn decode(input: &str) -> Result<Backup, String> {
+        let mut parts = input.trim().splitn(2, ' ');
+
+        let wal_position = parts
+            .next()
+            .and_then(|p| p.parse().ok())
+            .ok_or_else(|| String::from("ERR invalid backup format"))?;
+
+        let hex = parts.next().unwrap_or("");
+        if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+            return Err(String::from("ERR invalid backup format"));
+        }
+
+        let snapshot = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| String::from("ERR invalid backup format"))?;
+
+        Ok(Backup::new(wal_position, snapshot))
+    }
+
+    /// Layout of a backup file: the WAL position as 8 big endian bytes,
+    /// followed by the snapshot in the same format as the database file.
+    ///
+    /// # Example
+    /// ```
+    /// use tyozo::Backup;
+    ///
+    /// let backup = Backup::new(1, vec![7]);
+    /// assert_eq!(backup.to_bytes(), vec![0, 0, 0, 0, 0, 0, 0, 1, 7]);
+    /// assert_eq!(Backup::from_bytes(&backup.to_bytes()), Ok(backup));
+    /// ```
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = self.wal_position.to_be_bytes().to_vec();
+        buf.extend_from_slice(&self.snapshot);
+        buf
+    }
+
+    pub fn from_bytes(input: &[u8]) -> Result<Backup, String> {
+        if input.len() < 8 {
+            return Err(String::from("ERR invalid backup format"));
+        }
+
+        let mut position = [0u8; 8];
+        position.copy_from_slice(&input[..8]);
+
+        Ok(Backup::new(
+            u64::from_be_bytes(position),
+            input[8..].to_vec(),
+        ))
+    }
+
+    pub fn memdb(&self) -> Result<Memdb, String> {
+        Memdb::deserialize(&self.snapshot)
+    }
+}
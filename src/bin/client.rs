@@ -2,9 +2,21 @@ use std::io::prelude::*;
 use std::io::Write;
 use std::net::TcpStream;
 
+use tyozo::Backup;
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut stream = TcpStream::connect("127.0.0.1:3333")?;
 
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(i) = args.iter().position(|arg| arg == "--backup") {
+        let path = match args.get(i + 1) {
+            None => return Err("usage: tyozo-cli --backup <file>".into()),
+            Some(path) => path,
+        };
+
+        return backup(stream, path);
+    }
+
     loop {
         print!(">> ");
         std::io::stdout().flush().unwrap();
@@ -31,6 +43,26 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+fn backup(mut stream: TcpStream, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    writeln!(stream, "backup")?;
+    stream.flush()?;
+
+    let mut buf = String::new();
+    std::io::BufReader::new(&stream).read_line(&mut buf)?;
+
+    let backup = Backup::decode(&buf)?;
+    std::fs::write(path, backup.to_bytes())?;
+
+    println!(
+        "backup {} bytes at log position {} to {}",
+        backup.snapshot.len(),
+        backup.wal_position,
+        path
+    );
+
+    Ok(())
+}
+
 fn read<T: std::str::FromStr>() -> T {
     let mut s = String::new();
     std::io::stdin().read_line(&mut s).ok();
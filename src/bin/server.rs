@@ -6,14 +6,17 @@ use ansi_term::Colour::Red;
 use std::io::prelude::*;
 use std::io::Write;
 use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
 
 use tyozo::utils::fs_utils::open_or_create_file;
-use tyozo::Executor;
 use tyozo::Locks;
 use tyozo::Memdb;
+use tyozo::{Executor, TransactionLimits, DEFAULT_SHARDS};
 
 const DB_FILE_PATH: &str = "./tyozo.db";
 const LOG_FILE_PATH: &str = "./tyozo.log";
+// Redis と同じく 1秒に10回、期限切れの key を削除する
+const EXPIRE_CYCLE_INTERVAL: Duration = Duration::from_millis(100);
 
 fn handle_client(
     mut stream: TcpStream,
@@ -40,16 +43,91 @@ fn handle_client(
     }
 }
 
+fn option(name: &str) -> Option<String> {
+    let args: Vec<String> = std::env::args().collect();
+
+    args.iter()
+        .position(|arg| arg == name)
+        .and_then(|i| args.get(i + 1).cloned())
+}
+
+/// `tyozo-server --seed <backup file> [--catch-up <log file>]`
+fn seed_options() -> Option<(String, Option<String>)> {
+    option("--seed").map(|seed| (seed, option("--catch-up")))
+}
+
+/// `tyozo-server --lock-timeout <milliseconds>`
+fn locks() -> Result<Locks, Box<dyn std::error::Error>> {
+    match option("--lock-timeout") {
+        None => Ok(Locks::new()),
+        Some(millis) => Ok(Locks::with_timeout(Duration::from_millis(millis.parse()?))),
+    }
+}
+
+/// `tyozo-server --shards <count>`
+fn shards() -> Result<usize, Box<dyn std::error::Error>> {
+    match option("--shards") {
+        None => Ok(DEFAULT_SHARDS),
+        Some(count) => Ok(count.parse()?),
+    }
+}
+
+/// `tyozo-server --max-transaction-commands <count> --max-transaction-bytes <bytes>
+/// --max-transaction-time <milliseconds>`
+fn transaction_limits() -> Result<TransactionLimits, Box<dyn std::error::Error>> {
+    Ok(TransactionLimits {
+        max_commands: option("--max-transaction-commands")
+            .map(|count| count.parse())
+            .transpose()?,
+        max_bytes: option("--max-transaction-bytes")
+            .map(|bytes| bytes.parse())
+            .transpose()?,
+        max_duration: option("--max-transaction-time")
+            .map(|millis| millis.parse().map(Duration::from_millis))
+            .transpose()?,
+    })
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
 
     let db_file = open_or_create_file(DB_FILE_PATH)?;
     let log_file = open_or_create_file(LOG_FILE_PATH)?;
-    let db = Memdb::restore(DB_FILE_PATH, LOG_FILE_PATH)?;
+    let db = match seed_options() {
+        None => Memdb::restore(DB_FILE_PATH, LOG_FILE_PATH)?,
+        Some((backup_file_path, catch_up_log_path)) => {
+            info!("seeding database from {}", backup_file_path);
+            Memdb::seed(
+                &backup_file_path,
+                catch_up_log_path.as_deref(),
+                DB_FILE_PATH,
+                LOG_FILE_PATH,
+            )?
+        }
+    };
 
     let listener = TcpListener::bind("127.0.0.1:3333")?;
 
-    let executor = Executor::new(log_file, db_file, db, Locks::new());
+    let limits = transaction_limits()?;
+    let executor =
+        Executor::with_shards(log_file, db_file, db, locks()?, shards()?).with_limits(limits);
+
+    if let Some(max_duration) = limits.max_duration {
+        let reaper = executor.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep((max_duration / 10).max(Duration::from_millis(1)));
+            reaper.reap_transactions();
+        });
+    }
+
+    let expirer = executor.clone();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(EXPIRE_CYCLE_INTERVAL);
+        let removed = expirer.expire_keys();
+        if removed > 0 {
+            debug!("removed {} expired keys", removed);
+        }
+    });
 
     for stream in listener.incoming() {
         let executor = executor.clone();
@@ -0,0 +1,141 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::Instant;
+
+/// Identifies a client parked by a blocking pop.
+pub(crate) type WaiterId = u64;
+
+/// Clients parked by blocking list pops, queued per key in the order they started
+/// waiting.
+///
+/// A write to a key wakes only the longest waiting client on it. That client retries
+/// its pop, and once it is done wakes the next one, so elements are handed out in FIFO
+/// order even when a single push adds several of them.
+#[derive(Default, Debug)]
+pub(crate) struct BlockedClients {
+    table: Mutex<BlockedTable>,
+    condvar: Condvar,
+    // 登録されている client の数。0 なら書き込みのたびに table を lock しなくて済む
+    count: AtomicUsize,
+}
+
+#[derive(Default, Debug)]
+struct BlockedTable {
+    next_id: WaiterId,
+    // key ごとに、その key を待っている client が待ち始めた順に並ぶ
+    queues: HashMap<String, VecDeque<WaiterId>>,
+    keys: HashMap<WaiterId, Vec<String>>,
+    // 起こされたが、まだ目を覚ましていない client
+    woken: HashSet<WaiterId>,
+}
+
+impl BlockedTable {
+    fn wake(&mut self, key: &str) -> bool {
+        match self.queues.get(key).and_then(VecDeque::front) {
+            Some(id) => self.woken.insert(*id),
+            None => false,
+        }
+    }
+}
+
+impl BlockedClients {
+    /// Queues a client behind everyone already waiting on `keys`.
+    ///
+    /// Must be called while holding the shard locks of `keys`, so that a push to them
+    /// is either seen by the pop the caller just tried or wakes the caller.
+    pub fn register(&self, keys: &[&str]) -> WaiterId {
+        let mut table = self.table.lock().unwrap();
+        let id = table.next_id;
+        table.next_id += 1;
+
+        for key in keys {
+            table
+                .queues
+                .entry(key.to_string())
+                .or_default()
+                .push_back(id);
+        }
+        table
+            .keys
+            .insert(id, keys.iter().map(|key| key.to_string()).collect());
+        self.count.fetch_add(1, Ordering::SeqCst);
+
+        id
+    }
+
+    /// Removes the client from every queue it is on, once it has popped an element or
+    /// given up.
+    pub fn unregister(&self, id: WaiterId) {
+        let mut table = self.table.lock().unwrap();
+        let keys = match table.keys.remove(&id) {
+            Some(keys) => keys,
+            None => return,
+        };
+
+        for key in &keys {
+            let queue = table.queues.get_mut(key).unwrap();
+            queue.retain(|waiter| *waiter != id);
+            if queue.is_empty() {
+                table.queues.remove(key);
+            }
+        }
+        self.count.fetch_sub(1, Ordering::SeqCst);
+
+        // 起こされたのに pop せずに抜ける場合は、代わりに次の client を起こす
+        if table.woken.remove(&id) {
+            let woken = keys
+                .iter()
+                .fold(false, |woken, key| table.wake(key) | woken);
+            if woken {
+                self.condvar.notify_all();
+            }
+        }
+    }
+
+    /// Parks until the client is woken by `wake` or `deadline` passes, and returns false
+    /// on timeout.
+    pub fn wait(&self, id: WaiterId, deadline: Option<Instant>) -> bool {
+        let mut table = self.table.lock().unwrap();
+
+        loop {
+            if table.woken.remove(&id) {
+                return true;
+            }
+
+            table = match deadline {
+                None => self.condvar.wait(table).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    self.condvar.wait_timeout(table, deadline - now).unwrap().0
+                }
+            };
+        }
+    }
+
+    /// How many clients are parked or about to park, as shown by `info`.
+    pub fn len(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    /// Wakes the longest waiting client on each of `keys`. Writers call this after
+    /// changing `keys`, while still holding their shard locks.
+    pub fn wake<'a>(&self, keys: impl IntoIterator<Item = &'a str>) {
+        if self.count.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+
+        let mut table = self.table.lock().unwrap();
+        let mut woken = false;
+        for key in keys {
+            woken |= table.wake(key);
+        }
+
+        if woken {
+            self.condvar.notify_all();
+        }
+    }
+}
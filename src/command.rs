@@ -1,29 +1,588 @@
+use std::fmt;
+use std::time::Duration;
+
+use crate::expire::Expire;
+use crate::locks::LockWait;
+use crate::parser;
+use crate::transaction::TransactionOptions;
+
 #[derive(PartialEq, PartialOrd, Debug, Clone)]
 pub enum Command {
-    Set { key: String, value: String },
-    SetNX { key: String, value: String },
-    Get { key: String },
-    Del { keys: Vec<String> },
-    Multi,
+    Set {
+        key: String,
+        value: Vec<u8>,
+        expire: Option<Expire>,
+        condition: Option<SetCondition>,
+        /// Reply with the old value instead of `OK`.
+        get: bool,
+    },
+    SetNX {
+        key: String,
+        value: Vec<u8>,
+    },
+    Get {
+        key: String,
+    },
+    GetDel {
+        key: String,
+    },
+    /// Replaces the value only if it currently equals `expected`.
+    Cas {
+        key: String,
+        expected: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Del {
+        keys: Vec<String>,
+    },
+    MGet {
+        keys: Vec<String>,
+    },
+    Exists {
+        keys: Vec<String>,
+    },
+    Type {
+        key: String,
+    },
+    Rename {
+        key: String,
+        newkey: String,
+    },
+    RenameNX {
+        key: String,
+        newkey: String,
+    },
+    Copy {
+        source: String,
+        destination: String,
+        replace: bool,
+    },
+    DbSize,
+    FlushDb,
+    Keys {
+        pattern: String,
+    },
+    /// Returns the next cursor followed by the keys found, see `Keyspace::scan`.
+    Scan {
+        cursor: u64,
+        pattern: Option<String>,
+        count: usize,
+    },
+    MSet {
+        pairs: Vec<(String, Vec<u8>)>,
+    },
+    MSetNX {
+        pairs: Vec<(String, Vec<u8>)>,
+    },
+    IncrBy {
+        key: String,
+        increment: i64,
+    },
+    IncrByFloat {
+        key: String,
+        increment: f64,
+    },
+    Append {
+        key: String,
+        value: Vec<u8>,
+    },
+    StrLen {
+        key: String,
+    },
+    GetRange {
+        key: String,
+        start: i64,
+        end: i64,
+    },
+    SetRange {
+        key: String,
+        offset: usize,
+        value: Vec<u8>,
+    },
+    LPush {
+        key: String,
+        elements: Vec<Vec<u8>>,
+    },
+    RPush {
+        key: String,
+        elements: Vec<Vec<u8>>,
+    },
+    /// Without `count` a single element is popped, otherwise an array of up to `count`.
+    LPop {
+        key: String,
+        count: Option<usize>,
+    },
+    RPop {
+        key: String,
+        count: Option<usize>,
+    },
+    LRange {
+        key: String,
+        start: i64,
+        stop: i64,
+    },
+    LLen {
+        key: String,
+    },
+    LIndex {
+        key: String,
+        index: i64,
+    },
+    LSet {
+        key: String,
+        index: i64,
+        element: Vec<u8>,
+    },
+    /// Removes `count` occurrences from the head, or from the tail when `count` is
+    /// negative, and every occurrence when it is 0.
+    LRem {
+        key: String,
+        count: i64,
+        element: Vec<u8>,
+    },
+    LTrim {
+        key: String,
+        start: i64,
+        stop: i64,
+    },
+    /// Pops from the first non-empty list in `keys`, waiting up to `timeout` (forever
+    /// when `None`) for an element to be pushed when they are all empty.
+    BLPop {
+        keys: Vec<String>,
+        timeout: Option<Duration>,
+    },
+    BRPop {
+        keys: Vec<String>,
+        timeout: Option<Duration>,
+    },
+    /// Moves an element from one end of `source` to one end of `destination`, waiting
+    /// like `BLPop` while `source` is empty.
+    BLMove {
+        source: String,
+        destination: String,
+        from: ListEnd,
+        to: ListEnd,
+        timeout: Option<Duration>,
+    },
+    Expire {
+        key: String,
+        expire: Expire,
+    },
+    Ttl {
+        key: String,
+    },
+    PTtl {
+        key: String,
+    },
+    Persist {
+        key: String,
+    },
+    Multi(TransactionOptions),
+    Watch {
+        keys: Vec<String>,
+    },
+    Unwatch,
     Exec,
     Abort,
+    Savepoint {
+        name: String,
+    },
+    RollbackTo {
+        name: String,
+    },
+    Release {
+        name: String,
+    },
     Shutdown,
+    Backup,
+    LocksList {
+        pattern: Option<String>,
+    },
+    LocksWaiters,
+    Info,
+}
+
+/// `set ... nx` writes only a missing key and `set ... xx` only an existing one.
+#[derive(PartialEq, PartialOrd, Debug, Clone, Copy)]
+pub enum SetCondition {
+    NotExists,
+    Exists,
+}
+
+/// The head (`left`) or tail (`right`) of a list.
+#[derive(PartialEq, PartialOrd, Debug, Clone, Copy)]
+pub enum ListEnd {
+    Left,
+    Right,
+}
+
+impl Command {
+    /// True for commands that never modify the database. They don't need to be logged
+    /// and can run with a shared lock on `Memdb`.
+    pub fn is_readonly(&self) -> bool {
+        matches!(
+            self,
+            Command::Get { .. }
+                | Command::MGet { .. }
+                | Command::Exists { .. }
+                | Command::Type { .. }
+                | Command::DbSize
+                | Command::Keys { .. }
+                | Command::Scan { .. }
+                | Command::LRange { .. }
+                | Command::LLen { .. }
+                | Command::LIndex { .. }
+                | Command::StrLen { .. }
+                | Command::GetRange { .. }
+                | Command::Ttl { .. }
+                | Command::PTtl { .. }
+        )
+    }
+
+    /// The keys the command reads or writes.
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            Command::Set { key, .. }
+            | Command::SetNX { key, .. }
+            | Command::Get { key }
+            | Command::Type { key }
+            | Command::GetDel { key }
+            | Command::Cas { key, .. }
+            | Command::Expire { key, .. }
+            | Command::Ttl { key }
+            | Command::PTtl { key }
+            | Command::Persist { key }
+            | Command::IncrBy { key, .. }
+            | Command::IncrByFloat { key, .. }
+            | Command::Append { key, .. }
+            | Command::StrLen { key }
+            | Command::GetRange { key, .. }
+            | Command::SetRange { key, .. }
+            | Command::LPush { key, .. }
+            | Command::RPush { key, .. }
+            | Command::LPop { key, .. }
+            | Command::RPop { key, .. }
+            | Command::LRange { key, .. }
+            | Command::LLen { key }
+            | Command::LIndex { key, .. }
+            | Command::LSet { key, .. }
+            | Command::LRem { key, .. }
+            | Command::LTrim { key, .. } => vec![key],
+            Command::Del { keys }
+            | Command::BLPop { keys, .. }
+            | Command::BRPop { keys, .. }
+            | Command::MGet { keys }
+            | Command::Exists { keys }
+            | Command::Watch { keys } => keys.iter().map(String::as_str).collect(),
+            Command::Rename { key, newkey } | Command::RenameNX { key, newkey } => {
+                vec![key, newkey]
+            }
+            Command::Copy {
+                source,
+                destination,
+                ..
+            }
+            | Command::BLMove {
+                source,
+                destination,
+                ..
+            } => vec![source, destination],
+            Command::MSet { pairs } | Command::MSetNX { pairs } => {
+                pairs.iter().map(|(key, _)| key.as_str()).collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    /// True for the commands on lists, which are run by `list::exec_command`.
+    pub(crate) fn is_list_command(&self) -> bool {
+        matches!(
+            self,
+            Command::LPush { .. }
+                | Command::RPush { .. }
+                | Command::LPop { .. }
+                | Command::RPop { .. }
+                | Command::LRange { .. }
+                | Command::LLen { .. }
+                | Command::LIndex { .. }
+                | Command::LSet { .. }
+                | Command::LRem { .. }
+                | Command::LTrim { .. }
+                | Command::BLPop { .. }
+                | Command::BRPop { .. }
+                | Command::BLMove { .. }
+        )
+    }
+
+    /// The keys a blocking pop waits on for an element to be pushed, or an empty list
+    /// for commands that never block.
+    pub(crate) fn blocking_keys(&self) -> Vec<&str> {
+        match self {
+            Command::BLPop { keys, .. } | Command::BRPop { keys, .. } => {
+                keys.iter().map(String::as_str).collect()
+            }
+            Command::BLMove { source, .. } => vec![source],
+            _ => vec![],
+        }
+    }
+
+    /// True for commands that read or write every key rather than the ones in `keys`.
+    pub fn touches_all_keys(&self) -> bool {
+        matches!(
+            self,
+            Command::DbSize | Command::FlushDb | Command::Keys { .. } | Command::Scan { .. }
+        )
+    }
+
+    /// Replaces relative expiration times with deadlines computed from `now`, so the
+    /// command expires keys at the same time when it is replayed from the log.
+    pub(crate) fn resolve_expire(self, now: u64) -> Command {
+        match self {
+            Command::Set {
+                key,
+                value,
+                expire: Some(expire),
+                condition,
+                get,
+            } => Command::Set {
+                key,
+                value,
+                expire: Some(expire.at(now)),
+                condition,
+                get,
+            },
+            Command::Expire { key, expire } => Command::Expire {
+                key,
+                expire: expire.at(now),
+            },
+            command => command,
+        }
+    }
 }
 
-impl ToString for Command {
-    fn to_string(&self) -> String {
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         use self::Command::*;
 
         match self {
-            Set { key, value } => format!("set {} {}", key, value),
-            SetNX { key, value } => format!("setnx {} {}", key, value),
-            Get { key } => format!("get {}", key),
-            Del { keys } => format!(
-                "del {}",
-                keys.iter()
-                    .fold(String::new(), |acc, s| format!("{} {}", acc, s))
+            Set {
+                key,
+                value,
+                expire,
+                condition,
+                get,
+            } => {
+                write!(f, "set {} {}", quote(key), quote(value))?;
+                match condition {
+                    None => (),
+                    Some(SetCondition::NotExists) => write!(f, " nx")?,
+                    Some(SetCondition::Exists) => write!(f, " xx")?,
+                }
+                if *get {
+                    write!(f, " get")?;
+                }
+                match expire {
+                    None => Ok(()),
+                    Some(crate::expire::Expire::After(millis)) => write!(f, " px {}", millis),
+                    Some(crate::expire::Expire::At(millis)) => write!(f, " pxat {}", millis),
+                }
+            }
+            SetNX { key, value } => write!(f, "setnx {} {}", quote(key), quote(value)),
+            Get { key } => write!(f, "get {}", quote(key)),
+            GetDel { key } => write!(f, "getdel {}", quote(key)),
+            Cas {
+                key,
+                expected,
+                value,
+            } => write!(f, "cas {} {} {}", quote(key), quote(expected), quote(value)),
+            Del { keys } => write!(f, "del {}", quote_all(keys)),
+            MGet { keys } => write!(f, "mget {}", quote_all(keys)),
+            MSet { pairs } => write!(f, "mset {}", quote_pairs(pairs)),
+            Exists { keys } => write!(f, "exists {}", quote_all(keys)),
+            Type { key } => write!(f, "type {}", quote(key)),
+            Rename { key, newkey } => write!(f, "rename {} {}", quote(key), quote(newkey)),
+            RenameNX { key, newkey } => write!(f, "renamenx {} {}", quote(key), quote(newkey)),
+            Copy {
+                source,
+                destination,
+                replace,
+            } => {
+                write!(f, "copy {} {}", quote(source), quote(destination))?;
+                if *replace {
+                    write!(f, " replace")?;
+                }
+                Ok(())
+            }
+            DbSize => write!(f, "dbsize"),
+            FlushDb => write!(f, "flushdb"),
+            Keys { pattern } => write!(f, "keys {}", quote(pattern)),
+            Scan {
+                cursor,
+                pattern,
+                count,
+            } => {
+                write!(f, "scan {}", cursor)?;
+                if let Some(pattern) = pattern {
+                    write!(f, " match {}", quote(pattern))?;
+                }
+                write!(f, " count {}", count)
+            }
+            MSetNX { pairs } => write!(f, "msetnx {}", quote_pairs(pairs)),
+            Command::Expire {
+                key,
+                expire: crate::expire::Expire::After(millis),
+            } => write!(f, "pexpire {} {}", quote(key), millis),
+            Command::Expire {
+                key,
+                expire: crate::expire::Expire::At(millis),
+            } => write!(f, "pexpireat {} {}", quote(key), millis),
+            IncrBy { key, increment } => write!(f, "incrby {} {}", quote(key), increment),
+            IncrByFloat { key, increment } => {
+                write!(f, "incrbyfloat {} {}", quote(key), increment)
+            }
+            Append { key, value } => write!(f, "append {} {}", quote(key), quote(value)),
+            StrLen { key } => write!(f, "strlen {}", quote(key)),
+            GetRange { key, start, end } => write!(f, "getrange {} {} {}", quote(key), start, end),
+            SetRange { key, offset, value } => {
+                write!(f, "setrange {} {} {}", quote(key), offset, quote(value))
+            }
+            LPush { key, elements } => write!(f, "lpush {} {}", quote(key), quote_all(elements)),
+            RPush { key, elements } => write!(f, "rpush {} {}", quote(key), quote_all(elements)),
+            LPop { key, count: None } => write!(f, "lpop {}", quote(key)),
+            LPop {
+                key,
+                count: Some(count),
+            } => write!(f, "lpop {} {}", quote(key), count),
+            RPop { key, count: None } => write!(f, "rpop {}", quote(key)),
+            RPop {
+                key,
+                count: Some(count),
+            } => write!(f, "rpop {} {}", quote(key), count),
+            LRange { key, start, stop } => write!(f, "lrange {} {} {}", quote(key), start, stop),
+            LLen { key } => write!(f, "llen {}", quote(key)),
+            LIndex { key, index } => write!(f, "lindex {} {}", quote(key), index),
+            LSet {
+                key,
+                index,
+                element,
+            } => write!(f, "lset {} {} {}", quote(key), index, quote(element)),
+            LRem {
+                key,
+                count,
+                element,
+            } => write!(f, "lrem {} {} {}", quote(key), count, quote(element)),
+            LTrim { key, start, stop } => write!(f, "ltrim {} {} {}", quote(key), start, stop),
+            BLPop { keys, timeout } => {
+                write!(f, "blpop {} {}", quote_all(keys), seconds(timeout))
+            }
+            BRPop { keys, timeout } => {
+                write!(f, "brpop {} {}", quote_all(keys), seconds(timeout))
+            }
+            BLMove {
+                source,
+                destination,
+                from,
+                to,
+                timeout,
+            } => write!(
+                f,
+                "blmove {} {} {} {} {}",
+                quote(source),
+                quote(destination),
+                from,
+                to,
+                seconds(timeout)
             ),
-            _ => todo!(),
+            Ttl { key } => write!(f, "ttl {}", quote(key)),
+            PTtl { key } => write!(f, "pttl {}", quote(key)),
+            Persist { key } => write!(f, "persist {}", quote(key)),
+            Multi(options) => {
+                write!(f, "multi")?;
+                if options.queued {
+                    write!(f, " queued")?;
+                }
+                if options.snapshot {
+                    write!(f, " snapshot")?;
+                }
+                if options.readonly {
+                    write!(f, " readonly")?;
+                }
+                match options.lock_wait {
+                    LockWait::Default => Ok(()),
+                    LockWait::Timeout(timeout) => write!(f, " timeout {}", timeout.as_millis()),
+                    LockWait::NoWait => write!(f, " nowait"),
+                }
+            }
+            Watch { keys } => write!(f, "watch {}", quote_all(keys)),
+            Unwatch => write!(f, "unwatch"),
+            Exec => write!(f, "exec"),
+            Abort => write!(f, "abort"),
+            Savepoint { name } => write!(f, "savepoint {}", quote(name)),
+            RollbackTo { name } => write!(f, "rollback to {}", quote(name)),
+            Release { name } => write!(f, "release {}", quote(name)),
+            Shutdown => write!(f, "shutdown"),
+            Backup => write!(f, "backup"),
+            LocksList { pattern: None } => write!(f, "locks list"),
+            LocksList {
+                pattern: Some(pattern),
+            } => write!(f, "locks list {}", quote(pattern)),
+            LocksWaiters => write!(f, "locks waiters"),
+            Info => write!(f, "info"),
+        }
+    }
+}
+
+impl fmt::Display for ListEnd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListEnd::Left => write!(f, "left"),
+            ListEnd::Right => write!(f, "right"),
+        }
+    }
+}
+
+/// Wraps an argument in double quotes, escaping `"`, `\`, line breaks and bytes that are
+/// not UTF-8 (as `\xNN`), when the parser would otherwise split it or drop some of its
+/// characters, so the logged command is replayed with the same arguments.
+fn quote<A: AsRef<[u8]>>(arg: A) -> String {
+    let arg = arg.as_ref();
+    if !arg.is_empty() && arg.iter().all(|b| parser::is_letter(char::from(*b))) {
+        return String::from_utf8_lossy(arg).into_owned();
+    }
+
+    let mut quoted = String::from("\"");
+    for chunk in arg.utf8_chunks() {
+        for ch in chunk.valid().chars() {
+            match ch {
+                '"' => quoted.push_str("\\\""),
+                '\\' => quoted.push_str("\\\\"),
+                '\n' => quoted.push_str("\\n"),
+                '\r' => quoted.push_str("\\r"),
+                ch => quoted.push(ch),
+            }
+        }
+        for byte in chunk.invalid() {
+            quoted.push_str(&format!("\\x{:02x}", byte));
         }
     }
+    quoted.push('"');
+
+    quoted
+}
+
+fn quote_pairs(pairs: &[(String, Vec<u8>)]) -> String {
+    pairs
+        .iter()
+        .map(|(key, value)| format!("{} {}", quote(key), quote(value)))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn quote_all<A: AsRef<[u8]>>(args: &[A]) -> String {
+    args.iter().map(quote).collect::<Vec<_>>().join(" ")
+}
+
+// blocking pop の timeout は Redis と同じく秒で書き、0 は無期限
+fn seconds(timeout: &Option<Duration>) -> f64 {
+    timeout.map_or(0.0, |timeout| timeout.as_secs_f64())
 }
@@ -1,88 +1,262 @@
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Write;
-use std::sync::{Arc, Mutex, RwLock};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
+use crate::backup::Backup;
+use crate::blocking::BlockedClients;
 use crate::command::Command;
-use crate::locks::Locks;
+use crate::expire;
+use crate::keyspace::{self, Keyspace};
+use crate::list;
+use crate::locks::{LockMode, LockWait, Locks, TxId, LOCKTIMEOUT_ERROR};
 use crate::memdb::Memdb;
 use crate::parser;
-use crate::transaction::Transaction;
+use crate::reply;
+use crate::shards::{ShardGuards, Shards, DEFAULT_SHARDS};
+use crate::snapshot::SnapshotTransaction;
+use crate::transaction::{Transaction, TransactionLimits, TXN_TIMEOUT_ERROR, TXN_TOO_LARGE_ERROR};
 
 pub struct Executor {
     inner: Arc<ExecutorInner>,
+    // cloneされるごとに振られる、接続を識別するid
+    connection: u64,
     mode: Mode,
     transaction: Transaction,
+    queue: CommandQueue,
+    snapshot: Option<SnapshotTransaction>,
+    // watchしているkeyと、watchした時点での version
+    watched: HashMap<String, u64>,
+    limits: TransactionLimits,
+    // multi されてからの経過時間と送られたcommandの量
+    usage: Option<TransactionUsage>,
 }
 
 struct ExecutorInner {
-    log_file: Mutex<File>,
+    // log fileは append modeで開かれているので、一度のwriteで書いた行は他の書き込みと混ざらない。
+    // logに書くのは書き込むkeyのshardの write lockを持っている間だけで、
+    // logの長さの取得や切り詰めは全てのshardのlockを取ってから行うので、別にlockは必要ない
+    log_file: File,
     db_file: Mutex<File>,
-    locks: Mutex<Locks>,
-    memdb: RwLock<Memdb>,
+    locks: Locks,
+    shards: Shards,
+    transaction_ids: AtomicU64,
+    connection_ids: AtomicU64,
+    // 実行中の transaction。snapshot transaction も max_duration で reap するために含める
+    transactions: Mutex<HashMap<TxId, OpenTransaction>>,
+    // blocking pop で要素が追加されるのを待っている client
+    blocked: BlockedClients,
+}
+
+#[derive(Debug)]
+struct OpenTransaction {
+    connection: u64,
+    // max_duration が設定されている場合に reap される時刻
+    deadline: Option<Instant>,
+    // snapshot transaction の場合、reap する時に開放する snapshot の timestamp
+    snapshot: Option<u64>,
 }
 
 #[derive(Debug)]
 enum Mode {
     Nornal,
     Transaction,
+    Queued,
+    Snapshot,
+}
+
+/// Commands sent after `multi queued`, run together at `exec`.
+#[derive(Debug, Default)]
+struct CommandQueue {
+    commands: Vec<Command>,
+    // 不正なcommandが送られた場合、execは何も実行せずに失敗する
+    failed: bool,
+}
+
+#[derive(Debug)]
+struct TransactionUsage {
+    started: Instant,
+    commands: usize,
+    bytes: usize,
+}
+
+/// Write locks a write outside a lock-based transaction holds in `Locks`, released on drop.
+struct KeyLocks<'a> {
+    locks: &'a Locks,
+    owner: TxId,
+    keys: Vec<String>,
 }
 
+const EXECABORT_ERROR: &str = "EXECABORT Transaction discarded because of previous errors";
+
 impl Executor {
     pub fn new(log_file: File, db_file: File, memdb: Memdb, locks: Locks) -> Executor {
-        let log_file = Mutex::new(log_file);
+        Executor::with_shards(log_file, db_file, memdb, locks, DEFAULT_SHARDS)
+    }
+
+    /// Splits the keyspace into `shards` partitions, each with its own lock.
+    pub fn with_shards(
+        log_file: File,
+        db_file: File,
+        memdb: Memdb,
+        locks: Locks,
+        shards: usize,
+    ) -> Executor {
         let db_file = Mutex::new(db_file);
-        let locks = Mutex::new(locks);
-        let memdb = RwLock::new(memdb);
+        let shards = Shards::new(memdb, shards);
 
         let inner = Arc::new(ExecutorInner {
             log_file,
             db_file,
             locks,
-            memdb,
+            shards,
+            transaction_ids: AtomicU64::new(1),
+            connection_ids: AtomicU64::new(1),
+            transactions: Mutex::new(HashMap::new()),
+            blocked: BlockedClients::default(),
         });
 
         let mode = Mode::Nornal;
-        let transaction = Transaction::new();
+        let transaction = Transaction::default();
 
         Executor {
+            connection: inner.next_connection_id(),
             inner,
             mode,
             transaction,
+            queue: CommandQueue::default(),
+            snapshot: None,
+            watched: HashMap::new(),
+            limits: TransactionLimits::default(),
+            usage: None,
         }
     }
 
+    /// Aborts transactions that exceed `limits`. The limits are shared by clones made
+    /// afterwards, i.e. by every connection when set before accepting any.
+    pub fn with_limits(mut self, limits: TransactionLimits) -> Executor {
+        self.limits = limits;
+        self
+    }
+
     pub fn exec<S: Into<String>>(
         &mut self,
         input: S,
     ) -> Result<String, Box<dyn std::error::Error>> {
         let input = input.into();
-        let command = parser::parse(input)?;
+        let size = input.len();
+        let command = match parser::parse(input) {
+            Ok(command) => command,
+            Err(e) => {
+                if let Mode::Queued = self.mode {
+                    self.queue.failed = true;
+                }
+                return Err(e.into());
+            }
+        };
 
         // FIXME lock取得時のunwrap祭りをどうにかする
         if command == Command::Shutdown {
-            {
-                // lockをなる早で開放するためにブロックで囲っている
-                let serialized = self.inner.memdb.read().unwrap().serialize();
-                let mut db_file = self.inner.db_file.lock().unwrap();
+            let shards = self.inner.shards.read_all();
 
-                db_file.write_all(&serialized)?;
-                db_file.flush()?;
-            }
+            let mut db_file = self.inner.db_file.lock().unwrap();
+            db_file.set_len(0)?;
+            db_file.write_all(&shards.serialize())?;
+            db_file.flush()?;
 
-            let log_file = self.inner.log_file.lock().unwrap();
-            log_file.set_len(0)?;
+            self.inner.log_file.set_len(0)?;
 
             return Ok("shutdown!!".to_string());
         }
 
-        if command == Command::Multi {
-            self.as_transaction_mode();
+        if command == Command::Backup {
+            return Ok(self.backup()?.encode());
+        }
+
+        match &command {
+            Command::LocksList { pattern } => return Ok(self.locks_list(pattern.as_deref())),
+            Command::LocksWaiters => return Ok(self.locks_waiters()),
+            Command::Info => return Ok(self.info()),
+            _ => (),
+        }
+
+        if let Command::Watch { keys } = command {
+            return self.watch(keys);
+        }
+
+        if command == Command::Unwatch {
+            self.watched.clear();
+            return Ok(String::from("OK"));
+        }
+
+        if let Command::Multi(options) = command {
+            match self.mode {
+                Mode::Nornal if options.queued => {
+                    self.queue = CommandQueue::default();
+                    self.mode = Mode::Queued;
+                }
+                Mode::Nornal if options.snapshot || options.readonly => {
+                    let id = self.inner.next_transaction_id();
+                    let snapshot = match options.readonly {
+                        true => SnapshotTransaction::begin_readonly(id, &self.inner.shards),
+                        false => SnapshotTransaction::begin(id, &self.inner.shards),
+                    };
+                    self.open_transaction(id, Some(snapshot.timestamp()));
+                    self.snapshot = Some(snapshot);
+                    self.mode = Mode::Snapshot;
+                }
+                Mode::Nornal => {
+                    let id = self.inner.next_transaction_id();
+                    self.transaction = Transaction::new(id, options);
+                    self.as_transaction_mode();
+                    self.open_transaction(id, None);
+                }
+                Mode::Transaction => return Ok("Start transaction".to_owned()),
+                Mode::Queued | Mode::Snapshot => {
+                    return Err("ERR MULTI calls can not be nested".into())
+                }
+            }
+
+            self.usage = Some(TransactionUsage {
+                started: Instant::now(),
+                commands: 0,
+                bytes: 0,
+            });
             return Ok("Start transaction".to_owned());
         }
 
+        if let Err(e) = self.check_limits(&command, size) {
+            self.abort_transaction();
+            return Err(e.into());
+        }
+
+        // transaction の外で送られた制御用の command は、lock も log も通さずに失敗させる
+        match (&self.mode, &command) {
+            (Mode::Nornal, Command::Exec) => return Err("ERR EXEC without MULTI".into()),
+            (Mode::Nornal, Command::Abort) => return Err("ERR ABORT without MULTI".into()),
+            (Mode::Nornal, Command::Savepoint { .. })
+            | (Mode::Nornal, Command::RollbackTo { .. })
+            | (Mode::Nornal, Command::Release { .. }) => {
+                return Err("ERR SAVEPOINT can only be used in transaction".into())
+            }
+            // queue に入れると exec で log に書かれてしまうので、queue ごと失敗させる
+            (Mode::Queued, Command::Savepoint { .. })
+            | (Mode::Queued, Command::RollbackTo { .. })
+            | (Mode::Queued, Command::Release { .. }) => {
+                self.queue.failed = true;
+                return Err("ERR SAVEPOINT is not supported in queued transactions".into());
+            }
+            _ => (),
+        }
+
         let output = match self.mode {
             Mode::Nornal => self.exec_command_normal_mode(command),
             Mode::Transaction => self.exec_command_transaction_mode(command),
+            Mode::Queued => self.exec_command_queued_mode(command),
+            Mode::Snapshot => self.exec_command_snapshot_mode(command),
         }?;
 
         Ok(output)
@@ -92,38 +266,537 @@ impl Executor {
         &self,
         command: Command,
     ) -> Result<String, Box<dyn std::error::Error>> {
-        writeln!(
-            self.inner.log_file.lock().unwrap(),
-            "{}",
-            command.to_string()
-        )?;
+        // 読み込みだけのcommandはlogに書かず、shardの read lockだけで実行する。
+        // scan は一度に一つの shard しか見ないので、cursor の指す shard だけを lock する
+        if command.is_readonly() {
+            let mut shards = match &command {
+                Command::Scan { cursor, .. } => self
+                    .inner
+                    .shards
+                    .read_shard(keyspace::scan_partition(*cursor)),
+                _ if command.touches_all_keys() => self.inner.shards.read_all(),
+                _ => self.inner.shards.read(command.keys()),
+            };
+            return Ok(shards.exec_command(command)?);
+        }
+
+        if !command.blocking_keys().is_empty() {
+            return self.exec_blocking_command(command);
+        }
+
+        // shardのlockを保持したままlogを書くので、同じkeyへの書き込みはlogと同じ順番でmemdbに反映される。
+        // backupは全てのshardのlockを取るので、logに書かれてmemdbに反映されていない書き込みは見えない
+        let command = command.resolve_expire(expire::now());
+        let _locks = self
+            .inner
+            .lock_command_keys(std::slice::from_ref(&command), LockWait::Default)?;
+        let mut shards = match command.keys() {
+            keys if keys.is_empty() || command.touches_all_keys() => self.inner.shards.write_all(),
+            keys => self.inner.shards.write(keys),
+        };
+        self.inner.append_log(std::slice::from_ref(&command))?;
 
-        // TODO
-        // execが何かによらず write lockを取得してしまっている
-        // commandをW R 区別する。などの方法で write lockを常に取らないようにしたい
-        let output = self.inner.memdb.write().unwrap().exec_command(command)?;
+        let keys = command
+            .keys()
+            .into_iter()
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+        let output = shards.exec_command(command)?;
+        self.inner.blocked.wake(keys.iter().map(String::as_str));
 
         Ok(output)
     }
 
+    /// Runs a blocking pop, parking the connection until an element is pushed to one of
+    /// its keys or the timeout passes, in which case it replies `None`.
+    fn exec_blocking_command(
+        &self,
+        command: Command,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let timeout = match &command {
+            Command::BLPop { timeout, .. }
+            | Command::BRPop { timeout, .. }
+            | Command::BLMove { timeout, .. } => *timeout,
+            _ => None,
+        };
+        let deadline = match timeout {
+            None => None,
+            Some(timeout) => Some(
+                Instant::now()
+                    .checked_add(timeout)
+                    .ok_or("ERR timeout is out of range")?,
+            ),
+        };
+        let blocked = &self.inner.blocked;
+        let mut waiter = None;
+
+        loop {
+            // key lock は待っている間は開放して、transaction を止めないようにする。
+            // lock を待つのも timeout までで、それまでに取れなければ要素が来なかったのと同じく None を返す
+            let wait = match deadline {
+                None => LockWait::Default,
+                Some(deadline) => {
+                    LockWait::Timeout(deadline.saturating_duration_since(Instant::now()))
+                }
+            };
+            let locks = match self
+                .inner
+                .lock_command_keys(std::slice::from_ref(&command), wait)
+            {
+                Ok(locks) => locks,
+                Err(e) => {
+                    if let Some(id) = waiter {
+                        blocked.unregister(id);
+                    }
+                    if e == LOCKTIMEOUT_ERROR && deadline.is_some() {
+                        return Ok(String::from("None"));
+                    }
+                    return Err(e.into());
+                }
+            };
+            let mut shards = self.inner.shards.write(command.keys());
+
+            // 要素を取り出せた時だけlogに書く。replay では同じ状態から同じ要素が取り出される。
+            // shardのlockを持ったままなので、logの順番は memdb に反映された順番と変わらない
+            if list::is_ready(&mut shards, &command)? {
+                if let Some(id) = waiter {
+                    blocked.unregister(id);
+                }
+                let output = shards.exec_command(command.clone())?;
+                self.inner.append_log(std::slice::from_ref(&command))?;
+
+                // 残った要素や BLMOVE で移した要素を、次に待っている client に渡す
+                blocked.wake(command.keys());
+
+                return Ok(output);
+            }
+
+            // 待ち始める前に、shardのlockを持ったまま列に並ぶので、その後の push は見逃さない
+            let id = *waiter.get_or_insert_with(|| blocked.register(&command.blocking_keys()));
+            drop(shards);
+            drop(locks);
+
+            if !blocked.wait(id, deadline) {
+                blocked.unregister(id);
+                return Ok(String::from("None"));
+            }
+        }
+    }
+
+    /// Takes a consistent snapshot of the database together with the current length
+    /// of the log file, without blocking readers.
+    pub fn backup(&self) -> Result<Backup, Box<dyn std::error::Error>> {
+        let shards = self.inner.shards.read_all();
+
+        Ok(Backup::new(
+            self.inner.log_file.metadata()?.len(),
+            shards.serialize(),
+        ))
+    }
+
     fn exec_command_transaction_mode(
         &mut self,
         command: Command,
     ) -> Result<String, Box<dyn std::error::Error>> {
+        if command == Command::Exec {
+            let output = self.commit_transaction();
+
+            self.close_transaction();
+            self.as_normal_mode();
+
+            return output;
+        }
+
         let output =
             self.transaction
-                .exec_command(command.clone(), &self.inner.locks, &self.inner.memdb)?;
+                .exec_command(command.clone(), &self.inner.locks, &self.inner.shards);
 
-        if command == Command::Exec || command == Command::Abort {
+        // deadlockの解決などでtransactionがabortされた場合もnormal modeに戻す
+        if command == Command::Abort || self.transaction.is_aborted() {
             self.as_normal_mode();
-            self.transaction.clear_lock(&self.inner.locks);
+            self.close_transaction();
         }
 
-        Ok(output)
+        Ok(output?)
+    }
+
+    fn commit_transaction(&self) -> Result<String, Box<dyn std::error::Error>> {
+        self.inner.finish_transaction(self.transaction.id())?;
+
+        let write_keys = self.transaction.write_keys();
+        let keys = write_keys.iter().chain(self.watched.keys());
+
+        // commitする内容をlogに書き、memdbに反映し終わるまでshardのlockを保持する
+        let mut shards = self.inner.shards.write(keys.map(String::as_str));
+
+        if Executor::is_modified(&self.watched, &shards) {
+            return Ok(String::from("None"));
+        }
+
+        self.inner.append_log(&self.transaction.pending_writes())?;
+
+        self.transaction.commit(&mut shards)?;
+        self.inner
+            .blocked
+            .wake(write_keys.iter().map(String::as_str));
+        Ok(String::from("OK"))
+    }
+
+    fn exec_command_queued_mode(
+        &mut self,
+        command: Command,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        match command {
+            Command::Exec => {
+                let queue = std::mem::take(&mut self.queue);
+                let watched = std::mem::take(&mut self.watched);
+                self.as_normal_mode();
+
+                if queue.failed {
+                    return Err(EXECABORT_ERROR.into());
+                }
+
+                // 期限は queue に入れた時ではなく exec した時から数える
+                let now = expire::now();
+                let commands = queue
+                    .commands
+                    .into_iter()
+                    .map(|command| command.resolve_expire(now))
+                    .collect::<Vec<_>>();
+
+                let _locks = self.inner.lock_command_keys(&commands, LockWait::Default)?;
+                // 全てのcommandが触るshardのlockの中で実行するので、他のclientから途中の状態は見えない
+                let keys = commands
+                    .iter()
+                    .flat_map(Command::keys)
+                    .chain(watched.keys().map(String::as_str));
+                let mut shards = match commands.iter().any(Command::touches_all_keys) {
+                    true => self.inner.shards.write_all(),
+                    false => self.inner.shards.write(keys),
+                };
+
+                if Executor::is_modified(&watched, &shards) {
+                    return Ok(String::from("None"));
+                }
+
+                self.inner.append_log(&commands)?;
+
+                let written = commands
+                    .iter()
+                    .flat_map(Command::keys)
+                    .map(str::to_owned)
+                    .collect::<Vec<_>>();
+                let mut replies = vec![];
+                for command in commands {
+                    replies.push(match shards.exec_command(command) {
+                        Ok(reply) => reply,
+                        Err(e) => format!("(error) {}", e),
+                    });
+                }
+                self.inner.blocked.wake(written.iter().map(String::as_str));
+
+                Ok(reply::array(replies))
+            }
+            Command::Abort => {
+                self.queue = CommandQueue::default();
+                self.as_normal_mode();
+
+                Ok(String::from("Abort transaction"))
+            }
+            command => {
+                self.queue.commands.push(command);
+                Ok(String::from("QUEUED"))
+            }
+        }
+    }
+
+    fn exec_command_snapshot_mode(
+        &mut self,
+        command: Command,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        match command {
+            Command::Exec => {
+                let snapshot = self.snapshot.take().unwrap();
+                let watched = std::mem::take(&mut self.watched);
+                self.as_normal_mode();
+
+                // reap された snapshot は既に開放されているので、ここで終了してはいけない
+                self.inner.finish_transaction(snapshot.id())?;
+
+                // 書き込みが無いので log file の lock も conflict の確認も必要ない
+                let output = if snapshot.is_readonly() {
+                    Ok(String::from("OK"))
+                } else {
+                    self.commit_snapshot(&snapshot, &watched)
+                };
+
+                // snapshotの終了は全てのshardのlockを取るので、commitのlockを開放してから行う
+                snapshot.end(&self.inner.shards);
+                output
+            }
+            Command::Abort => {
+                self.close_snapshot();
+                self.as_normal_mode();
+
+                Ok(String::from("Abort transaction"))
+            }
+            command => {
+                let snapshot = self.snapshot.as_mut().unwrap();
+                Ok(snapshot.exec_command(command, &self.inner.shards)?)
+            }
+        }
+    }
+
+    fn commit_snapshot(
+        &self,
+        snapshot: &SnapshotTransaction,
+        watched: &HashMap<String, u64>,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let write_keys = snapshot.write_keys();
+        let keys = write_keys.iter().chain(watched.keys());
+
+        let _locks = self
+            .inner
+            .lock_keys(write_keys.clone(), LockWait::Default)?;
+        let mut shards = self.inner.shards.write(keys.map(String::as_str));
+
+        if Executor::is_modified(watched, &shards) {
+            return Ok(String::from("None"));
+        }
+
+        snapshot.check_conflicts(&shards)?;
+        self.inner.append_log(&snapshot.pending_writes())?;
+
+        snapshot.commit(&mut shards)?;
+        self.inner
+            .blocked
+            .wake(write_keys.iter().map(String::as_str));
+        Ok(String::from("OK"))
+    }
+
+    /// `locks list [pattern]`: every locked key with its mode, holders and queued waiters.
+    fn locks_list(&self, pattern: Option<&str>) -> String {
+        let lines = self
+            .inner
+            .locks
+            .list(pattern)
+            .into_iter()
+            .map(|lock| {
+                let mode = match lock.mode {
+                    None => String::from("unlocked"),
+                    Some(LockMode::Read) => format!("read({})", lock.owners.len()),
+                    Some(LockMode::Write) => String::from("write"),
+                };
+                let owners = lock.owners.iter().map(|owner| self.describe(*owner));
+                let waiters = lock.waiters.iter().map(|(owner, waited)| {
+                    format!("{} {}ms", self.describe(*owner), waited.as_millis())
+                });
+
+                format!(
+                    "{} {} by [{}] waiters [{}]",
+                    lock.key,
+                    mode,
+                    owners.collect::<Vec<_>>().join(", "),
+                    waiters.collect::<Vec<_>>().join(", ")
+                )
+            })
+            .collect();
+
+        reply::array(lines)
+    }
+
+    /// `locks waiters`: every blocked lock request and who it waits for, longest first.
+    fn locks_waiters(&self) -> String {
+        let lines = self
+            .inner
+            .locks
+            .waiters()
+            .into_iter()
+            .map(|wait| {
+                let blockers = wait.blockers.iter().map(|owner| self.describe(*owner));
+
+                format!(
+                    "{} waits {}ms for {} blocked by [{}]",
+                    self.describe(wait.owner),
+                    wait.waited.as_millis(),
+                    wait.key,
+                    blockers.collect::<Vec<_>>().join(", ")
+                )
+            })
+            .collect();
+
+        reply::array(lines)
+    }
+
+    fn info(&self) -> String {
+        let stats = self.inner.locks.stats();
+        let transactions = self.inner.transactions.lock().unwrap().len();
+
+        reply::array(vec![
+            format!("open_transactions:{}", transactions),
+            format!("locked_keys:{}", self.inner.locks.list(None).len()),
+            format!("blocked_transactions:{}", self.inner.locks.waiters().len()),
+            format!("blocked_clients:{}", self.inner.blocked.len()),
+            format!("lock_waits:{}", stats.waits),
+            format!("lock_timeouts:{}", stats.timeouts),
+            format!("deadlocks:{}", stats.deadlocks),
+        ])
+    }
+
+    // lock の持ち主を、それが実行されている接続と一緒に表示する
+    fn describe(&self, owner: TxId) -> String {
+        match self.inner.transactions.lock().unwrap().get(&owner) {
+            Some(transaction) => format!("tx {} (conn {})", owner, transaction.connection),
+            None => format!("tx {}", owner),
+        }
+    }
+
+    /// `watch key [key ...]`: remembers the current versions of the keys, so that
+    /// the next `exec` replies `None` without applying anything if one of them was
+    /// modified in the meantime.
+    fn watch(&mut self, keys: Vec<String>) -> Result<String, Box<dyn std::error::Error>> {
+        if let Mode::Transaction | Mode::Queued | Mode::Snapshot = self.mode {
+            return Err("ERR WATCH inside MULTI is not allowed".into());
+        }
+
+        for key in keys {
+            let version = self.inner.shards.version(&key);
+            self.watched.entry(key).or_insert(version);
+        }
+
+        Ok(String::from("OK"))
+    }
+
+    // 全ての書き込みはshardのlockを取ってから行われるので、
+    // watchしているkeyのshardのlockを保持した状態で呼び出せば確認からcommitまでの間に変更されることはない
+    fn is_modified<G: Deref<Target = Memdb>>(
+        watched: &HashMap<String, u64>,
+        shards: &ShardGuards<G>,
+    ) -> bool {
+        watched
+            .iter()
+            .any(|(key, version)| shards.version(key) != *version)
+    }
+
+    /// Removes expired keys that nobody has written since they expired. The server
+    /// calls this periodically; returns the number of keys removed.
+    pub fn expire_keys(&self) -> usize {
+        self.inner.shards.expire_cycle()
+    }
+
+    /// Aborts the transactions that have run longer than `TransactionLimits::max_duration`
+    /// and releases their locks, or their snapshot for `multi snapshot` and `multi readonly`,
+    /// even when their clients are idle. The server calls this periodically; the clients
+    /// are told the reason on their next command.
+    pub fn reap_transactions(&self) {
+        let now = Instant::now();
+
+        // commitと同時に reap されないよう、transactionsのlockを保持したままabortする
+        let mut transactions = self.inner.transactions.lock().unwrap();
+        transactions.retain(|id, transaction| {
+            if transaction.deadline.is_none_or(|deadline| deadline > now) {
+                return true;
+            }
+
+            // snapshot の開放は全ての shard の lock を取るが、shard の lock を持ったまま
+            // transactions の lock を取る所は無いので deadlock しない
+            if let Some(timestamp) = transaction.snapshot {
+                self.inner.shards.end_snapshot(timestamp);
+            }
+            self.inner.locks.abort(*id, TXN_TIMEOUT_ERROR);
+            false
+        });
+    }
+
+    fn check_limits(&mut self, command: &Command, size: usize) -> Result<(), String> {
+        let usage = match self.usage.as_mut() {
+            Some(usage) if *command != Command::Abort => usage,
+            _ => return Ok(()),
+        };
+
+        let id = match (&self.mode, &self.snapshot) {
+            (Mode::Transaction, _) => Some(self.transaction.id()),
+            (Mode::Snapshot, Some(snapshot)) => Some(snapshot.id()),
+            _ => None,
+        };
+        let locks = &self.inner.locks;
+        if let Some(reason) = id.and_then(|id| locks.abort_reason(id)) {
+            return Err(reason);
+        }
+
+        let limits = self.limits;
+        if limits
+            .max_duration
+            .is_some_and(|max| usage.started.elapsed() > max)
+        {
+            return Err(String::from(TXN_TIMEOUT_ERROR));
+        }
+
+        if *command == Command::Exec {
+            return Ok(());
+        }
+
+        usage.commands += 1;
+        usage.bytes += size;
+        if limits.max_commands.is_some_and(|max| usage.commands > max)
+            || limits.max_bytes.is_some_and(|max| usage.bytes > max)
+        {
+            return Err(String::from(TXN_TOO_LARGE_ERROR));
+        }
+
+        Ok(())
+    }
+
+    fn abort_transaction(&mut self) {
+        match self.mode {
+            Mode::Nornal => (),
+            Mode::Transaction => self.close_transaction(),
+            Mode::Queued => self.queue = CommandQueue::default(),
+            Mode::Snapshot => self.close_snapshot(),
+        }
+
+        self.as_normal_mode();
+    }
+
+    fn open_transaction(&self, id: TxId, snapshot: Option<u64>) {
+        let transaction = OpenTransaction {
+            connection: self.connection,
+            deadline: self.limits.max_duration.map(|max| Instant::now() + max),
+            snapshot,
+        };
+        let mut transactions = self.inner.transactions.lock().unwrap();
+        transactions.insert(id, transaction);
+    }
+
+    fn close_transaction(&mut self) {
+        self.transaction.clear_lock(&self.inner.locks);
+
+        let id = self.transaction.id();
+        self.inner.transactions.lock().unwrap().remove(&id);
+        // reap されたことに気付く前に終了した場合の理由は捨てる
+        self.inner.locks.abort_reason(id);
+    }
+
+    // reap されていない場合だけ snapshot を開放する。reap と同時に開放しないよう、
+    // transactions から取り除けた方が開放する
+    fn close_snapshot(&mut self) {
+        let snapshot = match self.snapshot.take() {
+            Some(snapshot) => snapshot,
+            None => return,
+        };
+
+        let id = snapshot.id();
+        let open = self.inner.transactions.lock().unwrap().remove(&id);
+        if open.is_some() {
+            snapshot.end(&self.inner.shards);
+        }
+        self.inner.locks.abort_reason(id);
     }
 
     fn as_normal_mode(&mut self) {
         self.mode = Mode::Nornal;
+        self.watched.clear();
+        self.usage = None;
     }
 
     fn as_transaction_mode(&mut self) {
@@ -131,18 +804,103 @@ impl Executor {
     }
 }
 
+impl ExecutorInner {
+    fn next_transaction_id(&self) -> TxId {
+        self.transaction_ids.fetch_add(1, Ordering::SeqCst)
+    }
+
+    fn next_connection_id(&self) -> u64 {
+        self.connection_ids.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// `lock_keys` on the keys `commands` write. `flushdb` locks every stored key.
+    fn lock_command_keys(
+        &self,
+        commands: &[Command],
+        wait: LockWait,
+    ) -> Result<KeyLocks<'_>, String> {
+        let mut keys = commands
+            .iter()
+            .flat_map(Command::keys)
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+        if commands.contains(&Command::FlushDb) {
+            keys.extend(self.shards.stored_keys());
+        }
+
+        self.lock_keys(keys, wait)
+    }
+
+    /// Write-locks `keys` in `Locks` for a write outside a lock-based transaction, so that
+    /// it waits for the transactions that read or wrote them instead of being overwritten
+    /// by their commit. The keys are locked in sorted order, before any shard.
+    fn lock_keys(&self, mut keys: Vec<String>, wait: LockWait) -> Result<KeyLocks<'_>, String> {
+        keys.sort_unstable();
+        keys.dedup();
+
+        // 途中で lock を取れなかった場合は、取れた分を drop で開放する
+        let mut guard = KeyLocks {
+            locks: &self.locks,
+            owner: self.next_transaction_id(),
+            keys: Vec::with_capacity(keys.len()),
+        };
+        for key in keys {
+            self.locks.write_lock(guard.owner, &key, wait)?;
+            guard.keys.push(key);
+        }
+
+        Ok(guard)
+    }
+
+    /// Makes sure the transaction is not reaped while it commits, or fails with
+    /// the reason if it already was.
+    fn finish_transaction(&self, id: TxId) -> Result<(), String> {
+        let mut transactions = self.transactions.lock().unwrap();
+        if let Some(reason) = self.locks.abort_reason(id) {
+            return Err(reason);
+        }
+
+        transactions.remove(&id);
+        Ok(())
+    }
+
+    /// Appends the commands to the log in a single write, so that lines of concurrent
+    /// writers don't interleave. The caller must hold the shards of the keys written.
+    fn append_log(&self, commands: &[Command]) -> std::io::Result<()> {
+        let logs = commands
+            .iter()
+            .map(|command| format!("{}\n", command))
+            .collect::<String>();
+
+        (&self.log_file).write_all(logs.as_bytes())
+    }
+}
+
 impl Clone for Executor {
     fn clone(&self) -> Self {
         Executor {
             inner: self.inner.clone(),
+            connection: self.inner.next_connection_id(),
             mode: Mode::Nornal,
-            transaction: Transaction::new(),
+            transaction: Transaction::default(),
+            queue: CommandQueue::default(),
+            snapshot: None,
+            watched: HashMap::new(),
+            limits: self.limits,
+            usage: None,
         }
     }
 }
 
+impl Drop for KeyLocks<'_> {
+    fn drop(&mut self) {
+        self.locks.release_keys(self.owner, &self.keys);
+    }
+}
+
 impl Drop for Executor {
     fn drop(&mut self) {
-        self.transaction.clear_lock(&self.inner.locks);
+        self.close_transaction();
+        self.close_snapshot();
     }
 }
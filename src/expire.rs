@@ -0,0 +1,34 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// When a key set by `set ... ex|px|exat|pxat` or `expire` family expires, in milliseconds.
+///
+/// Relative times are turned into deadlines when the command runs, and only deadlines
+/// are written to the log, so replaying it expires keys at the same time.
+#[derive(PartialEq, PartialOrd, Debug, Clone, Copy)]
+pub enum Expire {
+    /// Milliseconds from now.
+    After(i64),
+    /// Milliseconds since the Unix epoch.
+    At(i64),
+}
+
+impl Expire {
+    pub(crate) fn deadline(self, now: u64) -> u64 {
+        match self {
+            Expire::After(millis) => (now as i64).saturating_add(millis).max(0) as u64,
+            Expire::At(millis) => millis.max(0) as u64,
+        }
+    }
+
+    pub(crate) fn at(self, now: u64) -> Expire {
+        Expire::At(self.deadline(now) as i64)
+    }
+}
+
+/// Milliseconds since the Unix epoch, the unit of every deadline.
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,108 @@
+/// Redis style glob matching of keys, e.g. `user:*`, `h?llo`, `h[ae]llo`, `h[^e]llo`
+/// and `h[a-b]llo`. A backslash matches the following character literally.
+pub(crate) fn matches(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+
+    matches_from(&pattern, &text)
+}
+
+fn matches_from(pattern: &[char], text: &[char]) -> bool {
+    let (p, rest) = match pattern.split_first() {
+        None => return text.is_empty(),
+        Some(split) => split,
+    };
+
+    match p {
+        // 連続する * は一つとして扱う
+        '*' if rest.first() == Some(&'*') => matches_from(rest, text),
+        '*' => (0..=text.len()).any(|i| matches_from(rest, &text[i..])),
+        '?' => !text.is_empty() && matches_from(rest, &text[1..]),
+        '[' => match (text.first(), class_end(rest)) {
+            (Some(c), Some(end)) => {
+                matches_class(&rest[..end], *c) && matches_from(&rest[end + 1..], &text[1..])
+            }
+            // 閉じていない [ はそのままの文字として扱う
+            (Some(c), None) => *c == '[' && matches_from(rest, &text[1..]),
+            (None, _) => false,
+        },
+        '\\' if !rest.is_empty() => {
+            text.first() == Some(&rest[0]) && matches_from(&rest[1..], &text[1..])
+        }
+        p => text.first() == Some(p) && matches_from(rest, &text[1..]),
+    }
+}
+
+// `[` の後から対応する `]` までの長さ
+fn class_end(class: &[char]) -> Option<usize> {
+    let mut i = 0;
+    while i < class.len() {
+        match class[i] {
+            '\\' => i += 2,
+            ']' if i > 0 && !(i == 1 && class[0] == '^') => return Some(i),
+            _ => i += 1,
+        }
+    }
+
+    None
+}
+
+fn matches_class(class: &[char], c: char) -> bool {
+    let (negated, class) = match class.split_first() {
+        Some(('^', rest)) => (true, rest),
+        _ => (false, class),
+    };
+
+    let mut matched = false;
+    let mut i = 0;
+    while i < class.len() {
+        let start = match class[i] {
+            '\\' if i + 1 < class.len() => {
+                i += 1;
+                class[i]
+            }
+            ch => ch,
+        };
+
+        if i + 2 < class.len() && class[i + 1] == '-' {
+            let end = class[i + 2];
+            matched |= start.min(end) <= c && c <= start.max(end);
+            i += 3;
+        } else {
+            matched |= start == c;
+            i += 1;
+        }
+    }
+
+    matched != negated
+}
+
+#[test]
+fn test_matches() {
+    let cases = vec![
+        ("*", "", true),
+        ("*", "anything", true),
+        ("user:*", "user:1", true),
+        ("user:*", "users", false),
+        ("*:name", "user:1:name", true),
+        ("h?llo", "hello", true),
+        ("h?llo", "hllo", false),
+        ("h[ae]llo", "hallo", true),
+        ("h[ae]llo", "hillo", false),
+        ("h[^e]llo", "hallo", true),
+        ("h[^e]llo", "hello", false),
+        ("h[a-b]llo", "hbllo", true),
+        ("h[a-b]llo", "hcllo", false),
+        ("h[]]llo", "h]llo", true),
+        ("h[llo", "h[llo", true),
+        ("h\\*llo", "h*llo", true),
+        ("h\\*llo", "hello", false),
+        ("a**b", "axyzb", true),
+        ("key", "key", true),
+        ("key", "key1", false),
+    ];
+
+    for (pattern, text, expected) in cases {
+        assert_eq!(matches(pattern, text), expected, "{} {}", pattern, text);
+    }
+}
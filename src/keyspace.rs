@@ -0,0 +1,640 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+
+use crate::command::{Command, SetCondition};
+use crate::expire;
+use crate::glob;
+use crate::list;
+use crate::reply;
+
+pub const NOT_INTEGER_ERROR: &str = "ERR value is not an integer or out of range";
+pub const NOT_FLOAT_ERROR: &str = "ERR value is not a valid float";
+pub const OVERFLOW_ERROR: &str = "ERR increment or decrement would overflow";
+pub const NO_SUCH_KEY_ERROR: &str = "ERR no such key";
+pub const WRONGTYPE_ERROR: &str =
+    "WRONGTYPE Operation against a key holding the wrong kind of value";
+
+/// The longest value `append` and `setrange` may produce, 512MB like Redis.
+const MAX_VALUE_LENGTH: usize = 512 * 1024 * 1024;
+const MAX_VALUE_LENGTH_ERROR: &str = "ERR string exceeds maximum allowed size";
+
+// scan の cursor の上位 bit は partition の番号で、残りの bit はその partition の中の位置
+const SCAN_PARTITION_SHIFT: u32 = 48;
+
+/// What a key holds. Commands made for one type fail with `WRONGTYPE_ERROR` on the others.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(Vec<u8>),
+    List(VecDeque<Vec<u8>>),
+}
+
+impl Value {
+    /// The name `type` replies with.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+        }
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(value: Vec<u8>) -> Value {
+        Value::String(value)
+    }
+}
+
+/// A value and the time it expires at, in milliseconds since the Unix epoch.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Entry {
+    pub value: Value,
+    pub deadline: Option<u64>,
+}
+
+impl Entry {
+    pub fn new(value: impl Into<Value>) -> Entry {
+        Entry {
+            value: value.into(),
+            deadline: None,
+        }
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.deadline.is_some_and(|deadline| deadline <= now)
+    }
+}
+
+/// The primitive reads and writes every data command is built from.
+///
+/// `Memdb` applies them directly, while a transaction goes through its caches and
+/// key locks, so both execute commands with the same semantics.
+pub(crate) trait Keyspace: Sized {
+    /// Expired keys are never returned.
+    fn read_entry(&mut self, key: &str) -> Result<Option<Entry>, String>;
+
+    fn write_entry(&mut self, key: &str, entry: Entry) -> Result<(), String>;
+
+    /// Returns whether the key existed.
+    fn remove(&mut self, key: &str) -> Result<bool, String>;
+
+    /// Every key that may hold a value, including expired and deleted ones, so callers
+    /// read each of them to find the live keys.
+    fn stored_keys(&mut self) -> Result<Vec<String>, String>;
+
+    /// How many partitions `scan` walks through, one at a time.
+    fn scan_partitions(&self) -> usize {
+        1
+    }
+
+    /// Up to `count` of the `stored_keys` in partition `index` of `scan_partitions`, from
+    /// `scan_position` `from` on, in that order and with their positions. Read from the
+    /// ordered index each `Memdb` keeps, so `scan` never sorts a whole partition.
+    fn partition_range(
+        &mut self,
+        index: usize,
+        from: u64,
+        count: usize,
+    ) -> Result<Vec<(u64, String)>, String>;
+
+    /// Reads a key the command is about to write. A transaction takes the write lock
+    /// right away, so two read-modify-write commands on the same key queue up instead
+    /// of deadlocking on the lock upgrade.
+    fn read_for_update(&mut self, key: &str) -> Result<Option<Entry>, String> {
+        self.read_entry(key)
+    }
+
+    /// Reads a string, failing with `WRONGTYPE_ERROR` when the key holds another type.
+    fn read(&mut self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        match self.read_entry(key)? {
+            None => Ok(None),
+            Some(Entry {
+                value: Value::String(value),
+                ..
+            }) => Ok(Some(value)),
+            Some(_) => Err(String::from(WRONGTYPE_ERROR)),
+        }
+    }
+
+    /// Passes the value of `key` to `f` without copying it.
+    fn read_with<R>(
+        &mut self,
+        key: &str,
+        f: impl FnOnce(Option<&Value>) -> R,
+    ) -> Result<R, String> {
+        Ok(f(self.read_entry(key)?.map(|entry| entry.value).as_ref()))
+    }
+
+    /// Changes the value of `key` in place, keeping its expiration. `f` gets `None` for
+    /// a missing key and may fill it in to create the key without expiration, or take
+    /// the value out to delete the key. Nothing is written when `f` fails, so it has to
+    /// fail before it modifies the value.
+    fn update_value<R>(
+        &mut self,
+        key: &str,
+        f: impl FnOnce(&mut Option<Value>) -> Result<R, String>,
+    ) -> Result<R, String> {
+        let entry = self.read_for_update(key)?;
+        let deadline = entry.as_ref().and_then(|entry| entry.deadline);
+        let existed = entry.is_some();
+
+        let mut value = entry.map(|entry| entry.value);
+        let result = f(&mut value)?;
+        match value {
+            Some(value) => self.write_entry(key, Entry { value, deadline })?,
+            None if existed => {
+                self.remove(key)?;
+            }
+            None => (),
+        }
+
+        Ok(result)
+    }
+
+    /// `update_value` for a string, where a missing key is passed as an empty string.
+    fn update_string<R>(
+        &mut self,
+        key: &str,
+        f: impl FnOnce(&mut Vec<u8>) -> Result<R, String>,
+    ) -> Result<R, String> {
+        self.update_value(key, |value| {
+            match value.get_or_insert_with(|| Value::String(vec![])) {
+                Value::String(value) => f(value),
+                _ => Err(String::from(WRONGTYPE_ERROR)),
+            }
+        })
+    }
+
+    /// Writes a value that never expires, like `set` does.
+    fn write(&mut self, key: &str, value: Vec<u8>) -> Result<(), String> {
+        self.write_entry(key, Entry::new(value))
+    }
+
+    fn exec_command(&mut self, command: Command) -> Result<String, String> {
+        match command {
+            Command::Set {
+                key,
+                value,
+                expire,
+                condition,
+                get,
+            } => {
+                // 条件も get もなければ今の値を読む必要はない
+                let old = match (condition, get) {
+                    (None, false) => None,
+                    _ => self.read_for_update(&key)?.map(|entry| entry.value),
+                };
+                if get && !matches!(old, None | Some(Value::String(_))) {
+                    return Err(String::from(WRONGTYPE_ERROR));
+                }
+                let write = match condition {
+                    None => true,
+                    Some(SetCondition::NotExists) => old.is_none(),
+                    Some(SetCondition::Exists) => old.is_some(),
+                };
+
+                if write {
+                    let entry = Entry {
+                        value: Value::String(value),
+                        deadline: expire.map(|expire| expire.deadline(expire::now())),
+                    };
+                    self.write_entry(&key, entry)?;
+                }
+
+                Ok(match (get, old) {
+                    (true, Some(Value::String(old))) => String::from_utf8_lossy(&old).into_owned(),
+                    (true, _) => String::from("None"),
+                    (false, _) if write => String::from("OK"),
+                    (false, _) => String::from("None"),
+                })
+            }
+            Command::SetNX { key, value } => {
+                if self.read_for_update(&key)?.is_some() {
+                    return Err(String::from("ERR key is already exists"));
+                }
+
+                self.write(&key, value)?;
+                Ok(String::from("OK"))
+            }
+            Command::Get { key } => match self.read(&key)? {
+                None => Ok(String::from("None")),
+                // setrange で文字の途中を書き換えられた値もそのまま返す
+                Some(v) => Ok(String::from_utf8_lossy(&v).into_owned()),
+            },
+            Command::GetDel { key } => match self.read_for_update(&key)? {
+                None => Ok(String::from("None")),
+                Some(Entry {
+                    value: Value::String(value),
+                    ..
+                }) => {
+                    self.remove(&key)?;
+                    Ok(String::from_utf8_lossy(&value).into_owned())
+                }
+                Some(_) => Err(String::from(WRONGTYPE_ERROR)),
+            },
+            Command::Cas {
+                key,
+                expected,
+                value,
+            } => match self.read_for_update(&key)? {
+                // 期限は置き換える前の値のものを引き継ぐ
+                Some(entry) if matches!(&entry.value, Value::String(v) if v.as_slice() == expected.as_slice()) =>
+                {
+                    let value = Value::String(value);
+                    self.write_entry(&key, Entry { value, ..entry })?;
+                    Ok(String::from("1"))
+                }
+                _ => Ok(String::from("0")),
+            },
+            Command::Del { keys } => {
+                let mut count = 0;
+                for key in keys {
+                    if self.remove(&key)? {
+                        count += 1;
+                    }
+                }
+
+                Ok(format!("{}", count))
+            }
+            Command::MGet { keys } => {
+                let mut values = vec![];
+                for key in keys {
+                    // 文字列以外の値は存在しない key と同じく None になる
+                    values.push(self.read_with(&key, |value| match value {
+                        Some(Value::String(v)) => String::from_utf8_lossy(v).into_owned(),
+                        _ => String::from("None"),
+                    })?);
+                }
+
+                Ok(reply::array(values))
+            }
+            Command::Exists { keys } => {
+                let mut count = 0;
+                for key in keys {
+                    if self.read_with(&key, |value| value.is_some())? {
+                        count += 1;
+                    }
+                }
+
+                Ok(format!("{}", count))
+            }
+            Command::Type { key } => {
+                let name = self.read_with(&key, |value| value.map_or("none", Value::type_name))?;
+                Ok(String::from(name))
+            }
+            Command::Rename { key, newkey } => {
+                let entry = self
+                    .read_for_update(&key)?
+                    .ok_or_else(|| String::from(NO_SUCH_KEY_ERROR))?;
+
+                if key != newkey {
+                    self.remove(&key)?;
+                    self.write_entry(&newkey, entry)?;
+                }
+                Ok(String::from("OK"))
+            }
+            Command::RenameNX { key, newkey } => {
+                let entry = self
+                    .read_for_update(&key)?
+                    .ok_or_else(|| String::from(NO_SUCH_KEY_ERROR))?;
+                if self.read_for_update(&newkey)?.is_some() {
+                    return Ok(String::from("0"));
+                }
+
+                self.remove(&key)?;
+                self.write_entry(&newkey, entry)?;
+                Ok(String::from("1"))
+            }
+            Command::Copy {
+                source,
+                destination,
+                replace,
+            } => {
+                if source == destination {
+                    return Err(String::from(
+                        "ERR source and destination objects are the same",
+                    ));
+                }
+
+                let entry = match self.read_entry(&source)? {
+                    None => return Ok(String::from("0")),
+                    Some(entry) => entry,
+                };
+                if self.read_for_update(&destination)?.is_some() && !replace {
+                    return Ok(String::from("0"));
+                }
+
+                self.write_entry(&destination, entry)?;
+                Ok(String::from("1"))
+            }
+            Command::DbSize => {
+                let mut count = 0;
+                for key in self.stored_keys()? {
+                    if self.read_with(&key, |value| value.is_some())? {
+                        count += 1;
+                    }
+                }
+
+                Ok(format!("{}", count))
+            }
+            Command::FlushDb => {
+                for key in self.stored_keys()? {
+                    self.remove(&key)?;
+                }
+
+                Ok(String::from("OK"))
+            }
+            Command::Keys { pattern } => {
+                let mut keys = vec![];
+                for key in self.stored_keys()? {
+                    if glob::matches(&pattern, &key) && self.read_with(&key, |v| v.is_some())? {
+                        keys.push(key);
+                    }
+                }
+
+                keys.sort_unstable();
+                Ok(reply::array(keys))
+            }
+            Command::Scan {
+                cursor,
+                pattern,
+                count,
+            } => {
+                let (cursor, keys) = self.scan(cursor, count)?;
+                let keys = keys
+                    .into_iter()
+                    .filter(|key| pattern.as_deref().is_none_or(|p| glob::matches(p, key)));
+
+                Ok(reply::array(
+                    std::iter::once(cursor.to_string()).chain(keys).collect(),
+                ))
+            }
+            Command::MSet { pairs } => {
+                for (key, value) in pairs {
+                    self.write(&key, value)?;
+                }
+
+                Ok(String::from("OK"))
+            }
+            Command::MSetNX { pairs } => {
+                // 1つでも存在する key があれば何も書き込まない
+                for (key, _) in &pairs {
+                    if self.read_for_update(key)?.is_some() {
+                        return Ok(String::from("0"));
+                    }
+                }
+                for (key, value) in pairs {
+                    self.write(&key, value)?;
+                }
+
+                Ok(String::from("1"))
+            }
+            Command::IncrBy { key, increment } => {
+                let value = self.update(&key, |value| {
+                    let current = match value {
+                        None => 0,
+                        Some(value) => parse_integer(value)?,
+                    };
+                    let value = current
+                        .checked_add(increment)
+                        .ok_or_else(|| String::from(OVERFLOW_ERROR))?;
+
+                    Ok(value.to_string().into_bytes())
+                })?;
+
+                Ok(String::from_utf8(value).unwrap())
+            }
+            Command::IncrByFloat { key, increment } => {
+                let value = self.update(&key, |value| {
+                    let current = match value {
+                        None => 0.0,
+                        Some(value) => parse_float(value)?,
+                    };
+                    let value = current + increment;
+                    if !value.is_finite() {
+                        return Err(String::from("ERR increment would produce NaN or Infinity"));
+                    }
+
+                    Ok(value.to_string().into_bytes())
+                })?;
+
+                Ok(String::from_utf8(value).unwrap())
+            }
+            Command::Append { key, value } => {
+                let len = self.update_string(&key, |current| {
+                    if current.len() + value.len() > MAX_VALUE_LENGTH {
+                        return Err(String::from(MAX_VALUE_LENGTH_ERROR));
+                    }
+
+                    current.extend_from_slice(&value);
+                    Ok(current.len())
+                })?;
+
+                Ok(format!("{}", len))
+            }
+            Command::StrLen { key } => {
+                let len = self.read_with(&key, |value| as_string(value).map(<[u8]>::len))??;
+                Ok(format!("{}", len))
+            }
+            Command::GetRange { key, start, end } => self.read_with(&key, |value| {
+                as_string(value).map(|value| {
+                    let range = index_range(value.len(), start, end);
+                    String::from_utf8_lossy(&value[range]).into_owned()
+                })
+            })?,
+            Command::SetRange { key, offset, value } => {
+                // 空の値で存在しない key を作ることはしない
+                if value.is_empty() {
+                    let len = self.read_with(&key, |value| as_string(value).map(<[u8]>::len))??;
+                    return Ok(format!("{}", len));
+                }
+                if offset.saturating_add(value.len()) > MAX_VALUE_LENGTH {
+                    return Err(String::from(MAX_VALUE_LENGTH_ERROR));
+                }
+
+                let len = self.update_string(&key, |current| {
+                    let end = offset + value.len();
+                    if current.len() < end {
+                        // 末尾を越えた部分は 0 で埋める
+                        current.resize(end, 0);
+                    }
+
+                    current[offset..end].copy_from_slice(&value);
+                    Ok(current.len())
+                })?;
+
+                Ok(format!("{}", len))
+            }
+            Command::Expire { key, expire } => {
+                let entry = match self.read_for_update(&key)? {
+                    None => return Ok(String::from("0")),
+                    Some(entry) => entry,
+                };
+
+                // 既に過ぎた時刻を指定された key はその場で削除する
+                let now = expire::now();
+                let deadline = expire.deadline(now);
+                if deadline <= now {
+                    self.remove(&key)?;
+                } else {
+                    let deadline = Some(deadline);
+                    self.write_entry(&key, Entry { deadline, ..entry })?;
+                }
+
+                Ok(String::from("1"))
+            }
+            Command::Ttl { key } => {
+                let ttl = self.ttl(&key)?;
+                // 残りが 1.5 秒なら 2 を返すように、秒単位では四捨五入する
+                Ok(format!(
+                    "{}",
+                    if ttl < 0 { ttl } else { (ttl + 500) / 1000 }
+                ))
+            }
+            Command::PTtl { key } => Ok(format!("{}", self.ttl(&key)?)),
+            Command::Persist { key } => match self.read_for_update(&key)? {
+                Some(entry) if entry.deadline.is_some() => {
+                    let deadline = None;
+                    self.write_entry(&key, Entry { deadline, ..entry })?;
+                    Ok(String::from("1"))
+                }
+                _ => Ok(String::from("0")),
+            },
+            command if command.is_list_command() => list::exec_command(self, command),
+            _ => Err(String::from("ERR unsupport command")),
+        }
+    }
+
+    /// Replaces the value of `key` with what `f` makes of the current one, keeping its
+    /// expiration. A missing key is passed as `None` and created without expiration.
+    fn update(
+        &mut self,
+        key: &str,
+        f: impl FnOnce(Option<&[u8]>) -> Result<Vec<u8>, String>,
+    ) -> Result<Vec<u8>, String> {
+        self.update_value(key, |current| {
+            let value = match current {
+                None => f(None)?,
+                Some(Value::String(current)) => f(Some(current))?,
+                Some(_) => return Err(String::from(WRONGTYPE_ERROR)),
+            };
+
+            *current = Some(Value::String(value.clone()));
+            Ok(value)
+        })
+    }
+
+    /// Looks at `count` keys of one partition from `cursor` on and returns the live ones,
+    /// with the cursor to continue from or 0 once every partition was looked at. The cursor
+    /// holds the partition, see `scan_partition`, and the position in it.
+    ///
+    /// Keys are visited in the order of `scan_position`, which doesn't depend on the
+    /// other keys, so a key that exists for the whole scan is returned at least once
+    /// however the keyspace grows or shrinks in between.
+    fn scan(&mut self, cursor: u64, count: usize) -> Result<(u64, Vec<String>), String> {
+        let partition = scan_partition(cursor);
+        if partition >= self.scan_partitions() {
+            return Ok((0, vec![]));
+        }
+        let cursor = cursor & ((1 << SCAN_PARTITION_SHIFT) - 1);
+
+        // 同じ位置の key は次の cursor から見えなくなるので、ここでまとめて返す。
+        // 次の cursor のために、最後の位置の key の後の1つまで読む
+        let mut limit = count + 1;
+        let mut candidates = loop {
+            let candidates = self.partition_range(partition, cursor, limit)?;
+            if candidates.len() < limit || candidates[limit - 1].0 != candidates[limit - 2].0 {
+                break candidates;
+            }
+            limit *= 2;
+        };
+
+        let mut end = count.min(candidates.len());
+        while end > 0 && end < candidates.len() && candidates[end].0 == candidates[end - 1].0 {
+            end += 1;
+        }
+        let next = match candidates.get(end) {
+            Some((position, _)) => scan_cursor(partition, *position),
+            None if partition + 1 < self.scan_partitions() => scan_cursor(partition + 1, 0),
+            None => 0,
+        };
+
+        candidates.truncate(end);
+        let mut keys = vec![];
+        for (_, key) in candidates {
+            if self.read_with(&key, |value| value.is_some())? {
+                keys.push(key);
+            }
+        }
+
+        Ok((next, keys))
+    }
+
+    /// Milliseconds until `key` expires, -1 when it never expires and -2 when it doesn't exist.
+    fn ttl(&mut self, key: &str) -> Result<i64, String> {
+        Ok(match self.read_entry(key)? {
+            None => -2,
+            Some(Entry { deadline: None, .. }) => -1,
+            Some(Entry {
+                deadline: Some(deadline),
+                ..
+            }) => deadline.saturating_sub(expire::now()) as i64,
+        })
+    }
+}
+
+/// Where `scan` visits `key` in its partition, never 0 so that a cursor can point
+/// at the start of a partition, and small enough to leave room for the partition.
+pub(crate) fn scan_position(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+
+    (hasher.finish() >> (64 - SCAN_PARTITION_SHIFT + 1)) + 1
+}
+
+fn scan_cursor(partition: usize, position: u64) -> u64 {
+    ((partition as u64) << SCAN_PARTITION_SHIFT) | position
+}
+
+/// The partition a `scan` cursor continues in.
+pub(crate) fn scan_partition(cursor: u64) -> usize {
+    (cursor >> SCAN_PARTITION_SHIFT) as usize
+}
+
+/// A string value, where a missing key reads as an empty string.
+fn as_string(value: Option<&Value>) -> Result<&[u8], String> {
+    match value {
+        None => Ok(&[]),
+        Some(Value::String(value)) => Ok(value),
+        Some(_) => Err(String::from(WRONGTYPE_ERROR)),
+    }
+}
+
+/// The indexes `getrange`, `lrange` and `ltrim` select, where negative indexes count
+/// from the end and both ends are inclusive.
+pub(crate) fn index_range(len: usize, start: i64, end: i64) -> std::ops::Range<usize> {
+    let len = len as i64;
+    let index = |i: i64| if i < 0 { (len + i).max(0) } else { i };
+    let (start, end) = (index(start), index(end).min(len - 1));
+
+    if start > end {
+        return 0..0;
+    }
+    start as usize..end as usize + 1
+}
+
+fn parse_integer(value: &[u8]) -> Result<i64, String> {
+    std::str::from_utf8(value)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| String::from(NOT_INTEGER_ERROR))
+}
+
+// "inf" や "NaN" も f64 としては parse できるが、数値としては扱わない
+fn parse_float(value: &[u8]) -> Result<f64, String> {
+    std::str::from_utf8(value)
+        .ok()
+        .and_then(|value| value.parse::<f64>().ok())
+        .filter(|value| value.is_finite())
+        .ok_or_else(|| String::from(NOT_FLOAT_ERROR))
+}
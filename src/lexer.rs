@@ -1,6 +1,7 @@
 #[derive(Debug, Clone)]
 pub struct Lexer {
-    input: String,
+    // 位置は byte ではなく文字で数えるので、ASCII 以外の文字もそのまま読める
+    input: Vec<char>,
     current_position: usize,
     read_position: usize,
     current_ch: char,
@@ -9,7 +10,7 @@ pub struct Lexer {
 impl Lexer {
     pub fn new(input: String) -> Lexer {
         Lexer {
-            input,
+            input: input.chars().collect(),
             current_position: 0,
             read_position: 1,
             current_ch: '\0',
@@ -21,10 +22,7 @@ impl Lexer {
     }
 
     fn get_char(&self, position: usize) -> char {
-        match self.input.chars().nth(position) {
-            Some(c) => c,
-            None => '\0',
-        }
+        self.input.get(position).copied().unwrap_or('\0')
     }
 
     pub fn read_char(&mut self) {
@@ -33,27 +31,66 @@ impl Lexer {
         self.read_position += 1;
     }
 
-    pub fn read_string_literal(&mut self) -> Result<String, String> {
+    /// Reads a double quoted argument. `\"`, `\\`, `\n`, `\r` and `\xNN` (a byte in hex,
+    /// for values that are not UTF-8) are unescaped, and any other backslash is kept as it is.
+    pub fn read_string_literal(&mut self) -> Result<Vec<u8>, String> {
         if self.current_ch != '"' {
             return Err("failed to read string literal".to_string());
         }
 
         self.read_char();
 
-        let mut s = String::new();
+        let mut s = vec![];
+        let mut buf = [0; 4];
 
         while self.current_ch != '"' {
-            s.push(self.current_ch);
-            self.read_char();
-
             if self.is_end() {
                 return Err(String::from("failed to parse input, not found \""));
             }
+
+            if self.current_ch == '\\' {
+                self.read_char();
+                if self.is_end() {
+                    return Err(String::from("failed to parse input, not found \""));
+                }
+
+                match (self.current_ch, self.peek_byte()) {
+                    ('"', _) | ('\\', _) => s.push(self.current_ch as u8),
+                    ('n', _) => s.push(b'\n'),
+                    ('r', _) => s.push(b'\r'),
+                    ('x', Some(byte)) => {
+                        s.push(byte);
+                        self.read_char();
+                        self.read_char();
+                    }
+                    (ch, _) => {
+                        s.push(b'\\');
+                        s.extend_from_slice(ch.encode_utf8(&mut buf).as_bytes());
+                    }
+                }
+            } else {
+                s.extend_from_slice(self.current_ch.encode_utf8(&mut buf).as_bytes());
+            }
+
+            self.read_char();
         }
 
         Ok(s)
     }
 
+    // `\x` に続く2桁の16進数
+    fn peek_byte(&self) -> Option<u8> {
+        let hex = [
+            self.get_char(self.current_position),
+            self.get_char(self.read_position),
+        ];
+        if !hex.iter().all(char::is_ascii_hexdigit) {
+            return None;
+        }
+
+        u8::from_str_radix(&hex.iter().collect::<String>(), 16).ok()
+    }
+
     pub fn read_identifier(&mut self) -> String {
         let start_position = self.current_position - 1;
 
@@ -62,10 +99,11 @@ impl Lexer {
         }
 
         let end_position = self.current_position - 1;
-        self.input[start_position..end_position].to_string()
+        self.input[start_position..end_position].iter().collect()
     }
 
+    // 入力に含まれる '\0' は文字として読めるように、終端は位置で判定する
     pub fn is_end(&self) -> bool {
-        self.current_ch() == '\n' || self.current_ch() == '\0'
+        self.current_ch() == '\n' || self.current_position > self.input.len()
     }
 }
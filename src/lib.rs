@@ -1,13 +1,26 @@
+mod backup;
+mod blocking;
 mod command;
 mod executor;
+mod expire;
+mod glob;
+mod keyspace;
 mod lexer;
+mod list;
 mod locks;
 mod memdb;
 mod parser;
+mod reply;
+mod shards;
+mod snapshot;
 mod transaction;
 
 pub mod utils;
 
+pub use backup::Backup;
 pub use executor::Executor;
+pub use keyspace::Value;
 pub use locks::Locks;
 pub use memdb::Memdb;
+pub use shards::DEFAULT_SHARDS;
+pub use transaction::TransactionLimits;
@@ -0,0 +1,234 @@
+use std::collections::VecDeque;
+
+use crate::command::{Command, ListEnd};
+use crate::keyspace::{index_range, Keyspace, Value, NO_SUCH_KEY_ERROR, WRONGTYPE_ERROR};
+use crate::reply;
+
+/// Runs a command on a list. Like Redis, a list that becomes empty is removed together
+/// with its key, so no key ever holds an empty list.
+///
+/// Blocking pops never block here and reply `None` right away when there is nothing to
+/// pop; waiting for an element is up to the `Executor`.
+pub(crate) fn exec_command(db: &mut impl Keyspace, command: Command) -> Result<String, String> {
+    match command {
+        Command::LPush { key, elements } => {
+            let len = update(db, &key, |list| {
+                elements
+                    .into_iter()
+                    .for_each(|element| list.push_front(element));
+                Ok(list.len())
+            })?;
+
+            Ok(format!("{}", len))
+        }
+        Command::RPush { key, elements } => {
+            let len = update(db, &key, |list| {
+                elements
+                    .into_iter()
+                    .for_each(|element| list.push_back(element));
+                Ok(list.len())
+            })?;
+
+            Ok(format!("{}", len))
+        }
+        Command::LPop { key, count } => pop(db, &key, count, VecDeque::pop_front),
+        Command::RPop { key, count } => pop(db, &key, count, VecDeque::pop_back),
+        Command::LRange { key, start, stop } => read(db, &key, |list| {
+            let range = index_range(list.len(), start, stop);
+            reply::array(list.range(range).map(|element| text(element)).collect())
+        }),
+        Command::LLen { key } => read(db, &key, |list| format!("{}", list.len())),
+        Command::LIndex { key, index } => {
+            read(db, &key, |list| match position(list.len(), index) {
+                Some(i) => text(&list[i]),
+                None => String::from("None"),
+            })
+        }
+        Command::LSet {
+            key,
+            index,
+            element,
+        } => update(db, &key, |list| {
+            if list.is_empty() {
+                return Err(String::from(NO_SUCH_KEY_ERROR));
+            }
+            let i = position(list.len(), index)
+                .ok_or_else(|| String::from("ERR index out of range"))?;
+
+            list[i] = element;
+            Ok(String::from("OK"))
+        }),
+        Command::LRem {
+            key,
+            count,
+            element,
+        } => {
+            let removed = update(db, &key, |list| {
+                let element = element.as_slice();
+                let limit = match count {
+                    0 => usize::MAX,
+                    count => count.unsigned_abs() as usize,
+                };
+
+                // count が負の場合は末尾から探す
+                let mut matched = list
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, e)| e.as_slice() == element)
+                    .map(|(i, _)| i)
+                    .collect::<Vec<_>>();
+                if count < 0 {
+                    matched.reverse();
+                }
+                matched.truncate(limit);
+                matched.sort_unstable();
+
+                // 後ろから削除すれば、まだ削除していない位置はずれない
+                matched.iter().rev().for_each(|i| {
+                    list.remove(*i);
+                });
+                Ok(matched.len())
+            })?;
+
+            Ok(format!("{}", removed))
+        }
+        Command::LTrim { key, start, stop } => update(db, &key, |list| {
+            let range = index_range(list.len(), start, stop);
+            list.truncate(range.end);
+            list.drain(..range.start);
+
+            Ok(String::from("OK"))
+        }),
+        Command::BLPop { keys, .. } => pop_first(db, &keys, ListEnd::Left),
+        Command::BRPop { keys, .. } => pop_first(db, &keys, ListEnd::Right),
+        Command::BLMove {
+            source,
+            destination,
+            from,
+            to,
+            ..
+        } => {
+            // 取り出してから失敗しないように、先に移動先の型を確かめる
+            read(db, &destination, |_| ())?;
+
+            let element = match update(db, &source, |list| Ok(pop_end(list, from)))? {
+                None => return Ok(String::from("None")),
+                Some(element) => element,
+            };
+            let reply = text(&element);
+            update(db, &destination, |list| {
+                match to {
+                    ListEnd::Left => list.push_front(element),
+                    ListEnd::Right => list.push_back(element),
+                }
+                Ok(())
+            })?;
+
+            Ok(reply)
+        }
+        _ => Err(String::from("ERR unsupport command")),
+    }
+}
+
+// count が指定されていない場合は要素を1つ、指定されている場合は配列を返す
+fn pop(
+    db: &mut impl Keyspace,
+    key: &str,
+    count: Option<usize>,
+    pop: fn(&mut VecDeque<Vec<u8>>) -> Option<Vec<u8>>,
+) -> Result<String, String> {
+    update(db, key, |list| {
+        if list.is_empty() {
+            return Ok(String::from("None"));
+        }
+
+        Ok(match count {
+            None => text(&pop(list).unwrap()),
+            Some(count) => reply::array(
+                (0..count)
+                    .map_while(|_| pop(list))
+                    .map(|element| text(&element))
+                    .collect(),
+            ),
+        })
+    })
+}
+
+// 最初に要素を取り出せた key と要素を返す
+fn pop_first(db: &mut impl Keyspace, keys: &[String], end: ListEnd) -> Result<String, String> {
+    for key in keys {
+        if let Some(element) = update(db, key, |list| Ok(pop_end(list, end)))? {
+            return Ok(reply::array(vec![key.to_owned(), text(&element)]));
+        }
+    }
+
+    Ok(String::from("None"))
+}
+
+fn pop_end(list: &mut VecDeque<Vec<u8>>, end: ListEnd) -> Option<Vec<u8>> {
+    match end {
+        ListEnd::Left => list.pop_front(),
+        ListEnd::Right => list.pop_back(),
+    }
+}
+
+/// True when a blocking pop has something to do right away: one of the keys it waits
+/// on exists, so it either holds elements or fails with `WRONGTYPE`.
+pub(crate) fn is_ready(db: &mut impl Keyspace, command: &Command) -> Result<bool, String> {
+    for key in command.blocking_keys() {
+        if db.read_with(key, |value| value.is_some())? {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Passes the list at `key` to `f`, where a missing key reads as an empty list.
+fn read<R>(
+    db: &mut impl Keyspace,
+    key: &str,
+    f: impl FnOnce(&VecDeque<Vec<u8>>) -> R,
+) -> Result<R, String> {
+    db.read_with(key, |value| match value {
+        None => Ok(f(&VecDeque::new())),
+        Some(Value::List(list)) => Ok(f(list)),
+        Some(_) => Err(String::from(WRONGTYPE_ERROR)),
+    })?
+}
+
+/// Changes the list at `key` in place. A missing key is passed as an empty list, and
+/// the key is only created when `f` leaves elements in it.
+fn update<R>(
+    db: &mut impl Keyspace,
+    key: &str,
+    f: impl FnOnce(&mut VecDeque<Vec<u8>>) -> Result<R, String>,
+) -> Result<R, String> {
+    db.update_value(key, |value| {
+        let mut list = match value.take() {
+            None => VecDeque::new(),
+            Some(Value::List(list)) => list,
+            Some(other) => {
+                *value = Some(other);
+                return Err(String::from(WRONGTYPE_ERROR));
+            }
+        };
+
+        let result = f(&mut list);
+        if !list.is_empty() {
+            *value = Some(Value::List(list));
+        }
+        result
+    })
+}
+
+/// The position of `index` in a list of `len` elements, counting from the end when negative.
+fn position(len: usize, index: i64) -> Option<usize> {
+    let index = if index < 0 { len as i64 + index } else { index };
+
+    Some(index as usize).filter(|_| index >= 0 && (index as usize) < len)
+}
+
+fn text(element: &[u8]) -> String {
+    String::from_utf8_lossy(element).into_owned()
+}
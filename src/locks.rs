@@ -1,13 +1,80 @@
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::glob;
+
+/// Identifies the transaction that owns or waits for a lock.
+///
+/// Ids are handed out in increasing order, so a larger id is a younger transaction.
+pub type TxId = u64;
+
+pub const DEADLOCK_ERROR: &str = "DEADLOCK transaction was aborted to break a lock cycle";
+pub const LOCKTIMEOUT_ERROR: &str = "LOCKTIMEOUT could not acquire lock in time";
+
+/// How long a lock request may wait for conflicting holders.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default)]
+pub enum LockWait {
+    /// Use the default timeout of the `Locks`, which waits forever unless configured.
+    #[default]
+    Default,
+    Timeout(Duration),
+    /// Fail immediately instead of waiting.
+    NoWait,
+}
 
 #[derive(Default, Debug)]
 pub struct Locks {
-    // NOTE sync::RwLock で出来そうだが
-    // ライフタイムの関係で今の自分の実力ではよく分からなかったので
-    // RWLock enumで管理するようにしている。
-    // TODO @k-nasa コレで良いのか判断してくれ！ deadline: 2020/2/31
-    hashmap: Arc<Mutex<HashMap<String, RWLock>>>,
+    table: Arc<Mutex<LockTable>>,
+    default_timeout: Option<Duration>,
+}
+
+#[derive(Default, Debug)]
+struct LockTable {
+    entries: HashMap<String, KeyLock>,
+    // the key each blocked transaction is queued on; these are the edges of the wait-for graph
+    waiting: HashMap<TxId, Wait>,
+    // transactions aborted by someone else (deadlock victims, `Locks::abort`) that have not
+    // noticed it yet, and the error they fail with
+    aborted: HashMap<TxId, String>,
+    stats: LockStats,
+}
+
+#[derive(Debug)]
+struct Wait {
+    key: String,
+    mode: LockMode,
+    since: Instant,
+}
+
+/// Counters reported by `info`.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub(crate) struct LockStats {
+    /// Lock requests that had to queue behind conflicting holders.
+    pub waits: u64,
+    /// Requests that failed with `LOCKTIMEOUT_ERROR`, including `LockWait::NoWait` ones.
+    pub timeouts: u64,
+    pub deadlocks: u64,
+}
+
+/// A key in the lock table, as shown by `locks list`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct LockInfo {
+    pub key: String,
+    /// `None` while nobody holds the key and only waiters are queued.
+    pub mode: Option<LockMode>,
+    pub owners: Vec<TxId>,
+    /// Queued transactions in the order they will be granted, and how long they have waited.
+    pub waiters: Vec<(TxId, Duration)>,
+}
+
+/// A blocked lock request, as shown by `locks waiters`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct WaitInfo {
+    pub owner: TxId,
+    pub key: String,
+    pub waited: Duration,
+    pub blockers: Vec<TxId>,
 }
 
 #[derive(Debug, PartialOrd, PartialEq)]
@@ -16,177 +83,923 @@ enum RWLock {
     Write,
 }
 
+impl Default for RWLock {
+    fn default() -> RWLock {
+        RWLock::Read(0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LockMode {
+    Read,
+    Write,
+}
+
+/// State of a single key: who holds it, and who is queued behind them.
+///
+/// Waiters are granted strictly in arrival order, so a steady stream of
+/// readers cannot starve a queued writer.
+#[derive(Default, Debug)]
+struct KeyLock {
+    lock: RWLock,
+    owners: Vec<TxId>,
+    waiters: VecDeque<TxId>,
+    condvar: Arc<Condvar>,
+}
+
+impl KeyLock {
+    fn is_compatible(&self, owner: TxId, mode: LockMode) -> bool {
+        match (&self.lock, mode) {
+            (RWLock::Read(_), LockMode::Read) => true,
+            // 同じ transaction が何度も read lock を取っていても、他に reader がいなければ upgrade できる
+            (RWLock::Read(_), LockMode::Write) => self.owners.iter().all(|o| *o == owner),
+            _ => false,
+        }
+    }
+
+    fn is_reentrant(&self, owner: TxId, mode: LockMode) -> bool {
+        mode == LockMode::Read && self.owners.contains(&owner) && self.is_compatible(owner, mode)
+    }
+
+    fn is_upgrade(&self, owner: TxId, mode: LockMode) -> bool {
+        mode == LockMode::Write && self.lock != RWLock::Write && self.owners.contains(&owner)
+    }
+
+    fn grant(&mut self, owner: TxId, mode: LockMode) {
+        match mode {
+            LockMode::Read => match &mut self.lock {
+                RWLock::Read(count) => *count += 1,
+                RWLock::Write => unreachable!(),
+            },
+            LockMode::Write => {
+                self.lock = RWLock::Write;
+                self.owners.clear();
+            }
+        }
+        self.owners.push(owner);
+    }
+
+    fn is_idle(&self) -> bool {
+        self.lock == RWLock::Read(0) && self.waiters.is_empty()
+    }
+
+    /// Everyone `owner` has to wait for: the current holders, and since the queue
+    /// is FIFO, every waiter ahead of it.
+    fn blockers(&self, owner: TxId) -> Vec<TxId> {
+        let ahead = self.waiters.iter().take_while(|w| **w != owner);
+
+        self.owners
+            .iter()
+            .copied()
+            .chain(ahead.copied())
+            .filter(|o| *o != owner)
+            .collect()
+    }
+}
+
+impl LockTable {
+    fn release(&mut self, key: &str) {
+        let entry = self.entries.get_mut(key).unwrap();
+
+        if entry.is_idle() {
+            self.entries.remove(key);
+        } else if !entry.waiters.is_empty() {
+            entry.condvar.notify_all();
+        }
+    }
+
+    fn release_all(&mut self, owner: TxId) {
+        let keys = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.owners.contains(&owner))
+            .map(|(key, _)| key.to_owned())
+            .collect::<Vec<_>>();
+
+        for key in keys {
+            self.release_owner(owner, &key);
+        }
+    }
+
+    // owner が key に持っている lock を全て開放する。持っていない場合は何もしない
+    fn release_owner(&mut self, owner: TxId, key: &str) {
+        let entry = match self.entries.get_mut(key) {
+            Some(entry) if entry.owners.contains(&owner) => entry,
+            _ => return,
+        };
+
+        // 同じtransactionが何度もread lockを取った場合、owners にはその回数だけ入っている
+        entry.owners.retain(|o| *o != owner);
+        entry.lock = RWLock::Read(match entry.lock {
+            RWLock::Write => 0,
+            RWLock::Read(_) => entry.owners.len(),
+        });
+
+        self.release(key);
+    }
+
+    /// False once a queued request was aborted or can be granted, and only has to
+    /// wake up to notice it.
+    fn is_blocked(&self, owner: TxId, wait: &Wait) -> bool {
+        let entry = &self.entries[&wait.key];
+        let grantable =
+            entry.waiters.front() == Some(&owner) && entry.is_compatible(owner, wait.mode);
+
+        !grantable && !self.aborted.contains_key(&owner)
+    }
+
+    fn cancel_wait(&mut self, owner: TxId, key: &str) {
+        self.waiting.remove(&owner);
+
+        let entry = self.entries.get_mut(key).unwrap();
+        entry.waiters.retain(|w| *w != owner);
+
+        self.release(key);
+    }
+
+    /// Looks for a cycle in the wait-for graph that goes through `owner`
+    /// and picks the youngest transaction on it as the victim.
+    fn find_deadlock_victim(&self, owner: TxId) -> Option<TxId> {
+        let mut path = vec![owner];
+        let mut visited = HashSet::new();
+
+        if self.find_cycle(owner, &mut path, &mut visited) {
+            path.into_iter().max()
+        } else {
+            None
+        }
+    }
+
+    fn find_cycle(&self, start: TxId, path: &mut Vec<TxId>, visited: &mut HashSet<TxId>) -> bool {
+        let current = *path.last().unwrap();
+
+        let key = match self.waiting.get(&current) {
+            Some(wait) if !self.aborted.contains_key(&current) => &wait.key,
+            _ => return false,
+        };
+
+        for next in self.entries[key].blockers(current) {
+            if next == start {
+                return true;
+            }
+
+            if visited.insert(next) {
+                path.push(next);
+                if self.find_cycle(start, path, visited) {
+                    return true;
+                }
+                path.pop();
+            }
+        }
+
+        false
+    }
+}
+
 impl Locks {
     pub fn new() -> Locks {
         Locks {
-            hashmap: Arc::new(Mutex::new(HashMap::new())),
+            table: Arc::new(Mutex::new(LockTable::default())),
+            default_timeout: None,
         }
     }
 
-    pub fn read_lock(&mut self, key: &str) {
-        // FIXME Write lock の開放をループで待って良いのか？という気持ち
-
-        loop {
-            let mut hashmap = self.hashmap.lock().unwrap();
-
-            if let Some(lock) = hashmap.get_mut(key) {
-                match lock {
-                    RWLock::Read(count) => *count += 1,
-                    RWLock::Write => continue,
-                };
-                break;
-            } else {
-                hashmap.insert(key.to_owned(), RWLock::Read(1));
-                break;
-            }
+    /// Lock requests made with `LockWait::Default` give up after `timeout`.
+    pub fn with_timeout(timeout: Duration) -> Locks {
+        Locks {
+            default_timeout: Some(timeout),
+            ..Locks::new()
         }
     }
 
-    pub fn read_unlock(&mut self, key: &str) {
+    pub fn read_lock(&self, owner: TxId, key: &str, wait: LockWait) -> Result<(), String> {
+        self.acquire(owner, key, LockMode::Read, wait)
+    }
+
+    pub fn read_unlock(&self, owner: TxId, key: &str) {
         // FIXME refactor this method
         // Resultを返すようにしましょう
 
-        let mut hashmap = self.hashmap.lock().unwrap();
+        let mut table = self.table.lock().unwrap();
 
-        if let Some(lock) = hashmap.get_mut(key) {
-            match lock {
-                RWLock::Read(count) => {
-                    *count -= 1;
-                    if *count == 0 {
-                        hashmap.remove(key);
-                    }
-                }
+        if let Some(entry) = table.entries.get_mut(key) {
+            match &mut entry.lock {
+                RWLock::Read(0) => panic!("not found read lock"),
+                RWLock::Read(count) => *count -= 1,
                 RWLock::Write => panic!("Attempting to release write lock"),
             };
+
+            match entry.owners.iter().position(|o| *o == owner) {
+                Some(i) => entry.owners.remove(i),
+                None => panic!("not found read lock"),
+            };
+
+            table.release(key);
         } else {
             panic!("not found read lock")
         }
     }
 
-    pub fn write_lock(&mut self, key: &str) {
-        // FIXME read lock の開放をループで待って良いのか？という気持ち
-        loop {
-            let mut hashmap = self.hashmap.lock().unwrap();
+    /// Releases every lock `owner` holds. Locks that are not held are skipped, so this
+    /// can be called again after `abort` took the locks away.
+    pub fn release_all(&self, owner: TxId) {
+        self.table.lock().unwrap().release_all(owner);
+    }
 
-            if let Some(lock) = hashmap.get_mut(key) {
-                match lock {
-                    RWLock::Read(0) => {
-                        hashmap.insert(key.to_owned(), RWLock::Write);
-                        break;
-                    }
-                    _ => continue,
-                };
-            } else {
-                hashmap.insert(key.to_owned(), RWLock::Write);
-                break;
-            }
+    /// Aborts `owner` from outside the transaction: its locks are released right away,
+    /// and the lock request it is parked on, or its next one, fails with `reason`.
+    pub fn abort(&self, owner: TxId, reason: &str) {
+        let mut table = self.table.lock().unwrap();
+        table.release_all(owner);
+
+        if let Some(wait) = table.waiting.get(&owner) {
+            table.entries[&wait.key].condvar.notify_all();
         }
+        table.aborted.insert(owner, reason.to_owned());
+    }
+
+    /// Takes the reason `owner` was aborted with, if it was aborted by `abort`
+    /// and has not noticed it yet.
+    pub fn abort_reason(&self, owner: TxId) -> Option<String> {
+        self.table.lock().unwrap().aborted.remove(&owner)
+    }
+
+    /// Every key in the lock table whose name matches `pattern`, sorted by key.
+    pub(crate) fn list(&self, pattern: Option<&str>) -> Vec<LockInfo> {
+        let table = self.table.lock().unwrap();
+        let now = Instant::now();
+
+        let mut locks = table
+            .entries
+            .iter()
+            .filter(|(key, _)| pattern.is_none_or(|pattern| glob::matches(pattern, key)))
+            .map(|(key, entry)| {
+                // 同じtransactionが何度もread lockを取った場合、owners にはその回数だけ入っている
+                let mut owners = entry.owners.clone();
+                owners.sort_unstable();
+                owners.dedup();
+
+                LockInfo {
+                    key: key.to_owned(),
+                    mode: match entry.lock {
+                        RWLock::Write => Some(LockMode::Write),
+                        RWLock::Read(0) => None,
+                        RWLock::Read(_) => Some(LockMode::Read),
+                    },
+                    owners,
+                    waiters: entry
+                        .waiters
+                        .iter()
+                        .map(|w| (*w, now - table.waiting[w].since))
+                        .collect(),
+                }
+            })
+            .collect::<Vec<_>>();
+        locks.sort_by(|a, b| a.key.cmp(&b.key));
+
+        locks
+    }
+
+    /// Every blocked lock request, longest waiting first.
+    ///
+    /// Requests that were already granted or aborted but whose thread has not woken up
+    /// yet are left out, so an empty list means no client is stuck on a lock.
+    pub(crate) fn waiters(&self) -> Vec<WaitInfo> {
+        let table = self.table.lock().unwrap();
+        let now = Instant::now();
+
+        let mut waiters = table
+            .waiting
+            .iter()
+            .filter(|(owner, wait)| table.is_blocked(**owner, wait))
+            .map(|(owner, wait)| {
+                let mut blockers = table.entries[&wait.key].blockers(*owner);
+                blockers.sort_unstable();
+                blockers.dedup();
+
+                WaitInfo {
+                    owner: *owner,
+                    key: wait.key.to_owned(),
+                    waited: now - wait.since,
+                    blockers,
+                }
+            })
+            .collect::<Vec<_>>();
+        waiters.sort_by(|a, b| b.waited.cmp(&a.waited).then(a.owner.cmp(&b.owner)));
+
+        waiters
     }
 
-    pub fn write_unlock(&mut self, key: &str) {
+    /// Releases the locks `owner` holds on `keys`, without looking at the rest of the
+    /// table like `release_all` does. Keys it doesn't hold are skipped.
+    pub fn release_keys(&self, owner: TxId, keys: &[String]) {
+        let mut table = self.table.lock().unwrap();
+        keys.iter().for_each(|key| table.release_owner(owner, key));
+    }
+
+    pub(crate) fn stats(&self) -> LockStats {
+        self.table.lock().unwrap().stats
+    }
+
+    pub fn write_lock(&self, owner: TxId, key: &str, wait: LockWait) -> Result<(), String> {
+        self.acquire(owner, key, LockMode::Write, wait)
+    }
+
+    pub fn write_unlock(&self, owner: TxId, key: &str) {
         // FIXME refactor this method
         // Resultを返すようにしましょう
 
-        let mut hashmap = self.hashmap.lock().unwrap();
+        let mut table = self.table.lock().unwrap();
 
-        if let Some(lock) = hashmap.get_mut(key) {
-            match lock {
-                RWLock::Write => hashmap.remove(key),
+        if let Some(entry) = table.entries.get_mut(key) {
+            match entry.lock {
+                RWLock::Write if entry.owners == [owner] => entry.lock = RWLock::Read(0),
+                RWLock::Write => panic!("Attempting to release write lock of other transaction"),
+                RWLock::Read(0) => panic!("not found write lock"),
                 RWLock::Read(_) => panic!("Attempting to release read lock"),
             };
+            entry.owners.clear();
+
+            table.release(key);
         } else {
             panic!("not found write lock")
         }
     }
+
+    /// Grants the lock right away when nobody is queued on the key and the current
+    /// holders are compatible, otherwise parks the caller on the key's condition
+    /// variable until it reaches the head of the queue.
+    ///
+    /// Before parking, the wait-for graph is checked for a cycle. The youngest
+    /// transaction in the cycle is aborted with `DEADLOCK_ERROR`; if that is not
+    /// the caller, the victim is woken up and gives up its place in its queue.
+    ///
+    /// A transaction that is the only reader of a key, however many read locks it took,
+    /// can upgrade to a write lock;
+    /// while other readers remain, the upgrade waits at the head of the queue.
+    ///
+    /// A request that is still queued when its timeout expires fails with
+    /// `LOCKTIMEOUT_ERROR`, and with `LockWait::NoWait` it fails without queueing.
+    fn acquire(
+        &self,
+        owner: TxId,
+        key: &str,
+        mode: LockMode,
+        wait: LockWait,
+    ) -> Result<(), String> {
+        let mut table = self.table.lock().unwrap();
+
+        if let Some(reason) = table.aborted.remove(&owner) {
+            return Err(reason);
+        }
+
+        let entry = table.entries.entry(key.to_owned()).or_default();
+
+        // upgrade する transaction は既に read lock を持っているので、後ろに並んでいる
+        // write lock を待たずに取得できる。待つ場合も queue の先頭に並ぶ
+        let upgrade = entry.is_upgrade(owner, mode);
+
+        if ((entry.waiters.is_empty() || upgrade) && entry.is_compatible(owner, mode))
+            || entry.is_reentrant(owner, mode)
+        {
+            entry.grant(owner, mode);
+            return Ok(());
+        }
+
+        let deadline = match wait {
+            LockWait::NoWait => {
+                table.stats.timeouts += 1;
+                return Err(String::from(LOCKTIMEOUT_ERROR));
+            }
+            LockWait::Timeout(timeout) => Some(Instant::now() + timeout),
+            LockWait::Default => self.default_timeout.map(|t| Instant::now() + t),
+        };
+
+        if upgrade {
+            entry.waiters.push_front(owner);
+        } else {
+            entry.waiters.push_back(owner);
+        }
+        let condvar = entry.condvar.clone();
+        table.waiting.insert(
+            owner,
+            Wait {
+                key: key.to_owned(),
+                mode,
+                since: Instant::now(),
+            },
+        );
+        table.stats.waits += 1;
+
+        loop {
+            if let Some(reason) = table.aborted.remove(&owner) {
+                table.cancel_wait(owner, key);
+                return Err(reason);
+            }
+
+            let entry = table
+                .entries
+                .get_mut(key)
+                .expect("lock entry removed while waiting");
+            let is_head = entry.waiters.front() == Some(&owner);
+
+            if is_head && entry.is_compatible(owner, mode) {
+                entry.waiters.pop_front();
+                entry.grant(owner, mode);
+
+                // 後ろに並んでいる read lock もまとめて取得できる可能性があるので起こす
+                if !entry.waiters.is_empty() {
+                    condvar.notify_all();
+                }
+                table.waiting.remove(&owner);
+                return Ok(());
+            }
+
+            if let Some(victim) = table.find_deadlock_victim(owner) {
+                table.stats.deadlocks += 1;
+
+                if victim == owner {
+                    table.cancel_wait(owner, key);
+                    return Err(String::from(DEADLOCK_ERROR));
+                }
+
+                let victim_key = &table.waiting[&victim].key;
+                table.entries[victim_key].condvar.notify_all();
+                table.aborted.insert(victim, String::from(DEADLOCK_ERROR));
+            }
+
+            table = match deadline {
+                None => condvar.wait(table).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        table.cancel_wait(owner, key);
+                        table.stats.timeouts += 1;
+                        return Err(String::from(LOCKTIMEOUT_ERROR));
+                    }
+
+                    condvar.wait_timeout(table, deadline - now).unwrap().0
+                }
+            };
+        }
+    }
 }
 
 #[test]
 fn test_read_lock() {
-    let mut locks = Locks::new();
+    let locks = Locks::new();
 
     let key = "key";
 
-    locks.read_lock(key);
-    locks.read_lock(key);
-    locks.read_lock(key);
-    locks.read_lock(key);
+    locks.read_lock(1, key, LockWait::Default).unwrap();
+    locks.read_lock(1, key, LockWait::Default).unwrap();
+    locks.read_lock(1, key, LockWait::Default).unwrap();
+    locks.read_lock(1, key, LockWait::Default).unwrap();
 
-    let hashmap = locks.hashmap.lock().unwrap();
-    let lock = hashmap.get(key).unwrap();
+    let table = locks.table.lock().unwrap();
+    let lock = &table.entries.get(key).unwrap().lock;
 
     assert_eq!(lock, &RWLock::Read(4));
 }
 
 #[test]
 fn test_read_unlock() {
-    let mut locks = Locks::new();
+    let locks = Locks::new();
 
     let key = "key";
 
-    locks.read_lock(key);
-    locks.read_unlock(key);
+    locks.read_lock(1, key, LockWait::Default).unwrap();
+    locks.read_unlock(1, key);
 
-    let hashmap = locks.hashmap.lock().unwrap();
-    assert!(hashmap.get(key).is_none());
+    let table = locks.table.lock().unwrap();
+    assert!(!table.entries.contains_key(key));
 }
 
 #[test]
 fn test_write_lock() {
-    let mut locks = Locks::new();
+    let locks = Locks::new();
 
     let key = "key";
 
-    locks.write_lock(key);
+    locks.write_lock(1, key, LockWait::Default).unwrap();
 
-    let hashmap = locks.hashmap.lock().unwrap();
-    let lock = hashmap.get(key).unwrap();
+    let table = locks.table.lock().unwrap();
+    let lock = &table.entries.get(key).unwrap().lock;
 
     assert_eq!(lock, &RWLock::Write);
 }
 
 #[test]
 fn test_write_unlock() {
-    let mut locks = Locks::new();
+    let locks = Locks::new();
 
     let key = "key";
 
-    locks.write_lock(key);
-    locks.write_unlock(key);
+    locks.write_lock(1, key, LockWait::Default).unwrap();
+    locks.write_unlock(1, key);
 
-    let hashmap = locks.hashmap.lock().unwrap();
-    assert!(hashmap.get(key).is_none());
+    let table = locks.table.lock().unwrap();
+    assert!(!table.entries.contains_key(key));
 }
 
 #[test]
 #[should_panic]
 fn test_write_unlock_panic_when_not_found_key() {
-    let mut locks = Locks::new();
+    let locks = Locks::new();
 
-    locks.write_unlock("not found key");
+    locks.write_unlock(1, "not found key");
 }
 
 #[test]
 #[should_panic]
 fn test_write_unlock_panic_when_read_lock() {
-    let mut locks = Locks::new();
+    let locks = Locks::new();
 
-    locks.read_lock("key");
-    locks.write_unlock("key");
+    locks.read_lock(1, "key", LockWait::Default).unwrap();
+    locks.write_unlock(1, "key");
 }
 
 #[test]
 #[should_panic]
 fn test_read_unlock_panic_when_not_found_key() {
-    let mut locks = Locks::new();
+    let locks = Locks::new();
 
-    locks.read_unlock("not found key");
+    locks.read_unlock(1, "not found key");
 }
 
 #[test]
 #[should_panic]
 fn test_write_unlock_panic_when_write_lock() {
-    let mut locks = Locks::new();
+    let locks = Locks::new();
+
+    locks.write_lock(1, "key", LockWait::Default).unwrap();
+    locks.read_unlock(1, "key");
+}
+
+#[cfg(test)]
+fn wait_until_queued(locks: &Locks, key: &str, count: usize) {
+    while locks
+        .table
+        .lock()
+        .unwrap()
+        .entries
+        .get(key)
+        .map_or(0, |entry| entry.waiters.len())
+        < count
+    {
+        std::thread::yield_now();
+    }
+}
+
+#[test]
+fn test_read_lock_waits_for_write_unlock() {
+    let locks = Arc::new(Locks::new());
+    locks.write_lock(1, "key", LockWait::Default).unwrap();
+
+    let reader = {
+        let locks = locks.clone();
+        std::thread::spawn(move || locks.read_lock(2, "key", LockWait::Default).unwrap())
+    };
+
+    wait_until_queued(&locks, "key", 1);
+    locks.write_unlock(1, "key");
+    reader.join().unwrap();
+
+    let table = locks.table.lock().unwrap();
+    let entry = table.entries.get("key").unwrap();
+    assert_eq!(entry.lock, RWLock::Read(1));
+    assert_eq!(entry.owners, vec![2]);
+}
 
-    locks.write_lock("key");
-    locks.read_unlock("key");
+#[test]
+fn test_queued_writer_is_not_starved_by_readers() {
+    let locks = Arc::new(Locks::new());
+    locks.read_lock(1, "key", LockWait::Default).unwrap();
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+
+    let writer = {
+        let (locks, sender) = (locks.clone(), sender.clone());
+        std::thread::spawn(move || {
+            locks.write_lock(2, "key", LockWait::Default).unwrap();
+            sender.send("write").unwrap();
+            locks.write_unlock(2, "key");
+        })
+    };
+    wait_until_queued(&locks, "key", 1);
+
+    // the writer is queued, so a new reader has to wait behind it
+    let reader = {
+        let locks = locks.clone();
+        std::thread::spawn(move || {
+            locks.read_lock(3, "key", LockWait::Default).unwrap();
+            sender.send("read").unwrap();
+            locks.read_unlock(3, "key");
+        })
+    };
+    wait_until_queued(&locks, "key", 2);
+
+    locks.read_unlock(1, "key");
+    writer.join().unwrap();
+    reader.join().unwrap();
+
+    assert_eq!(receiver.iter().collect::<Vec<_>>(), vec!["write", "read"]);
+    assert!(!locks.table.lock().unwrap().entries.contains_key("key"));
+}
+
+#[test]
+fn test_deadlock_aborts_requesting_transaction_when_youngest() {
+    let locks = Arc::new(Locks::new());
+    locks.write_lock(1, "a", LockWait::Default).unwrap();
+    locks.write_lock(2, "b", LockWait::Default).unwrap();
+
+    let older = {
+        let locks = locks.clone();
+        std::thread::spawn(move || locks.write_lock(1, "b", LockWait::Default))
+    };
+    wait_until_queued(&locks, "b", 1);
+
+    assert_eq!(
+        locks.write_lock(2, "a", LockWait::Default),
+        Err(String::from(DEADLOCK_ERROR))
+    );
+
+    locks.write_unlock(2, "b");
+    assert_eq!(older.join().unwrap(), Ok(()));
+}
+
+#[test]
+fn test_deadlock_aborts_waiting_transaction_when_youngest() {
+    let locks = Arc::new(Locks::new());
+    locks.write_lock(2, "a", LockWait::Default).unwrap();
+    locks.write_lock(1, "b", LockWait::Default).unwrap();
+
+    let younger = {
+        let locks = locks.clone();
+        std::thread::spawn(move || {
+            let result = locks.write_lock(2, "b", LockWait::Default);
+            locks.write_unlock(2, "a");
+            result
+        })
+    };
+    wait_until_queued(&locks, "b", 1);
+
+    assert_eq!(locks.write_lock(1, "a", LockWait::Default), Ok(()));
+    assert_eq!(younger.join().unwrap(), Err(String::from(DEADLOCK_ERROR)));
+
+    let table = locks.table.lock().unwrap();
+    assert!(table.waiting.is_empty());
+    assert!(table.aborted.is_empty());
+}
+
+#[test]
+fn test_nowait_fails_on_conflict() {
+    let locks = Locks::new();
+    locks.read_lock(1, "key", LockWait::Default).unwrap();
+
+    assert_eq!(
+        locks.write_lock(2, "key", LockWait::NoWait),
+        Err(String::from(LOCKTIMEOUT_ERROR))
+    );
+    assert_eq!(locks.read_lock(2, "key", LockWait::NoWait), Ok(()));
+}
+
+#[test]
+fn test_lock_wait_timeout() {
+    let locks = Locks::with_timeout(Duration::from_millis(10));
+    locks.write_lock(1, "key", LockWait::Default).unwrap();
+
+    assert_eq!(
+        locks.read_lock(2, "key", LockWait::Default),
+        Err(String::from(LOCKTIMEOUT_ERROR))
+    );
+    assert_eq!(
+        locks.read_lock(2, "key", LockWait::Timeout(Duration::from_millis(10))),
+        Err(String::from(LOCKTIMEOUT_ERROR))
+    );
+
+    let table = locks.table.lock().unwrap();
+    assert!(table.entries["key"].waiters.is_empty());
+    assert!(table.waiting.is_empty());
+}
+
+#[test]
+fn test_upgrade_read_lock_of_sole_reader() {
+    let locks = Locks::new();
+    locks.read_lock(1, "key", LockWait::Default).unwrap();
+    locks.write_lock(1, "key", LockWait::Default).unwrap();
+
+    {
+        let table = locks.table.lock().unwrap();
+        let entry = &table.entries["key"];
+        assert_eq!(entry.lock, RWLock::Write);
+        assert_eq!(entry.owners, vec![1]);
+    }
+
+    locks.write_unlock(1, "key");
+    assert!(!locks.table.lock().unwrap().entries.contains_key("key"));
+}
+
+#[test]
+fn test_upgrade_read_lock_taken_twice() {
+    let locks = Locks::new();
+
+    locks.read_lock(1, "key", LockWait::Default).unwrap();
+    locks.read_lock(1, "key", LockWait::Default).unwrap();
+    locks.write_lock(1, "key", LockWait::NoWait).unwrap();
+
+    assert!(locks.read_lock(2, "key", LockWait::NoWait).is_err());
+    locks.write_unlock(1, "key");
+    locks.read_lock(2, "key", LockWait::NoWait).unwrap();
+}
+
+#[test]
+fn test_upgrade_waits_for_other_readers() {
+    let locks = Arc::new(Locks::new());
+    locks.read_lock(1, "key", LockWait::Default).unwrap();
+    locks.read_lock(2, "key", LockWait::Default).unwrap();
+
+    assert_eq!(
+        locks.write_lock(1, "key", LockWait::NoWait),
+        Err(String::from(LOCKTIMEOUT_ERROR))
+    );
+
+    // a writer that queued first must not get ahead of the upgrade
+    let writer = {
+        let locks = locks.clone();
+        std::thread::spawn(move || locks.write_lock(3, "key", LockWait::Default))
+    };
+    wait_until_queued(&locks, "key", 1);
+
+    let upgrader = {
+        let locks = locks.clone();
+        std::thread::spawn(move || {
+            locks.write_lock(1, "key", LockWait::Default).unwrap();
+            locks.write_unlock(1, "key");
+        })
+    };
+    wait_until_queued(&locks, "key", 2);
+    assert_eq!(locks.table.lock().unwrap().entries["key"].waiters[0], 1);
+
+    locks.read_unlock(2, "key");
+    upgrader.join().unwrap();
+    assert_eq!(writer.join().unwrap(), Ok(()));
+}
+
+#[test]
+fn test_concurrent_upgrades_deadlock() {
+    let locks = Arc::new(Locks::new());
+    locks.read_lock(1, "key", LockWait::Default).unwrap();
+    locks.read_lock(2, "key", LockWait::Default).unwrap();
+
+    let older = {
+        let locks = locks.clone();
+        std::thread::spawn(move || locks.write_lock(1, "key", LockWait::Default))
+    };
+    wait_until_queued(&locks, "key", 1);
+
+    assert_eq!(
+        locks.write_lock(2, "key", LockWait::Default),
+        Err(String::from(DEADLOCK_ERROR))
+    );
+    locks.read_unlock(2, "key");
+
+    assert_eq!(older.join().unwrap(), Ok(()));
+}
+
+#[test]
+fn test_release_all() {
+    let locks = Locks::new();
+    locks.read_lock(1, "a", LockWait::Default).unwrap();
+    locks.read_lock(1, "a", LockWait::Default).unwrap();
+    locks.read_lock(2, "a", LockWait::Default).unwrap();
+    locks.write_lock(1, "b", LockWait::Default).unwrap();
+
+    locks.release_all(1);
+    locks.release_all(1);
+
+    {
+        let table = locks.table.lock().unwrap();
+        assert_eq!(table.entries["a"].lock, RWLock::Read(1));
+        assert_eq!(table.entries["a"].owners, vec![2]);
+        assert!(!table.entries.contains_key("b"));
+    }
+
+    assert_eq!(locks.write_lock(3, "b", LockWait::NoWait), Ok(()));
+}
+
+#[test]
+fn test_release_keys() {
+    let locks = Locks::new();
+    locks.write_lock(1, "a", LockWait::Default).unwrap();
+    locks.write_lock(1, "b", LockWait::Default).unwrap();
+    locks.read_lock(2, "c", LockWait::Default).unwrap();
+
+    // only the given keys are released, and keys that are not held are skipped
+    locks.release_keys(1, &[String::from("a"), String::from("c")]);
+
+    {
+        let table = locks.table.lock().unwrap();
+        assert!(!table.entries.contains_key("a"));
+        assert_eq!(table.entries["b"].owners, vec![1]);
+        assert_eq!(table.entries["c"].owners, vec![2]);
+    }
+
+    assert_eq!(locks.write_lock(3, "a", LockWait::NoWait), Ok(()));
+}
+
+#[test]
+fn test_abort_parked_transaction() {
+    let locks = Arc::new(Locks::new());
+    locks.write_lock(1, "a", LockWait::Default).unwrap();
+    locks.write_lock(2, "b", LockWait::Default).unwrap();
+
+    let waiter = {
+        let locks = locks.clone();
+        std::thread::spawn(move || locks.write_lock(2, "a", LockWait::Default))
+    };
+    wait_until_queued(&locks, "a", 1);
+
+    locks.abort(2, "ABORTED");
+    // no longer blocked, even if the thread has not woken up yet
+    assert!(locks.waiters().is_empty());
+    assert_eq!(waiter.join().unwrap(), Err(String::from("ABORTED")));
+
+    // the aborted transaction's locks were released, and it left the queue
+    assert_eq!(locks.write_lock(3, "b", LockWait::NoWait), Ok(()));
+    let table = locks.table.lock().unwrap();
+    assert!(table.entries["a"].waiters.is_empty());
+    assert!(table.aborted.is_empty());
+}
+
+#[test]
+fn test_abort_idle_transaction() {
+    let locks = Locks::new();
+    locks.write_lock(1, "a", LockWait::Default).unwrap();
+
+    locks.abort(1, "ABORTED");
+    assert_eq!(locks.write_lock(2, "a", LockWait::NoWait), Ok(()));
+
+    // the next lock request of the aborted transaction reports the reason once
+    assert_eq!(
+        locks.read_lock(1, "c", LockWait::Default),
+        Err(String::from("ABORTED"))
+    );
+    assert_eq!(locks.abort_reason(1), None);
+
+    locks.abort(1, "ABORTED");
+    assert_eq!(locks.abort_reason(1), Some(String::from("ABORTED")));
+}
+
+#[test]
+fn test_list_and_waiters() {
+    let locks = Arc::new(Locks::new());
+    locks.read_lock(1, "apple", LockWait::Default).unwrap();
+    locks.read_lock(1, "apple", LockWait::Default).unwrap();
+    locks.read_lock(2, "apple", LockWait::Default).unwrap();
+    locks.write_lock(3, "banana", LockWait::Default).unwrap();
+
+    let waiter = {
+        let locks = locks.clone();
+        std::thread::spawn(move || locks.write_lock(4, "apple", LockWait::Default))
+    };
+    wait_until_queued(&locks, "apple", 1);
+    assert_eq!(
+        locks.write_lock(5, "banana", LockWait::NoWait),
+        Err(String::from(LOCKTIMEOUT_ERROR))
+    );
+
+    let list = locks.list(None);
+    assert_eq!(list.len(), 2);
+    assert_eq!(list[0].key, "apple");
+    assert_eq!(list[0].mode, Some(LockMode::Read));
+    assert_eq!(list[0].owners, vec![1, 2]);
+    assert_eq!(list[0].waiters.len(), 1);
+    assert_eq!(list[0].waiters[0].0, 4);
+    assert_eq!(list[1].key, "banana");
+    assert_eq!(list[1].mode, Some(LockMode::Write));
+    assert_eq!(list[1].owners, vec![3]);
+
+    let list = locks.list(Some("b*"));
+    assert_eq!(list.len(), 1);
+    assert_eq!(list[0].key, "banana");
+
+    let waiters = locks.waiters();
+    assert_eq!(waiters.len(), 1);
+    assert_eq!(waiters[0].owner, 4);
+    assert_eq!(waiters[0].key, "apple");
+    assert_eq!(waiters[0].blockers, vec![1, 2]);
+
+    locks.release_all(1);
+    locks.release_all(2);
+    assert_eq!(waiter.join().unwrap(), Ok(()));
+    assert!(locks.waiters().is_empty());
+    assert_eq!(
+        locks.stats(),
+        LockStats {
+            waits: 1,
+            timeouts: 1,
+            deadlocks: 0,
+        }
+    );
 }
@@ -1,23 +1,69 @@
-use std::collections::HashMap;
-
-use crate::command::Command;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::backup::Backup;
+use crate::expire;
+use crate::keyspace::{self, Entry, Keyspace, Value};
 use crate::parser;
 
 use std::io::prelude::*;
 
 use crate::utils::fs_utils::{file_clear, open_or_create_file};
 
-type MemdbInner = HashMap<String, Vec<u8>>;
+type MemdbInner = HashMap<String, Value>;
+
+// (書き込まれた時の version, 値) の順に並んだ古い値。None は key が存在しなかったことを表す
+type History = Vec<(u64, Option<Entry>)>;
+
+// serialize された key の長さの最上位bitが立っている場合、値の後ろに期限が続く
+const DEADLINE_FLAG: u64 = 1 << 63;
+// 次のbitが立っている場合、値は (長さ, 要素) を並べた list
+const LIST_FLAG: u64 = 1 << 62;
+
+// active expiry の1回の試行で調べる key の数
+const EXPIRE_SAMPLE: usize = 20;
+const EXPIRE_MAX_ROUNDS: usize = 16;
 
-#[derive(Debug, Clone, PartialEq, Default)]
+#[derive(Debug, Clone, Default)]
 pub struct Memdb {
     inner: MemdbInner,
+    // 期限付きの key の期限 (Unix epoch からのミリ秒)
+    expires: HashMap<String, u64>,
+    // keyが最後に書き込まれた時の clock。削除されたkeyの version も残しておく
+    versions: HashMap<String, u64>,
+    // shard 間で共有される
+    clock: Arc<AtomicU64>,
+    // snapshotから見える古い値
+    history: HashMap<String, History>,
+    // 開いているsnapshotの timestamp とその数
+    snapshots: BTreeMap<u64, usize>,
+    // stored_keys を scan する順 (scan_position, key) に並べたもの
+    scan_index: BTreeSet<(u64, String)>,
+}
+
+impl PartialEq for Memdb {
+    fn eq(&self, other: &Memdb) -> bool {
+        self.inner == other.inner && self.expires == other.expires
+    }
 }
 
 impl Memdb {
     pub fn new() -> Memdb {
+        Memdb::with_clock(Arc::new(AtomicU64::new(0)))
+    }
+
+    pub(crate) fn with_clock(clock: Arc<AtomicU64>) -> Memdb {
         Memdb {
             inner: HashMap::new(),
+            expires: HashMap::new(),
+            versions: HashMap::new(),
+            clock,
+            history: HashMap::new(),
+            snapshots: BTreeMap::new(),
+            scan_index: BTreeSet::new(),
         }
     }
 
@@ -27,25 +73,99 @@ impl Memdb {
     ) -> Result<Memdb, Box<dyn std::error::Error>> {
         let mut db_file = open_or_create_file(db_file_path)?;
 
-        let mut contents = String::new();
-        db_file.read_to_string(&mut contents)?;
+        let mut contents = vec![];
+        db_file.read_to_end(&mut contents)?;
 
-        let mut db = Memdb::deserialize(contents.as_bytes())?;
+        let mut db = Memdb::deserialize(&contents)?;
 
         let mut log_file = open_or_create_file(log_file_path)?;
 
         let mut logs = String::new();
         log_file.read_to_string(&mut logs)?;
 
-        logs.lines().for_each(|log| {
-            let _ = db.exec(log);
-        });
+        db.replay(&logs)?;
 
         file_clear(log_file_path)?;
 
         Ok(db)
     }
 
+    /// Seeds a database from a file written by `tyozo-cli --backup`.
+    ///
+    /// When `log_file_path` is given, the log records written after the snapshot
+    /// was taken are replayed on top of it, so the restored database catches up
+    /// with the server the backup came from.
+    pub fn restore_backup(
+        backup_file_path: &str,
+        log_file_path: Option<&str>,
+    ) -> Result<Memdb, Box<dyn std::error::Error>> {
+        let backup = Backup::from_bytes(&std::fs::read(backup_file_path)?)?;
+        let mut db = backup.memdb()?;
+
+        if let Some(log_file_path) = log_file_path {
+            let logs = std::fs::read(log_file_path)?;
+            let position = backup.wal_position as usize;
+
+            // backup の後に log が切り詰められていると、その間の書き込みは取り戻せない。
+            // 切り詰めた後に書き足された場合も、位置が行の先頭に当たらなければ分かる
+            let truncated = match position {
+                0 => false,
+                _ => logs.get(position - 1) != Some(&b'\n'),
+            };
+            if truncated {
+                return Err(format!(
+                    "ERR the log was truncated after the backup was taken \
+                     (backup position {}, log length {})",
+                    position,
+                    logs.len()
+                )
+                .into());
+            }
+
+            db.replay(&String::from_utf8_lossy(&logs[position..]))?;
+        }
+
+        Ok(db)
+    }
+
+    /// Seeds the database files of a server with `restore_backup`: the seeded database
+    /// is written to `db_file_path` and the log at `log_file_path` is cleared, so that
+    /// the next plain `restore` starts from the seeded state instead of the old files.
+    pub fn seed(
+        backup_file_path: &str,
+        catch_up_log_path: Option<&str>,
+        db_file_path: &str,
+        log_file_path: &str,
+    ) -> Result<Memdb, Box<dyn std::error::Error>> {
+        let db = Memdb::restore_backup(backup_file_path, catch_up_log_path)?;
+
+        std::fs::write(db_file_path, db.serialize())?;
+        file_clear(log_file_path)?;
+
+        Ok(db)
+    }
+
+    /// Runs the commands of a log on top of the database.
+    ///
+    /// A command that fails, like `setnx` on an existing key, failed the same way when it
+    /// was logged, so it is skipped. A line that can't be parsed is an error rather than
+    /// being dropped, except for a last line without a line break, which is a write that
+    /// was cut off.
+    fn replay(&mut self, logs: &str) -> Result<(), String> {
+        let complete = match logs.rfind('\n') {
+            Some(end) => &logs[..=end],
+            None => "",
+        };
+
+        for (i, log) in complete.lines().enumerate() {
+            let command = parser::parse(log)
+                .map_err(|e| format!("ERR failed to replay line {} of the log: {}", i + 1, e))?;
+            let _ = self.exec_command(command);
+        }
+
+        Ok(())
+    }
+
     /// # Example
     /// ```
     /// use tyozo::Memdb;
@@ -73,69 +193,43 @@ impl Memdb {
         self.exec_command(command)
     }
 
-    pub fn exec_command(&mut self, command: Command) -> Result<String, String> {
-        match command {
-            Command::Set { key, value } => {
-                self.set(key, value);
-                Ok(String::from("OK"))
-            }
-            Command::SetNX { key, value } => {
-                self.setnx(key, value)?;
-                Ok(String::from("OK"))
-            }
-            Command::Get { key } => match self.get(key) {
-                None => Ok(String::from("None")),
-                Some(v) => Ok(String::from_utf8(v).unwrap()),
-            },
-            Command::Del { keys } => {
-                let result = self.del(keys);
-                Ok(format!("{}", result))
-            }
-            Command::Multi => todo!(),
-            Command::Exec => todo!(),
-            Command::Abort => todo!(),
-            _ => unreachable!(),
-        }
-    }
-
     /// # Example
     /// ```
     /// use tyozo::Memdb;
     /// let mut memdb = Memdb::new();
     ///
     /// memdb.set("key", "value");
-    /// assert_eq!(memdb.inner().get("key"), Some(&b"value".to_vec()));
+    /// assert_eq!(memdb.get("key"), Some(b"value".to_vec()));
     ///
     /// // value is override
     /// memdb.set("key", "next value");
-    /// assert_eq!(memdb.inner().get("key"), Some(&b"next value".to_vec()));
+    /// assert_eq!(memdb.get("key"), Some(b"next value".to_vec()));
     /// ```
     pub fn set(&mut self, key: impl AsRef<str>, value: impl AsRef<[u8]>) {
-        self.inner
-            .insert(key.as_ref().to_owned(), value.as_ref().to_owned());
+        let entry = Entry::new(value.as_ref().to_owned());
+        self.put(key.as_ref(), Some(entry));
     }
 
     /// # Example
     /// ```
     /// use tyozo::Memdb;
     /// let mut memdb = Memdb::new();
-
+    ///
     /// let result = memdb.setnx("key", "value");
     /// assert!(result.is_ok());
-    /// assert_eq!(memdb.inner().get("key"), Some(&b"value".to_vec()));
-
+    /// assert_eq!(memdb.get("key"), Some(b"value".to_vec()));
+    ///
     /// // value is not override
     /// let result = memdb.setnx("key", "next value");
     /// assert!(result.is_err());
-    /// assert_eq!(memdb.inner().get("key"), Some(&b"value".to_vec()));
+    /// assert_eq!(memdb.get("key"), Some(b"value".to_vec()));
     /// ```
     pub fn setnx(&mut self, key: impl AsRef<str>, value: impl AsRef<[u8]>) -> Result<(), String> {
-        if self.get(&key).is_some() {
+        if self.get_entry(key.as_ref()).is_some() {
             return Err(String::from("ERR key is already exists"));
         }
 
-        self.inner
-            .insert(key.as_ref().to_owned(), value.as_ref().to_owned());
+        self.set(key, value);
 
         Ok(())
     }
@@ -151,8 +245,31 @@ impl Memdb {
     ///
     /// assert_eq!(memdb.get("not setted key"), None);
     /// ```
+    ///
+    /// Keys holding a type other than a string read as `None`.
     pub fn get(&self, key: impl AsRef<str>) -> Option<Vec<u8>> {
-        self.inner.get(&key.as_ref().to_owned()).cloned()
+        self.get_entry(key.as_ref()).and_then(string_value)
+    }
+
+    /// The value of a live key, without copying it.
+    pub(crate) fn get_value(&self, key: &str) -> Option<&Value> {
+        let expired = self
+            .expires
+            .get(key)
+            .is_some_and(|deadline| *deadline <= expire::now());
+
+        self.inner.get(key).filter(|_| !expired)
+    }
+
+    /// Expired keys are hidden, but stay in memory until they are accessed through
+    /// `Keyspace` with a mutable reference or removed by `expire_cycle`.
+    pub(crate) fn get_entry(&self, key: &str) -> Option<Entry> {
+        let entry = Entry {
+            value: self.inner.get(key)?.clone(),
+            deadline: self.expires.get(key).copied(),
+        };
+
+        Some(entry).filter(|entry| !entry.is_expired(expire::now()))
     }
 
     /// # Exmaple
@@ -171,11 +288,266 @@ impl Memdb {
     /// ```
     pub fn del(&mut self, keys: Vec<impl AsRef<str>>) -> usize {
         keys.into_iter()
-            .map(|key| self.inner.remove(&key.as_ref().to_owned()))
-            .filter(|v| v.is_some())
+            .filter(|key| self.remove_key(key.as_ref()))
             .count()
     }
 
+    /// Returns a counter that changes whenever `key` is written or deleted,
+    /// which `watch` compares to detect concurrent modifications.
+    ///
+    /// # Example
+    /// ```
+    /// use tyozo::Memdb;
+    /// let mut memdb = Memdb::new();
+    /// assert_eq!(memdb.version("key"), 0);
+    ///
+    /// memdb.set("key", "value");
+    /// let version = memdb.version("key");
+    /// assert_ne!(version, 0);
+    ///
+    /// memdb.del(vec!["key"]);
+    /// assert_ne!(memdb.version("key"), version);
+    /// ```
+    pub fn version(&self, key: impl AsRef<str>) -> u64 {
+        self.versions.get(key.as_ref()).copied().unwrap_or(0)
+    }
+
+    /// Opens a snapshot of the current state and returns its timestamp.
+    /// Values overwritten from now on stay readable through `get_at` until
+    /// the snapshot is released with `end_snapshot`.
+    ///
+    /// # Example
+    /// ```
+    /// use tyozo::Memdb;
+    /// let mut memdb = Memdb::new();
+    /// memdb.set("key", "old");
+    ///
+    /// let snapshot = memdb.begin_snapshot();
+    /// memdb.set("key", "new");
+    /// memdb.set("created", "value");
+    ///
+    /// assert_eq!(memdb.get_at("key", snapshot), Some(b"old".to_vec()));
+    /// assert_eq!(memdb.get_at("created", snapshot), None);
+    ///
+    /// memdb.end_snapshot(snapshot);
+    /// assert_eq!(memdb.get("key"), Some(b"new".to_vec()));
+    /// ```
+    pub fn begin_snapshot(&mut self) -> u64 {
+        let timestamp = self.clock.load(Ordering::SeqCst);
+        *self.snapshots.entry(timestamp).or_insert(0) += 1;
+
+        timestamp
+    }
+
+    pub fn end_snapshot(&mut self, timestamp: u64) {
+        if let Some(count) = self.snapshots.get_mut(&timestamp) {
+            *count -= 1;
+            if *count == 0 {
+                self.snapshots.remove(&timestamp);
+            }
+        }
+
+        self.collect_garbage();
+    }
+
+    /// The value of `key` as seen by the snapshot taken at `timestamp`.
+    pub fn get_at(&self, key: impl AsRef<str>, timestamp: u64) -> Option<Vec<u8>> {
+        self.get_entry_at(key.as_ref(), timestamp)
+            .and_then(string_value)
+    }
+
+    // 期限は snapshot の時刻ではなく現在の時刻で判定する
+    pub(crate) fn get_entry_at(&self, key: &str, timestamp: u64) -> Option<Entry> {
+        if self.version(key) <= timestamp {
+            return self.get_entry(key);
+        }
+
+        self.history
+            .get(key)
+            .and_then(|versions| versions.iter().rev().find(|(v, _)| *v <= timestamp))
+            .and_then(|(_, entry)| entry.clone())
+            .filter(|entry| !entry.is_expired(expire::now()))
+    }
+
+    /// Removes expired keys, which are otherwise only removed when they are written.
+    ///
+    /// Like Redis, this checks a random sample of the keys with a deadline, and samples
+    /// again while more than a quarter of them had expired. Returns the number of keys
+    /// removed.
+    ///
+    /// # Example
+    /// ```
+    /// use tyozo::Memdb;
+    /// let mut memdb = Memdb::new();
+    /// memdb.exec("set session value px 1").unwrap();
+    /// memdb.exec("set key value").unwrap();
+    ///
+    /// std::thread::sleep(std::time::Duration::from_millis(5));
+    /// assert_eq!(memdb.expire_cycle(), 1);
+    /// assert_eq!(memdb.inner().len(), 1);
+    /// ```
+    pub fn expire_cycle(&mut self) -> usize {
+        let now = expire::now();
+        let mut removed = 0;
+
+        for _ in 0..EXPIRE_MAX_ROUNDS {
+            if self.expires.is_empty() {
+                break;
+            }
+
+            // HashMap の順番は固定なので、毎回ランダムな位置から sample する
+            let start = RandomState::new().build_hasher().finish() as usize % self.expires.len();
+            let sample = self
+                .expires
+                .iter()
+                .cycle()
+                .skip(start)
+                .take(EXPIRE_SAMPLE.min(self.expires.len()))
+                .collect::<Vec<_>>();
+            let sampled = sample.len();
+
+            let expired = sample
+                .into_iter()
+                .filter(|(_, deadline)| **deadline <= now)
+                .map(|(key, _)| key.to_owned())
+                .collect::<Vec<_>>();
+            expired.iter().for_each(|key| {
+                self.put(key, None);
+            });
+            removed += expired.len();
+
+            if expired.len() * 4 <= sampled {
+                break;
+            }
+        }
+
+        removed
+    }
+
+    /// Number of old values kept for open snapshots.
+    pub fn history_len(&self) -> usize {
+        self.history.values().map(Vec::len).sum()
+    }
+
+    /// Drops old values that no open snapshot can read anymore. A value stays readable
+    /// from its version until the next one, so it is kept only while a snapshot was taken
+    /// in between, not just while an older snapshot is open.
+    fn collect_garbage(&mut self) {
+        let (snapshots, current) = (&self.snapshots, &self.versions);
+        let mut dropped = vec![];
+        self.history.retain(|key, versions| {
+            // 各値は次の version が書かれるまで読める
+            let ends = versions
+                .iter()
+                .skip(1)
+                .map(|(v, _)| *v)
+                .chain(current.get(key).copied())
+                .collect::<Vec<_>>();
+            let mut ends = ends.into_iter();
+            versions.retain(|(v, _)| {
+                let end = ends.next().unwrap_or(u64::MAX);
+                snapshots.range(*v..end).next().is_some()
+            });
+            if versions.is_empty() {
+                dropped.push(key.to_owned());
+            }
+            !versions.is_empty()
+        });
+
+        dropped.iter().for_each(|key| self.index_key(key));
+    }
+
+    // key が stored_keys に含まれるかどうかを scan_index に反映する
+    fn index_key(&mut self, key: &str) {
+        let indexed = (keyspace::scan_position(key), key.to_owned());
+        if self.inner.contains_key(key) || self.history.contains_key(key) {
+            self.scan_index.insert(indexed);
+        } else {
+            self.scan_index.remove(&indexed);
+        }
+    }
+
+    /// Every write and delete goes through here, so the version of the key is bumped
+    /// and the previous value is kept while an open snapshot can read it.
+    fn put(&mut self, key: &str, entry: Option<Entry>) -> Option<Entry> {
+        let old_deadline = match entry.as_ref().and_then(|entry| entry.deadline) {
+            Some(deadline) => self.expires.insert(key.to_owned(), deadline),
+            None => self.expires.remove(key),
+        };
+        let old = match entry {
+            Some(entry) => self.inner.insert(key.to_owned(), entry.value),
+            None => self.inner.remove(key),
+        };
+        let old = old.map(|value| Entry {
+            value,
+            deadline: old_deadline,
+        });
+
+        self.record_write(key, || old.clone());
+        old
+    }
+
+    // 一番新しい snapshot より後に書かれた値は、どの snapshot からも読まれない
+    fn keeps_history(&self, key: &str) -> bool {
+        matches!(self.snapshots.keys().next_back(), Some(newest) if self.version(key) <= *newest)
+    }
+
+    // 書き込み前の値を読める snapshot があれば値を残し、key の version を進める
+    fn record_write(&mut self, key: &str, old: impl FnOnce() -> Option<Entry>) {
+        if self.keeps_history(key) {
+            let version = self.version(key);
+            self.history
+                .entry(key.to_owned())
+                .or_default()
+                .push((version, old()));
+        }
+
+        let version = self.clock.fetch_add(1, Ordering::SeqCst) + 1;
+        self.versions.insert(key.to_owned(), version);
+        self.index_key(key);
+    }
+
+    // 期限切れの key も削除するが、存在しなかったものとして扱う
+    fn remove_key(&mut self, key: &str) -> bool {
+        if !self.inner.contains_key(key) {
+            return false;
+        }
+
+        let existed = self.get_entry(key).is_some();
+        self.put(key, None);
+        existed
+    }
+
+    /// Every key with its deadline, including expired ones.
+    pub(crate) fn entries(&self) -> impl Iterator<Item = (&str, Entry)> {
+        self.inner.iter().map(move |(key, value)| {
+            let entry = Entry {
+                value: value.clone(),
+                deadline: self.expires.get(key).copied(),
+            };
+            (key.as_str(), entry)
+        })
+    }
+
+    /// Keys holding a value, including expired ones, and the deleted keys whose old
+    /// values are kept for snapshots.
+    pub(crate) fn stored_keys(&self) -> impl Iterator<Item = &String> {
+        self.inner.keys().chain(
+            self.history
+                .keys()
+                .filter(move |key| !self.inner.contains_key(*key)),
+        )
+    }
+
+    /// Up to `count` of the `stored_keys` from `scan_position` `from` on, in that order.
+    pub(crate) fn scan_range(&self, from: u64, count: usize) -> Vec<(u64, String)> {
+        self.scan_index
+            .range((from, String::new())..)
+            .take(count)
+            .cloned()
+            .collect()
+    }
+
     pub fn inner(&self) -> &MemdbInner {
         &self.inner
     }
@@ -191,17 +563,41 @@ impl Memdb {
     ///
     /// assert_eq!(serialized, vec![0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 107, 118]);
     /// ```
+    ///
+    /// A key with a deadline has the top bit of its length set, and is followed by the
+    /// deadline after the value. A list has the next bit set, and its value is each
+    /// element preceded by its length.
     pub fn serialize(&self) -> Vec<u8> {
         self.inner.iter().fold(vec![], |mut buf, (key, value)| {
-            let key_length_bytes = key.len().to_be_bytes();
+            let deadline = self.expires.get(key);
+
+            let mut key_length = key.len() as u64;
+            if deadline.is_some() {
+                key_length |= DEADLINE_FLAG;
+            }
+            let value = match value {
+                Value::String(value) => value.clone(),
+                Value::List(list) => {
+                    key_length |= LIST_FLAG;
+                    list.iter().fold(vec![], |mut value, element| {
+                        value.extend_from_slice(&element.len().to_be_bytes());
+                        value.extend_from_slice(element);
+                        value
+                    })
+                }
+            };
             let value_length_bytes = value.len().to_be_bytes();
 
-            buf.extend_from_slice(&key_length_bytes);
+            buf.extend_from_slice(&key_length.to_be_bytes());
             buf.extend_from_slice(&value_length_bytes);
 
-            buf.extend_from_slice(&key.as_bytes());
+            buf.extend_from_slice(key.as_bytes());
             buf.extend_from_slice(&value);
 
+            if let Some(deadline) = deadline {
+                buf.extend_from_slice(&deadline.to_be_bytes());
+            }
+
             buf
         })
     }
@@ -222,24 +618,27 @@ impl Memdb {
     /// ```
     pub fn deserialize(input: &[u8]) -> Result<Memdb, String> {
         let mut position = 0usize;
-        let mut inner = HashMap::new();
+        let mut memdb = Memdb::default();
 
         while input.len() > position {
-            position += Memdb::deserialize_paier(&mut inner, &input[position..])?;
+            position += memdb.deserialize_paier(&input[position..])?;
         }
 
-        Ok(Memdb { inner })
+        Ok(memdb)
     }
 
-    fn deserialize_paier(inner: &mut MemdbInner, input: &[u8]) -> Result<usize, String> {
+    fn deserialize_paier(&mut self, input: &[u8]) -> Result<usize, String> {
         let key_position = 8;
         let key_length = match input.get(0..key_position) {
             None => return Err(String::from("ERR invalid database format")),
-            Some(bytes) => usize::from_be_bytes([
+            Some(bytes) => u64::from_be_bytes([
                 // FIXME 絶対なにかいい方法がある！！
                 bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7],
             ]),
         };
+        let has_deadline = key_length & DEADLINE_FLAG != 0;
+        let is_list = key_length & LIST_FLAG != 0;
+        let key_length = (key_length & !(DEADLINE_FLAG | LIST_FLAG)) as usize;
 
         let value_position = key_position + 8;
         let value_length = match input.get(key_position..value_position) {
@@ -249,17 +648,150 @@ impl Memdb {
             ]),
         };
 
-        let key_position = value_position + value_length;
-        // TODO add error handle
-        let key =
-            String::from_utf8(input.get(value_position..key_position).unwrap().to_vec()).unwrap();
+        let key_end = value_position + key_length;
+        let key = match input.get(value_position..key_end) {
+            None => return Err(String::from("ERR invalid database format")),
+            Some(bytes) => String::from_utf8(bytes.to_vec())
+                .map_err(|_| String::from("ERR invalid database format"))?,
+        };
+
+        let value_end = key_end + value_length;
+        let value = match input.get(key_end..value_end) {
+            None => return Err(String::from("ERR invalid database format")),
+            Some(bytes) if is_list => Value::List(deserialize_list(bytes)?),
+            Some(bytes) => Value::String(bytes.to_vec()),
+        };
+
+        let mut end = value_end;
+        if has_deadline {
+            end += 8;
+            let deadline = match input.get(value_end..end) {
+                None => return Err(String::from("ERR invalid database format")),
+                Some(bytes) => u64::from_be_bytes([
+                    bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7],
+                ]),
+            };
+            self.expires.insert(key.clone(), deadline);
+        }
+
+        self.inner.insert(key.clone(), value);
+        self.index_key(&key);
+
+        Ok(end)
+    }
+}
+
+impl Keyspace for Memdb {
+    // 読み込まれた時に期限が切れていた key はその場で削除する
+    fn read_entry(&mut self, key: &str) -> Result<Option<Entry>, String> {
+        let entry = self.get_entry(key);
+        if entry.is_none() && self.inner.contains_key(key) {
+            self.put(key, None);
+        }
+
+        Ok(entry)
+    }
+
+    fn write_entry(&mut self, key: &str, entry: Entry) -> Result<(), String> {
+        self.put(key, Some(entry));
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> Result<bool, String> {
+        Ok(self.remove_key(key))
+    }
+
+    fn stored_keys(&mut self) -> Result<Vec<String>, String> {
+        Ok(Memdb::stored_keys(self).cloned().collect())
+    }
+
+    fn partition_range(
+        &mut self,
+        _index: usize,
+        from: u64,
+        count: usize,
+    ) -> Result<Vec<(u64, String)>, String> {
+        Ok(self.scan_range(from, count))
+    }
+
+    fn read_with<R>(
+        &mut self,
+        key: &str,
+        f: impl FnOnce(Option<&Value>) -> R,
+    ) -> Result<R, String> {
+        if self.get_value(key).is_none() {
+            self.read_entry(key)?;
+        }
+
+        Ok(f(self.get_value(key)))
+    }
+
+    // 値は HashMap から取り出して渡すので、snapshot が古い値を読まなければ copy されない
+    fn update_value<R>(
+        &mut self,
+        key: &str,
+        f: impl FnOnce(&mut Option<Value>) -> Result<R, String>,
+    ) -> Result<R, String> {
+        // 期限切れの key は先に削除し、存在しなかったものとして扱う
+        self.read_with(key, |_| ())?;
+        let old = match self.keeps_history(key) {
+            true => self.get_entry(key),
+            false => None,
+        };
+
+        let mut value = self.inner.remove(key);
+        let existed = value.is_some();
+        let result = f(&mut value);
+
+        match value {
+            // f は失敗する前に値を変更しないので、元の値を戻すだけでよい
+            Some(value) if existed && result.is_err() => {
+                self.inner.insert(key.to_owned(), value);
+            }
+            _ if result.is_err() => (),
+            Some(value) if existed => {
+                self.inner.insert(key.to_owned(), value);
+                self.record_write(key, || old);
+            }
+            Some(value) => {
+                self.put(key, Some(Entry::new(value)));
+            }
+            None if existed => {
+                self.expires.remove(key);
+                self.record_write(key, || old);
+            }
+            None => (),
+        }
 
-        // TODO add error handle
-        let value_position = key_position + key_length;
-        let value = input.get(key_position..value_position).unwrap().to_vec();
+        result
+    }
+}
+
+/// The value of a string entry.
+fn string_value(entry: Entry) -> Option<Vec<u8>> {
+    match entry.value {
+        Value::String(value) => Some(value),
+        _ => None,
+    }
+}
+
+fn deserialize_list(mut input: &[u8]) -> Result<VecDeque<Vec<u8>>, String> {
+    let mut list = VecDeque::new();
 
-        inner.insert(key, value);
+    while !input.is_empty() {
+        let length = match input.get(0..8) {
+            None => return Err(String::from("ERR invalid database format")),
+            Some(bytes) => usize::from_be_bytes([
+                bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7],
+            ]),
+        };
+        let element = input
+            .get(8..8usize.saturating_add(length))
+            .ok_or_else(|| String::from("ERR invalid database format"))?;
 
-        Ok(value_position)
+        list.push_back(element.to_vec());
+        input = &input[8 + length..];
     }
+
+    Ok(list)
 }
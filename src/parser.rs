@@ -1,5 +1,16 @@
-use crate::command::Command;
+use std::convert::TryFrom;
+use std::ops::Deref;
+use std::time::Duration;
+
+use crate::command::{Command, ListEnd, SetCondition};
+use crate::expire::Expire;
+use crate::keyspace::{NOT_FLOAT_ERROR, NOT_INTEGER_ERROR};
 use crate::lexer::Lexer;
+use crate::locks::LockWait;
+use crate::transaction::TransactionOptions;
+
+/// How many keys `scan` looks at when no `count` is given, the same as Redis.
+const DEFAULT_SCAN_COUNT: usize = 10;
 
 pub fn parse<S: Into<String>>(input: S) -> Result<Command, String> {
     let input = split_input(input)?;
@@ -7,7 +18,7 @@ pub fn parse<S: Into<String>>(input: S) -> Result<Command, String> {
 }
 
 fn parse_to_commnad(input: SplitedCommand) -> Result<Command, String> {
-    let command_name = match input.get(0) {
+    let command_name = match input.first() {
         None => return Err(String::from("not input command name")),
         Some(c) => c,
     };
@@ -16,11 +27,99 @@ fn parse_to_commnad(input: SplitedCommand) -> Result<Command, String> {
         "set" => parse_set_command(input)?,
         "get" => parse_get_command(input)?,
         "setnx" => parse_setnx_command(input)?,
+        "getset" => {
+            let (key, value) = parse_set_command_common(input)?;
+            Command::Set {
+                key,
+                value,
+                expire: None,
+                condition: None,
+                get: true,
+            }
+        }
+        "getdel" => Command::GetDel {
+            key: parse_key(input)?,
+        },
+        "cas" => parse_cas_command(input)?,
         "del" => parse_del_command(input)?,
+        "mget" => parse_mget_command(input)?,
+        "exists" => parse_exists_command(input)?,
+        "type" => Command::Type {
+            key: parse_key(input)?,
+        },
+        "rename" | "renamenx" => parse_rename_command(input)?,
+        "copy" => parse_copy_command(input)?,
+        "dbsize" => Command::DbSize,
+        "flushdb" => Command::FlushDb,
+        "keys" => Command::Keys {
+            pattern: parse_key(input)?,
+        },
+        "scan" => parse_scan_command(input)?,
+        "mset" => Command::MSet {
+            pairs: parse_pairs(input)?,
+        },
+        "msetnx" => Command::MSetNX {
+            pairs: parse_pairs(input)?,
+        },
+        "expire" | "pexpire" | "expireat" | "pexpireat" => parse_expire_command(input)?,
+        "incr" | "decr" | "incrby" | "decrby" => parse_incr_command(input)?,
+        "incrbyfloat" => parse_incrbyfloat_command(input)?,
+        "append" => parse_append_command(input)?,
+        "strlen" => Command::StrLen {
+            key: parse_key(input)?,
+        },
+        "getrange" => parse_getrange_command(input)?,
+        "setrange" => parse_setrange_command(input)?,
+        "lpush" | "rpush" => parse_push_command(input)?,
+        "lpop" | "rpop" => parse_pop_command(input)?,
+        "lrange" | "ltrim" => parse_list_range_command(input)?,
+        "llen" => Command::LLen {
+            key: parse_key(input)?,
+        },
+        "lindex" => match &input[1..] {
+            [key, index] => Command::LIndex {
+                key: key.to_owned(),
+                index: parse_integer(index)?,
+            },
+            _ => {
+                return Err(String::from(
+                    "ERR wrong number of arguments for 'lindex' command",
+                ))
+            }
+        },
+        "lset" | "lrem" => parse_list_element_command(input)?,
+        "blpop" | "brpop" => parse_blocking_pop_command(input)?,
+        "blmove" => parse_blmove_command(input)?,
+        "ttl" => Command::Ttl {
+            key: parse_key(input)?,
+        },
+        "pttl" => Command::PTtl {
+            key: parse_key(input)?,
+        },
+        "persist" => Command::Persist {
+            key: parse_key(input)?,
+        },
         "shutdown" => Command::Shutdown,
-        "multi" => Command::Multi,
+        "backup" => Command::Backup,
+        "multi" => parse_multi_command(input)?,
+        "watch" => parse_watch_command(input)?,
+        "unwatch" => Command::Unwatch,
         "exec" => Command::Exec,
         "abort" => Command::Abort,
+        "savepoint" => Command::Savepoint {
+            name: parse_savepoint_name(&input[1..])?,
+        },
+        "rollback" => match input.get(1).map(String::as_str) {
+            Some("to") => Command::RollbackTo {
+                name: parse_savepoint_name(&input[2..])?,
+            },
+            _ => return Err(String::from("ERR syntax error")),
+        },
+        "release" => Command::Release {
+            name: parse_savepoint_name(&input[1..])?,
+        },
+        "locks" => parse_locks_command(input)?,
+        "info" => Command::Info,
         _ => return Err(String::from("unknown command")),
     };
 
@@ -28,6 +127,13 @@ fn parse_to_commnad(input: SplitedCommand) -> Result<Command, String> {
 }
 
 fn parse_get_command(input: SplitedCommand) -> Result<Command, String> {
+    Ok(Command::Get {
+        key: parse_key(input)?,
+    })
+}
+
+// key を1つだけ取る command の引数
+fn parse_key(input: SplitedCommand) -> Result<String, String> {
     let key = match input.get(1) {
         None => return Err(String::from("not input key")),
         Some(k) => k.to_string(),
@@ -37,25 +143,28 @@ fn parse_get_command(input: SplitedCommand) -> Result<Command, String> {
         return Err(String::from("Invalid arguments"));
     }
 
-    Ok(Command::Get { key })
+    Ok(key)
+}
+
+fn parse_set_command_common(input: SplitedCommand) -> Result<(String, Vec<u8>), String> {
+    if input.len() > 3 {
+        return Err(String::from("Invalid arguments"));
+    }
+
+    parse_key_value(&input)
 }
 
-fn parse_set_command_common(input: SplitedCommand) -> Result<(String, String), String> {
+fn parse_key_value(input: &SplitedCommand) -> Result<(String, Vec<u8>), String> {
     let key = match input.get(1) {
         None => return Err(String::from("not input key")),
         Some(k) => k.to_string(),
     };
 
-    let value = match input.get(2) {
-        None => return Err(String::from("not input value")),
-        Some(v) => v.to_string(),
-    };
-
-    if input.len() > 3 {
-        return Err(String::from("Invalid arguments"));
+    if input.len() < 3 {
+        return Err(String::from("not input value"));
     }
 
-    Ok((key, value))
+    Ok((key, input.value(2)))
 }
 
 fn parse_setnx_command(input: SplitedCommand) -> Result<Command, String> {
@@ -64,10 +173,309 @@ fn parse_setnx_command(input: SplitedCommand) -> Result<Command, String> {
     Ok(Command::SetNX { key, value })
 }
 
+// `set key value [nx | xx] [get] [ex seconds | px milliseconds | exat timestamp | pxat milliseconds-timestamp]`
 fn parse_set_command(input: SplitedCommand) -> Result<Command, String> {
+    let (key, value) = parse_key_value(&input)?;
+
+    let (mut expire, mut condition, mut get) = (None, None, false);
+    let mut options = input[3..].iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "nx" if condition.is_none() => condition = Some(SetCondition::NotExists),
+            "xx" if condition.is_none() => condition = Some(SetCondition::Exists),
+            "get" if !get => get = true,
+            "ex" | "px" | "exat" | "pxat" if expire.is_none() => match options.next() {
+                Some(time) => expire = Some(parse_set_expire(option, time)?),
+                None => return Err(String::from("Invalid arguments")),
+            },
+            _ => return Err(String::from("ERR syntax error")),
+        }
+    }
+
+    Ok(Command::Set {
+        key,
+        value,
+        expire,
+        condition,
+        get,
+    })
+}
+
+fn parse_set_expire(option: &str, time: &str) -> Result<Expire, String> {
+    let invalid = || String::from("ERR invalid expire time in 'set' command");
+
+    let time = parse_integer(time)?;
+    if time <= 0 {
+        return Err(invalid());
+    }
+
+    match option {
+        "ex" => Ok(Expire::After(time.checked_mul(1000).ok_or_else(invalid)?)),
+        "px" => Ok(Expire::After(time)),
+        "exat" => Ok(Expire::At(time.checked_mul(1000).ok_or_else(invalid)?)),
+        "pxat" => Ok(Expire::At(time)),
+        _ => Err(String::from("ERR syntax error")),
+    }
+}
+
+// `expire key seconds` / `pexpire key milliseconds` / `expireat key timestamp` / `pexpireat key milliseconds-timestamp`
+fn parse_expire_command(input: SplitedCommand) -> Result<Command, String> {
+    let name = input[0].as_str();
+    let (key, time) = match &input[1..] {
+        [key, time] => (key.to_owned(), parse_integer(time)?),
+        _ => {
+            return Err(format!(
+                "ERR wrong number of arguments for '{}' command",
+                name
+            ))
+        }
+    };
+
+    let seconds = |time: i64| {
+        time.checked_mul(1000)
+            .ok_or_else(|| format!("ERR invalid expire time in '{}' command", name))
+    };
+    let expire = match name {
+        "expire" => Expire::After(seconds(time)?),
+        "pexpire" => Expire::After(time),
+        "expireat" => Expire::At(seconds(time)?),
+        _ => Expire::At(time),
+    };
+
+    Ok(Command::Expire { key, expire })
+}
+
+// `incr key` / `decr key` / `incrby key increment` / `decrby key decrement`
+fn parse_incr_command(input: SplitedCommand) -> Result<Command, String> {
+    let name = input[0].as_str();
+    let (key, increment) = match (name, &input[1..]) {
+        ("incr", [key]) => (key.to_owned(), 1),
+        ("decr", [key]) => (key.to_owned(), -1),
+        ("incrby", [key, increment]) => (key.to_owned(), parse_integer(increment)?),
+        ("decrby", [key, decrement]) => {
+            let increment = parse_integer(decrement)?
+                .checked_neg()
+                .ok_or_else(|| String::from("ERR decrement would overflow"))?;
+            (key.to_owned(), increment)
+        }
+        _ => {
+            return Err(format!(
+                "ERR wrong number of arguments for '{}' command",
+                name
+            ))
+        }
+    };
+
+    Ok(Command::IncrBy { key, increment })
+}
+
+fn parse_incrbyfloat_command(input: SplitedCommand) -> Result<Command, String> {
+    match &input[1..] {
+        [key, increment] => Ok(Command::IncrByFloat {
+            key: key.to_owned(),
+            increment: increment
+                .parse::<f64>()
+                .ok()
+                .filter(|increment| increment.is_finite())
+                .ok_or_else(|| String::from(NOT_FLOAT_ERROR))?,
+        }),
+        _ => Err(String::from(
+            "ERR wrong number of arguments for 'incrbyfloat' command",
+        )),
+    }
+}
+
+fn parse_cas_command(input: SplitedCommand) -> Result<Command, String> {
+    match &input[1..] {
+        [key, _, _] => Ok(Command::Cas {
+            key: key.to_owned(),
+            expected: input.value(2),
+            value: input.value(3),
+        }),
+        _ => Err(String::from(
+            "ERR wrong number of arguments for 'cas' command",
+        )),
+    }
+}
+
+// `lpush key element [element ...]` / `rpush key element [element ...]`
+fn parse_push_command(input: SplitedCommand) -> Result<Command, String> {
+    if input.len() < 3 {
+        return Err(format!(
+            "ERR wrong number of arguments for '{}' command",
+            input[0]
+        ));
+    }
+
+    let key = input[1].to_owned();
+    let elements = input.bytes[2..].to_vec();
+    match input[0].as_str() {
+        "lpush" => Ok(Command::LPush { key, elements }),
+        _ => Ok(Command::RPush { key, elements }),
+    }
+}
+
+// `lpop key [count]` / `rpop key [count]`
+fn parse_pop_command(input: SplitedCommand) -> Result<Command, String> {
+    let (key, count) = match &input[1..] {
+        [key] => (key.to_owned(), None),
+        [key, count] => {
+            let count = usize::try_from(parse_integer(count)?)
+                .map_err(|_| String::from("ERR value is out of range, must be positive"))?;
+            (key.to_owned(), Some(count))
+        }
+        _ => {
+            return Err(format!(
+                "ERR wrong number of arguments for '{}' command",
+                input[0]
+            ))
+        }
+    };
+
+    match input[0].as_str() {
+        "lpop" => Ok(Command::LPop { key, count }),
+        _ => Ok(Command::RPop { key, count }),
+    }
+}
+
+// `lrange key start stop` / `ltrim key start stop`
+fn parse_list_range_command(input: SplitedCommand) -> Result<Command, String> {
+    let (key, start, stop) = match &input[1..] {
+        [key, start, stop] => (key.to_owned(), parse_integer(start)?, parse_integer(stop)?),
+        _ => {
+            return Err(format!(
+                "ERR wrong number of arguments for '{}' command",
+                input[0]
+            ))
+        }
+    };
+
+    match input[0].as_str() {
+        "lrange" => Ok(Command::LRange { key, start, stop }),
+        _ => Ok(Command::LTrim { key, start, stop }),
+    }
+}
+
+// `lset key index element` / `lrem key count element`
+fn parse_list_element_command(input: SplitedCommand) -> Result<Command, String> {
+    let (key, n, element) = match &input[1..] {
+        [key, n, _] => (key.to_owned(), parse_integer(n)?, input.value(3)),
+        _ => {
+            return Err(format!(
+                "ERR wrong number of arguments for '{}' command",
+                input[0]
+            ))
+        }
+    };
+
+    match input[0].as_str() {
+        "lset" => Ok(Command::LSet {
+            key,
+            index: n,
+            element,
+        }),
+        _ => Ok(Command::LRem {
+            key,
+            count: n,
+            element,
+        }),
+    }
+}
+
+// `blpop key [key ...] timeout` / `brpop key [key ...] timeout`
+fn parse_blocking_pop_command(input: SplitedCommand) -> Result<Command, String> {
+    if input.len() < 3 {
+        return Err(format!(
+            "ERR wrong number of arguments for '{}' command",
+            input[0]
+        ));
+    }
+
+    let keys = input[1..input.len() - 1].to_vec();
+    let timeout = parse_block_timeout(&input[input.len() - 1])?;
+    match input[0].as_str() {
+        "blpop" => Ok(Command::BLPop { keys, timeout }),
+        _ => Ok(Command::BRPop { keys, timeout }),
+    }
+}
+
+// `blmove source destination left|right left|right timeout`
+fn parse_blmove_command(input: SplitedCommand) -> Result<Command, String> {
+    match &input[1..] {
+        [source, destination, from, to, timeout] => Ok(Command::BLMove {
+            source: source.to_owned(),
+            destination: destination.to_owned(),
+            from: parse_list_end(from)?,
+            to: parse_list_end(to)?,
+            timeout: parse_block_timeout(timeout)?,
+        }),
+        _ => Err(String::from(
+            "ERR wrong number of arguments for 'blmove' command",
+        )),
+    }
+}
+
+fn parse_list_end(arg: &str) -> Result<ListEnd, String> {
+    match arg {
+        "left" => Ok(ListEnd::Left),
+        "right" => Ok(ListEnd::Right),
+        _ => Err(String::from("ERR syntax error")),
+    }
+}
+
+// timeout は秒で、小数も書ける。0 は無期限に待つ
+fn parse_block_timeout(arg: &str) -> Result<Option<Duration>, String> {
+    let seconds = arg
+        .parse::<f64>()
+        .ok()
+        .filter(|seconds| seconds.is_finite())
+        .ok_or_else(|| String::from("ERR timeout is not a float or out of range"))?;
+
+    match seconds {
+        _ if seconds < 0.0 => Err(String::from("ERR timeout is negative")),
+        _ if seconds == 0.0 => Ok(None),
+        _ => Duration::try_from_secs_f64(seconds)
+            .map(Some)
+            .map_err(|_| String::from("ERR timeout is not a float or out of range")),
+    }
+}
+
+fn parse_append_command(input: SplitedCommand) -> Result<Command, String> {
     let (key, value) = parse_set_command_common(input)?;
 
-    Ok(Command::Set { key, value })
+    Ok(Command::Append { key, value })
+}
+
+// `getrange key start end`。負の index は末尾から数える
+fn parse_getrange_command(input: SplitedCommand) -> Result<Command, String> {
+    match &input[1..] {
+        [key, start, end] => Ok(Command::GetRange {
+            key: key.to_owned(),
+            start: parse_integer(start)?,
+            end: parse_integer(end)?,
+        }),
+        _ => Err(String::from(
+            "ERR wrong number of arguments for 'getrange' command",
+        )),
+    }
+}
+
+fn parse_setrange_command(input: SplitedCommand) -> Result<Command, String> {
+    match &input[1..] {
+        [key, offset, _] => Ok(Command::SetRange {
+            key: key.to_owned(),
+            offset: usize::try_from(parse_integer(offset)?)
+                .map_err(|_| String::from("ERR offset is out of range"))?,
+            value: input.value(3),
+        }),
+        _ => Err(String::from(
+            "ERR wrong number of arguments for 'setrange' command",
+        )),
+    }
+}
+
+fn parse_integer(arg: &str) -> Result<i64, String> {
+    arg.parse().map_err(|_| String::from(NOT_INTEGER_ERROR))
 }
 
 fn parse_del_command(input: SplitedCommand) -> Result<Command, String> {
@@ -82,7 +490,209 @@ fn parse_del_command(input: SplitedCommand) -> Result<Command, String> {
     })
 }
 
-type SplitedCommand = Vec<String>;
+fn parse_mget_command(input: SplitedCommand) -> Result<Command, String> {
+    if input.len() < 2 {
+        return Err(String::from(
+            "ERR wrong number of arguments for 'mget' command",
+        ));
+    }
+
+    Ok(Command::MGet {
+        keys: input[1..].to_vec(),
+    })
+}
+
+fn parse_exists_command(input: SplitedCommand) -> Result<Command, String> {
+    if input.len() < 2 {
+        return Err(String::from(
+            "ERR wrong number of arguments for 'exists' command",
+        ));
+    }
+
+    Ok(Command::Exists {
+        keys: input[1..].to_vec(),
+    })
+}
+
+fn parse_rename_command(input: SplitedCommand) -> Result<Command, String> {
+    let (key, newkey) = match &input[1..] {
+        [key, newkey] => (key.to_owned(), newkey.to_owned()),
+        _ => {
+            return Err(format!(
+                "ERR wrong number of arguments for '{}' command",
+                input[0]
+            ))
+        }
+    };
+
+    match input[0].as_str() {
+        "rename" => Ok(Command::Rename { key, newkey }),
+        _ => Ok(Command::RenameNX { key, newkey }),
+    }
+}
+
+// `copy source destination [replace]`
+fn parse_copy_command(input: SplitedCommand) -> Result<Command, String> {
+    let (source, destination, replace) = match &input[1..] {
+        [source, destination] => (source, destination, false),
+        [source, destination, option] if option == "replace" => (source, destination, true),
+        [_, _, _] => return Err(String::from("ERR syntax error")),
+        _ => {
+            return Err(String::from(
+                "ERR wrong number of arguments for 'copy' command",
+            ))
+        }
+    };
+
+    Ok(Command::Copy {
+        source: source.to_owned(),
+        destination: destination.to_owned(),
+        replace,
+    })
+}
+
+// `scan cursor [match pattern] [count count]`
+fn parse_scan_command(input: SplitedCommand) -> Result<Command, String> {
+    let cursor = match input.get(1) {
+        None => {
+            return Err(String::from(
+                "ERR wrong number of arguments for 'scan' command",
+            ))
+        }
+        Some(cursor) => cursor
+            .parse()
+            .map_err(|_| String::from("ERR invalid cursor"))?,
+    };
+
+    let (mut pattern, mut count) = (None, DEFAULT_SCAN_COUNT);
+    let mut args = input[2..].iter();
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("match", Some(p)) => pattern = Some(p.to_owned()),
+            ("count", Some(n)) => {
+                count = usize::try_from(parse_integer(n)?)
+                    .ok()
+                    .filter(|count| *count > 0)
+                    .ok_or_else(|| String::from("ERR syntax error"))?
+            }
+            _ => return Err(String::from("ERR syntax error")),
+        }
+    }
+
+    Ok(Command::Scan {
+        cursor,
+        pattern,
+        count,
+    })
+}
+
+// `mset key value [key value ...]` の key と value の組
+fn parse_pairs(input: SplitedCommand) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let args = &input[1..];
+    if args.is_empty() || !args.len().is_multiple_of(2) {
+        return Err(format!(
+            "ERR wrong number of arguments for '{}' command",
+            input[0]
+        ));
+    }
+
+    Ok((1..input.len())
+        .step_by(2)
+        .map(|i| (input[i].to_owned(), input.value(i + 1)))
+        .collect())
+}
+
+fn parse_watch_command(input: SplitedCommand) -> Result<Command, String> {
+    if input.len() < 2 {
+        return Err(String::from(
+            "ERR wrong number of arguments for 'watch' command",
+        ));
+    }
+
+    Ok(Command::Watch {
+        keys: input[1..].to_vec(),
+    })
+}
+
+// `release savepoint name` のように省略可能な savepoint キーワードも受け付ける
+fn parse_savepoint_name(args: &[String]) -> Result<String, String> {
+    let args = match args.first().map(String::as_str) {
+        Some("savepoint") if args.len() == 2 => &args[1..],
+        _ => args,
+    };
+
+    match args {
+        [name] => Ok(name.to_owned()),
+        _ => Err(String::from("ERR wrong number of arguments for savepoint")),
+    }
+}
+
+fn parse_locks_command(input: SplitedCommand) -> Result<Command, String> {
+    let args = input[1..].iter().map(String::as_str).collect::<Vec<_>>();
+
+    match args.as_slice() {
+        ["list"] => Ok(Command::LocksList { pattern: None }),
+        ["list", pattern] => Ok(Command::LocksList {
+            pattern: Some(pattern.to_string()),
+        }),
+        ["waiters"] => Ok(Command::LocksWaiters),
+        _ => Err(String::from("ERR syntax error")),
+    }
+}
+
+fn parse_multi_command(input: SplitedCommand) -> Result<Command, String> {
+    let mut options = TransactionOptions::default();
+    let mut args = input[1..].iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "nowait" => options.lock_wait = LockWait::NoWait,
+            "queued" => options.queued = true,
+            "snapshot" => options.snapshot = true,
+            "readonly" => options.readonly = true,
+            "timeout" => {
+                let millis = match args.next().map(|ms| ms.parse::<u64>()) {
+                    Some(Ok(millis)) => millis,
+                    _ => return Err(String::from("ERR timeout is not an integer")),
+                };
+                options.lock_wait = LockWait::Timeout(Duration::from_millis(millis));
+            }
+            _ => return Err(String::from("ERR syntax error")),
+        }
+    }
+
+    Ok(Command::Multi(options))
+}
+
+/// The arguments of a command line. It derefs to them as text, where bytes that are not
+/// UTF-8 are replaced, so arguments that are values are taken with `value` instead.
+#[derive(Debug, Clone, PartialEq)]
+struct SplitedCommand {
+    args: Vec<String>,
+    bytes: Vec<Vec<u8>>,
+}
+
+impl SplitedCommand {
+    fn new(bytes: Vec<Vec<u8>>) -> SplitedCommand {
+        let args = bytes
+            .iter()
+            .map(|arg| String::from_utf8_lossy(arg).into_owned())
+            .collect();
+        SplitedCommand { args, bytes }
+    }
+
+    fn value(&self, index: usize) -> Vec<u8> {
+        self.bytes[index].clone()
+    }
+}
+
+impl Deref for SplitedCommand {
+    type Target = [String];
+
+    fn deref(&self) -> &[String] {
+        &self.args
+    }
+}
 
 fn split_input<S: Into<String>>(input: S) -> Result<SplitedCommand, String> {
     let mut lexer = Lexer::new(input.into());
@@ -99,18 +709,20 @@ fn split_input<S: Into<String>>(input: S) -> Result<SplitedCommand, String> {
 
         if is_letter(lexer.current_ch()) {
             let ident = lexer.read_identifier();
-            splited_command.push(ident);
+            splited_command.push(ident.into_bytes());
         }
 
         lexer.read_char();
     }
 
-    Ok(splited_command)
+    Ok(SplitedCommand::new(splited_command))
 }
 
-fn is_letter(ch: char) -> bool {
-    const CHS: [char; 3] = ['|', '-', '+'];
-    'a' <= ch && ch <= 'z' || 'A' <= ch && ch <= 'Z' || CHS.iter().any(|c| &ch == c)
+/// True for the characters an unquoted argument can start with. `Command`'s `Display`
+/// quotes every argument that has any other character in it.
+pub(crate) fn is_letter(ch: char) -> bool {
+    const CHS: [char; 9] = ['|', '-', '+', '*', '?', '[', '\\', '.', ':'];
+    ch.is_ascii_alphanumeric() || CHS.iter().any(|c| &ch == c)
 }
 
 #[cfg(test)]
@@ -125,6 +737,9 @@ mod test {
                 Ok(Command::Set {
                     key: "key".into(),
                     value: "value".into(),
+                    expire: None,
+                    condition: None,
+                    get: false,
                 }),
             ),
             (
@@ -138,12 +753,64 @@ mod test {
             (
                 vec!["del", "key", "key2"],
                 Ok(Command::Del {
-                    keys: str_vec_to_splited_command(vec!["key", "key2"]),
+                    keys: vec!["key".into(), "key2".into()],
                 }),
             ),
-            (vec!["multi"], Ok(Command::Multi)),
+            (
+                vec!["multi"],
+                Ok(Command::Multi(TransactionOptions::default())),
+            ),
             (vec!["exec"], Ok(Command::Exec)),
+            (
+                vec!["watch", "key", "key2"],
+                Ok(Command::Watch {
+                    keys: vec!["key".into(), "key2".into()],
+                }),
+            ),
+            (
+                vec!["watch"],
+                Err(String::from(
+                    "ERR wrong number of arguments for 'watch' command",
+                )),
+            ),
+            (vec!["unwatch"], Ok(Command::Unwatch)),
             (vec!["abort"], Ok(Command::Abort)),
+            (
+                vec!["savepoint", "sp"],
+                Ok(Command::Savepoint { name: "sp".into() }),
+            ),
+            (
+                vec!["rollback", "to", "sp"],
+                Ok(Command::RollbackTo { name: "sp".into() }),
+            ),
+            (
+                vec!["rollback", "to", "savepoint", "sp"],
+                Ok(Command::RollbackTo { name: "sp".into() }),
+            ),
+            (
+                vec!["release", "sp"],
+                Ok(Command::Release { name: "sp".into() }),
+            ),
+            (
+                vec!["release", "savepoint", "sp"],
+                Ok(Command::Release { name: "sp".into() }),
+            ),
+            (
+                vec!["rollback", "sp"],
+                Err(String::from("ERR syntax error")),
+            ),
+            (
+                vec!["savepoint"],
+                Err(String::from("ERR wrong number of arguments for savepoint")),
+            ),
+            (vec!["backup"], Ok(Command::Backup)),
+            (vec!["ttl", "key"], Ok(Command::Ttl { key: "key".into() })),
+            (vec!["pttl", "key"], Ok(Command::PTtl { key: "key".into() })),
+            (
+                vec!["persist", "key"],
+                Ok(Command::Persist { key: "key".into() }),
+            ),
+            (vec!["ttl"], Err(String::from("not input key"))),
         ];
 
         for (input, expect) in test_case {
@@ -194,6 +861,20 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_split_input_byte_escape() {
+        assert_eq!(
+            parse(r#"set key "\xff\x41\xz1""#),
+            Ok(Command::Set {
+                key: "key".into(),
+                value: b"\xffA\\xz1".to_vec(),
+                expire: None,
+                condition: None,
+                get: false,
+            })
+        );
+    }
+
     #[test]
     fn test_split_input_error() {
         assert!(split_input(r#"set key "value"#).is_err());
@@ -207,17 +888,143 @@ mod test {
             output,
             Ok(Command::Set {
                 key: "key".into(),
-                value: "value".into()
+                value: "value".into(),
+                expire: None,
+                condition: None,
+                get: false,
             })
         );
     }
 
+    #[test]
+    fn test_parse_set_command_with_expire() {
+        let cases = vec![
+            (vec!["ex", "10"], Expire::After(10_000)),
+            (vec!["px", "10"], Expire::After(10)),
+            (vec!["exat", "1600000000"], Expire::At(1_600_000_000_000)),
+            (vec!["pxat", "1600000000000"], Expire::At(1_600_000_000_000)),
+        ];
+
+        for (option, expire) in cases {
+            let mut input = vec!["set", "key", "value"];
+            input.extend(option);
+            let input = str_vec_to_splited_command(input);
+
+            assert_eq!(
+                parse_set_command(input),
+                Ok(Command::Set {
+                    key: "key".into(),
+                    value: "value".into(),
+                    expire: Some(expire),
+                    condition: None,
+                    get: false,
+                })
+            );
+        }
+
+        let errors = vec![
+            (vec!["ex", "0"], "ERR invalid expire time in 'set' command"),
+            (vec!["px", "-1"], "ERR invalid expire time in 'set' command"),
+            (
+                vec!["ex", "ten"],
+                "ERR value is not an integer or out of range",
+            ),
+            (vec!["in", "10"], "ERR syntax error"),
+            (vec!["ex"], "Invalid arguments"),
+        ];
+
+        for (option, expect) in errors {
+            let mut input = vec!["set", "key", "value"];
+            input.extend(option);
+            let input = str_vec_to_splited_command(input);
+
+            assert_eq!(parse_set_command(input), Err(String::from(expect)));
+        }
+    }
+
+    #[test]
+    fn test_parse_set_command_with_condition() {
+        assert_eq!(
+            parse("set key value xx get px 10"),
+            Ok(Command::Set {
+                key: "key".into(),
+                value: "value".into(),
+                expire: Some(Expire::After(10)),
+                condition: Some(SetCondition::Exists),
+                get: true,
+            })
+        );
+        assert_eq!(
+            parse("getset key value"),
+            Ok(Command::Set {
+                key: "key".into(),
+                value: "value".into(),
+                expire: None,
+                condition: None,
+                get: true,
+            })
+        );
+        assert_eq!(
+            parse("cas key old new"),
+            Ok(Command::Cas {
+                key: "key".into(),
+                expected: "old".into(),
+                value: "new".into(),
+            })
+        );
+        assert_eq!(
+            parse("set key value get get"),
+            Err(String::from("ERR syntax error"))
+        );
+    }
+
+    #[test]
+    fn test_parse_expire_command() {
+        let cases = vec![
+            (vec!["expire", "key", "10"], Ok(Expire::After(10_000))),
+            (vec!["pexpire", "key", "-1"], Ok(Expire::After(-1))),
+            (
+                vec!["expireat", "key", "1600000000"],
+                Ok(Expire::At(1_600_000_000_000)),
+            ),
+            (
+                vec!["pexpireat", "key", "1600000000000"],
+                Ok(Expire::At(1_600_000_000_000)),
+            ),
+            (
+                vec!["expire", "key"],
+                Err(String::from(
+                    "ERR wrong number of arguments for 'expire' command",
+                )),
+            ),
+            (
+                vec!["expire", "key", "9223372036854775807"],
+                Err(String::from("ERR invalid expire time in 'expire' command")),
+            ),
+            (
+                vec!["pexpire", "key", "soon"],
+                Err(String::from("ERR value is not an integer or out of range")),
+            ),
+        ];
+
+        for (input, expected) in cases {
+            let input = str_vec_to_splited_command(input);
+            let expected = expected.map(|expire| Command::Expire {
+                key: "key".into(),
+                expire,
+            });
+
+            assert_eq!(parse_expire_command(input), expected);
+        }
+    }
+
     #[test]
     fn test_parse_set_command_error() {
         let test_case = vec![
             (vec!["set", "key"], "not input value"),
             (vec!["set"], "not input key"),
-            (vec!["set", "key", "value", "invalid"], "Invalid arguments"),
+            (vec!["set", "key", "value", "invalid"], "ERR syntax error"),
+            (vec!["set", "key", "value", "nx", "xx"], "ERR syntax error"),
         ];
 
         for (input, expect) in test_case {
@@ -279,7 +1086,7 @@ mod test {
             (
                 vec!["del", "key1", "key2", "key3"],
                 Ok(Command::Del {
-                    keys: str_vec_to_splited_command(vec!["key1", "key2", "key3"]),
+                    keys: vec!["key1".into(), "key2".into(), "key3".into()],
                 }),
             ),
         ];
@@ -302,7 +1109,467 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_parse_multi_command() {
+        let test_case = vec![
+            (vec!["multi"], Ok(LockWait::Default)),
+            (vec!["multi", "nowait"], Ok(LockWait::NoWait)),
+            (
+                vec!["multi", "timeout", "500"],
+                Ok(LockWait::Timeout(Duration::from_millis(500))),
+            ),
+            (
+                vec!["multi", "timeout"],
+                Err(String::from("ERR timeout is not an integer")),
+            ),
+            (
+                vec!["multi", "invalid"],
+                Err(String::from("ERR syntax error")),
+            ),
+        ];
+
+        for (input, expected) in test_case {
+            let input = str_vec_to_splited_command(input);
+            let expected = expected.map(|lock_wait| {
+                Command::Multi(TransactionOptions {
+                    lock_wait,
+                    ..TransactionOptions::default()
+                })
+            });
+
+            assert_eq!(parse_multi_command(input), expected);
+        }
+    }
+
+    #[test]
+    fn test_parse_multi_queued_command() {
+        let input = str_vec_to_splited_command(vec!["multi", "queued"]);
+
+        assert_eq!(
+            parse_multi_command(input),
+            Ok(Command::Multi(TransactionOptions {
+                queued: true,
+                ..TransactionOptions::default()
+            }))
+        );
+    }
+
+    #[test]
+    fn test_parse_multi_snapshot_command() {
+        let input = str_vec_to_splited_command(vec!["multi", "snapshot"]);
+
+        assert_eq!(
+            parse_multi_command(input),
+            Ok(Command::Multi(TransactionOptions {
+                snapshot: true,
+                ..TransactionOptions::default()
+            }))
+        );
+    }
+
+    #[test]
+    fn test_parse_multi_readonly_command() {
+        let input = str_vec_to_splited_command(vec!["multi", "readonly"]);
+
+        assert_eq!(
+            parse_multi_command(input),
+            Ok(Command::Multi(TransactionOptions {
+                readonly: true,
+                ..TransactionOptions::default()
+            }))
+        );
+    }
+
+    #[test]
+    fn test_parse_locks_command() {
+        let cases = vec![
+            (
+                vec!["locks", "list"],
+                Ok(Command::LocksList { pattern: None }),
+            ),
+            (
+                vec!["locks", "list", "user:*"],
+                Ok(Command::LocksList {
+                    pattern: Some("user:*".into()),
+                }),
+            ),
+            (vec!["locks", "waiters"], Ok(Command::LocksWaiters)),
+            (vec!["locks"], Err(String::from("ERR syntax error"))),
+            (
+                vec!["locks", "waiters", "a"],
+                Err(String::from("ERR syntax error")),
+            ),
+        ];
+
+        for (input, expected) in cases {
+            let input = str_vec_to_splited_command(input);
+            assert_eq!(parse_locks_command(input), expected);
+        }
+    }
+
+    #[test]
+    fn test_split_input_include_glob_pattern() {
+        assert_eq!(
+            split_input("locks list *"),
+            Ok(str_vec_to_splited_command(vec!["locks", "list", "*"]))
+        );
+        assert_eq!(
+            split_input("locks list [ab]*"),
+            Ok(str_vec_to_splited_command(vec!["locks", "list", "[ab]*"]))
+        );
+    }
+
+    #[test]
+    fn test_split_input_include_digits() {
+        assert_eq!(
+            split_input("multi timeout 500"),
+            Ok(str_vec_to_splited_command(vec!["multi", "timeout", "500"]))
+        );
+        assert_eq!(
+            split_input("set key1 100"),
+            Ok(str_vec_to_splited_command(vec!["set", "key1", "100"]))
+        );
+    }
+
+    #[test]
+    fn test_parse_incr_command() {
+        let cases = vec![
+            (vec!["incr", "key"], Ok(1)),
+            (vec!["decr", "key"], Ok(-1)),
+            (vec!["incrby", "key", "-5"], Ok(-5)),
+            (vec!["decrby", "key", "5"], Ok(-5)),
+            (
+                vec!["decrby", "key", "-9223372036854775808"],
+                Err(String::from("ERR decrement would overflow")),
+            ),
+            (
+                vec!["incrby", "key", "1.5"],
+                Err(String::from(NOT_INTEGER_ERROR)),
+            ),
+            (
+                vec!["incr", "key", "1"],
+                Err(String::from(
+                    "ERR wrong number of arguments for 'incr' command",
+                )),
+            ),
+        ];
+
+        for (input, expected) in cases {
+            let input = str_vec_to_splited_command(input);
+            let expected = expected.map(|increment| Command::IncrBy {
+                key: "key".into(),
+                increment,
+            });
+
+            assert_eq!(parse_incr_command(input), expected);
+        }
+
+        assert_eq!(
+            parse("incrbyfloat key .5"),
+            Ok(Command::IncrByFloat {
+                key: "key".into(),
+                increment: 0.5,
+            })
+        );
+        assert_eq!(
+            parse("incrbyfloat key inf"),
+            Err(String::from(NOT_FLOAT_ERROR))
+        );
+    }
+
+    #[test]
+    fn test_parse_string_range_commands() {
+        assert_eq!(
+            parse("getrange key 0 -1"),
+            Ok(Command::GetRange {
+                key: "key".into(),
+                start: 0,
+                end: -1,
+            })
+        );
+        assert_eq!(
+            parse("setrange key 5 value"),
+            Ok(Command::SetRange {
+                key: "key".into(),
+                offset: 5,
+                value: "value".into(),
+            })
+        );
+        assert_eq!(
+            parse("setrange key -1 value"),
+            Err(String::from("ERR offset is out of range"))
+        );
+        assert_eq!(
+            parse("getrange key 0"),
+            Err(String::from(
+                "ERR wrong number of arguments for 'getrange' command"
+            ))
+        );
+        assert_eq!(parse("append key"), Err(String::from("not input value")));
+    }
+
+    #[test]
+    fn test_parse_multi_key_commands() {
+        assert_eq!(
+            parse("mget a b"),
+            Ok(Command::MGet {
+                keys: vec!["a".into(), "b".into()],
+            })
+        );
+        assert_eq!(
+            parse("msetnx a 1 b 2"),
+            Ok(Command::MSetNX {
+                pairs: vec![("a".into(), "1".into()), ("b".into(), "2".into())],
+            })
+        );
+        assert_eq!(
+            parse("mset a 1 b"),
+            Err(String::from(
+                "ERR wrong number of arguments for 'mset' command"
+            ))
+        );
+        assert_eq!(
+            parse("mget"),
+            Err(String::from(
+                "ERR wrong number of arguments for 'mget' command"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_key_management_commands() {
+        assert_eq!(
+            parse("renamenx a b"),
+            Ok(Command::RenameNX {
+                key: "a".into(),
+                newkey: "b".into(),
+            })
+        );
+        assert_eq!(
+            parse("copy a b replace"),
+            Ok(Command::Copy {
+                source: "a".into(),
+                destination: "b".into(),
+                replace: true,
+            })
+        );
+        assert_eq!(
+            parse("copy a b force"),
+            Err(String::from("ERR syntax error"))
+        );
+        assert_eq!(
+            parse("rename a"),
+            Err(String::from(
+                "ERR wrong number of arguments for 'rename' command"
+            ))
+        );
+        assert_eq!(parse("flushdb"), Ok(Command::FlushDb));
+    }
+
+    #[test]
+    fn test_parse_scan_command() {
+        assert_eq!(
+            parse("scan 0"),
+            Ok(Command::Scan {
+                cursor: 0,
+                pattern: None,
+                count: DEFAULT_SCAN_COUNT,
+            })
+        );
+        assert_eq!(
+            parse("scan 42 count 100 match user:*"),
+            Ok(Command::Scan {
+                cursor: 42,
+                pattern: Some("user:*".into()),
+                count: 100,
+            })
+        );
+        assert_eq!(parse("scan -1"), Err(String::from("ERR invalid cursor")));
+        assert_eq!(
+            parse("scan 0 count 0"),
+            Err(String::from("ERR syntax error"))
+        );
+        assert_eq!(parse("scan 0 match"), Err(String::from("ERR syntax error")));
+    }
+
+    #[test]
+    fn test_parse_list_commands() {
+        assert_eq!(
+            parse("rpush queue a \"b c\""),
+            Ok(Command::RPush {
+                key: "queue".into(),
+                elements: vec!["a".into(), "b c".into()],
+            })
+        );
+        assert_eq!(
+            parse("lpop queue 2"),
+            Ok(Command::LPop {
+                key: "queue".into(),
+                count: Some(2),
+            })
+        );
+        assert_eq!(
+            parse("lrem queue -2 a"),
+            Ok(Command::LRem {
+                key: "queue".into(),
+                count: -2,
+                element: "a".into(),
+            })
+        );
+        assert_eq!(
+            parse("rpop queue -1"),
+            Err(String::from("ERR value is out of range, must be positive"))
+        );
+        assert_eq!(
+            parse("lpush queue"),
+            Err(String::from(
+                "ERR wrong number of arguments for 'lpush' command"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_blocking_list_commands() {
+        assert_eq!(
+            parse("blpop a b 0"),
+            Ok(Command::BLPop {
+                keys: vec!["a".into(), "b".into()],
+                timeout: None,
+            })
+        );
+        assert_eq!(
+            parse("brpop a 1.5"),
+            Ok(Command::BRPop {
+                keys: vec!["a".into()],
+                timeout: Some(Duration::from_millis(1500)),
+            })
+        );
+        assert_eq!(
+            parse("blmove a b right left 2"),
+            Ok(Command::BLMove {
+                source: "a".into(),
+                destination: "b".into(),
+                from: ListEnd::Right,
+                to: ListEnd::Left,
+                timeout: Some(Duration::from_secs(2)),
+            })
+        );
+        assert_eq!(
+            parse("blpop a -1"),
+            Err(String::from("ERR timeout is negative"))
+        );
+        assert_eq!(
+            parse("blpop a b"),
+            Err(String::from("ERR timeout is not a float or out of range"))
+        );
+        assert_eq!(
+            parse("blmove a b up left 0"),
+            Err(String::from("ERR syntax error"))
+        );
+        assert_eq!(
+            parse("blpop 0"),
+            Err(String::from(
+                "ERR wrong number of arguments for 'blpop' command"
+            ))
+        );
+    }
+
+    // 引数がどんな文字を含んでいても、log に書いた command を読み直すと元に戻る
+    #[test]
+    fn test_display_and_parse_roundtrip() {
+        let mut values = [
+            "",
+            " ",
+            "#tag",
+            "_id",
+            "/tmp/x",
+            "@me",
+            "{job}",
+            "日本語",
+            "say \"hi\"",
+            "\"",
+            "back\\slash",
+            "\\",
+            "\\\"",
+            "ends with \\",
+            "line\nbreak",
+            "cr\r",
+            "tab\tx",
+            "nul\0",
+            "a b  c",
+            "\\n",
+            "\\x41",
+            "\\xc3",
+            "-1",
+            "émoji 🦀",
+        ]
+        .iter()
+        .map(|value| value.to_string())
+        .collect::<Vec<_>>();
+
+        // 記号や空白、ASCII 以外の文字を混ぜた値を、決まった seed から生成する
+        let alphabet = "ab1 \"\\\n\r\t\0#_/@{}()'`~!$%^&=<>,;éあ🦀|-+*?[.:"
+            .chars()
+            .collect::<Vec<_>>();
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        for _ in 0..500 {
+            let mut value = String::new();
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            for i in 0..seed % 12 {
+                value.push(alphabet[((seed >> (i * 5)) % alphabet.len() as u64) as usize]);
+            }
+            values.push(value);
+        }
+
+        for value in &values {
+            // UTF-8 として読めない byte を混ぜた値は `\xNN` で書かれる
+            let mut binary = value.clone().into_bytes();
+            binary.insert(binary.len() / 2, 0xff);
+            binary.push(0xc3);
+
+            let commands = vec![
+                Command::Set {
+                    key: value.clone(),
+                    value: value.clone().into(),
+                    expire: None,
+                    condition: None,
+                    get: false,
+                },
+                Command::Cas {
+                    key: "key".into(),
+                    expected: value.clone().into(),
+                    value: binary.clone(),
+                },
+                Command::MSet {
+                    pairs: vec![
+                        (value.clone(), binary.clone()),
+                        ("k".into(), value.clone().into()),
+                    ],
+                },
+                Command::RPush {
+                    key: "list".into(),
+                    elements: vec![binary.clone(), "".into(), value.clone().into()],
+                },
+                Command::Rename {
+                    key: value.clone(),
+                    newkey: "other".into(),
+                },
+            ];
+
+            for command in commands {
+                assert_eq!(
+                    parse(command.to_string()),
+                    Ok(command.clone()),
+                    "{}",
+                    command
+                );
+            }
+        }
+    }
+
     fn str_vec_to_splited_command(input: Vec<&str>) -> SplitedCommand {
-        input.iter().map(ToString::to_string).collect()
+        SplitedCommand::new(input.iter().map(|arg| arg.as_bytes().to_vec()).collect())
     }
 }
@@ -0,0 +1,19 @@
+/// Renders several replies on a single protocol line.
+/// Each item is quoted, so items containing spaces or commas stay unambiguous.
+pub(crate) fn array(items: Vec<String>) -> String {
+    let items = items
+        .iter()
+        .map(|item| format!("{:?}", item))
+        .collect::<Vec<_>>();
+
+    format!("[{}]", items.join(", "))
+}
+
+#[test]
+fn test_array() {
+    assert_eq!(array(vec![]), "[]");
+    assert_eq!(
+        array(vec!["OK".into(), "a \"b\"".into()]),
+        r#"["OK", "a \"b\""]"#
+    );
+}
@@ -0,0 +1,468 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::keyspace::{self, Entry, Keyspace, Value};
+use crate::memdb::Memdb;
+
+pub const DEFAULT_SHARDS: usize = 16;
+
+/// The keyspace split by key hash into `Memdb`s, each behind its own lock, so that
+/// writers to different keys don't wait for each other.
+///
+/// Commands touching several shards lock them in index order, which keeps them from
+/// deadlocking. All shards share one clock, so versions and snapshot timestamps can
+/// be compared across shards.
+#[derive(Debug)]
+pub(crate) struct Shards {
+    shards: Vec<RwLock<Memdb>>,
+}
+
+/// Locks held on some of the shards, which can only access keys in those shards.
+pub(crate) struct ShardGuards<G> {
+    count: usize,
+    guards: BTreeMap<usize, G>,
+}
+
+pub(crate) type ShardsReader<'a> = ShardGuards<RwLockReadGuard<'a, Memdb>>;
+pub(crate) type ShardsWriter<'a> = ShardGuards<RwLockWriteGuard<'a, Memdb>>;
+
+fn shard_index(key: &str, count: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+
+    (hasher.finish() % count as u64) as usize
+}
+
+impl Shards {
+    pub fn new(memdb: Memdb, count: usize) -> Shards {
+        let count = count.max(1);
+        let clock = Arc::new(AtomicU64::new(0));
+
+        let mut shards = (0..count)
+            .map(|_| Memdb::with_clock(clock.clone()))
+            .collect::<Vec<_>>();
+        for (key, entry) in memdb.entries() {
+            shards[shard_index(key, count)]
+                .write_entry(key, entry)
+                .unwrap();
+        }
+
+        Shards {
+            shards: shards.into_iter().map(RwLock::new).collect(),
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// The index of the shard holding `key`.
+    pub fn shard_of(&self, key: &str) -> usize {
+        shard_index(key, self.shards.len())
+    }
+
+    fn shard(&self, key: &str) -> &RwLock<Memdb> {
+        &self.shards[shard_index(key, self.shards.len())]
+    }
+
+    pub fn get_entry(&self, key: &str) -> Option<Entry> {
+        self.shard(key).read().unwrap().get_entry(key)
+    }
+
+    pub fn get_entry_at(&self, key: &str, timestamp: u64) -> Option<Entry> {
+        self.shard(key).read().unwrap().get_entry_at(key, timestamp)
+    }
+
+    /// Runs `Memdb::expire_cycle` on each shard in turn, so only one shard is locked at a time.
+    pub fn expire_cycle(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.write().unwrap().expire_cycle())
+            .sum()
+    }
+
+    pub fn version(&self, key: &str) -> u64 {
+        self.shard(key).read().unwrap().version(key)
+    }
+
+    /// `Memdb::stored_keys` of every shard, locking one shard at a time.
+    pub fn stored_keys(&self) -> Vec<String> {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .read()
+                    .unwrap()
+                    .stored_keys()
+                    .cloned()
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// `Memdb::scan_range` of the shard `index`, merged with the keys a transaction wrote
+    /// that the shard doesn't hold yet.
+    pub fn scan_range(
+        &self,
+        index: usize,
+        from: u64,
+        count: usize,
+        written: Vec<String>,
+    ) -> Vec<(u64, String)> {
+        let mut keys = self.shards[index].read().unwrap().scan_range(from, count);
+        keys.extend(
+            written
+                .into_iter()
+                .filter(|key| self.shard_of(key) == index)
+                .map(|key| (keyspace::scan_position(&key), key))
+                .filter(|(position, _)| *position >= from),
+        );
+        keys.sort_unstable();
+        keys.dedup();
+        keys.truncate(count);
+
+        keys
+    }
+
+    /// Takes shared locks on the shards holding `keys`.
+    pub fn read<'a>(&self, keys: impl IntoIterator<Item = &'a str>) -> ShardsReader<'_> {
+        self.lock(self.indexes(keys), |shard| shard.read().unwrap())
+    }
+
+    /// Takes exclusive locks on the shards holding `keys`.
+    pub fn write<'a>(&self, keys: impl IntoIterator<Item = &'a str>) -> ShardsWriter<'_> {
+        self.lock(self.indexes(keys), |shard| shard.write().unwrap())
+    }
+
+    /// Takes a shared lock on the shard `index` alone, or on none when there is no such shard.
+    pub fn read_shard(&self, index: usize) -> ShardsReader<'_> {
+        let indexes = (index < self.shards.len()).then_some(index);
+        self.lock(indexes, |shard| shard.read().unwrap())
+    }
+
+    pub fn read_all(&self) -> ShardsReader<'_> {
+        self.lock(0..self.shards.len(), |shard| shard.read().unwrap())
+    }
+
+    pub fn write_all(&self) -> ShardsWriter<'_> {
+        self.lock(0..self.shards.len(), |shard| shard.write().unwrap())
+    }
+
+    /// Opens a snapshot on every shard. While all shards are locked nothing can advance
+    /// the clock, so every shard returns the same timestamp.
+    pub fn begin_snapshot(&self) -> u64 {
+        let mut shards = self.write_all();
+
+        shards
+            .guards
+            .values_mut()
+            .map(|memdb| memdb.begin_snapshot())
+            .last()
+            .unwrap()
+    }
+
+    pub fn end_snapshot(&self, timestamp: u64) {
+        self.write_all()
+            .guards
+            .values_mut()
+            .for_each(|memdb| memdb.end_snapshot(timestamp));
+    }
+
+    fn indexes<'a>(&self, keys: impl IntoIterator<Item = &'a str>) -> Vec<usize> {
+        let mut indexes = keys
+            .into_iter()
+            .map(|key| shard_index(key, self.shards.len()))
+            .collect::<Vec<_>>();
+        indexes.sort_unstable();
+        indexes.dedup();
+
+        indexes
+    }
+
+    // indexes は昇順なので、複数のshardのlockは常に同じ順番で取られる
+    fn lock<'a, G>(
+        &'a self,
+        indexes: impl IntoIterator<Item = usize>,
+        lock: impl Fn(&'a RwLock<Memdb>) -> G,
+    ) -> ShardGuards<G> {
+        ShardGuards {
+            count: self.shards.len(),
+            guards: indexes
+                .into_iter()
+                .map(|i| (i, lock(&self.shards[i])))
+                .collect(),
+        }
+    }
+}
+
+impl<G: Deref<Target = Memdb>> ShardGuards<G> {
+    /// `Memdb::stored_keys` of the locked shards.
+    fn locked_keys(&self) -> Vec<String> {
+        self.guards
+            .values()
+            .flat_map(|memdb| memdb.stored_keys().cloned())
+            .collect()
+    }
+
+    /// `Memdb::scan_range` of the locked shard `index`.
+    fn scan_range(&self, index: usize, from: u64, count: usize) -> Vec<(u64, String)> {
+        self.guards
+            .get(&index)
+            .expect("the shard is not locked")
+            .scan_range(from, count)
+    }
+
+    fn memdb(&self, key: &str) -> &Memdb {
+        self.guards
+            .get(&shard_index(key, self.count))
+            .expect("the shard of the key is not locked")
+    }
+
+    pub fn version(&self, key: &str) -> u64 {
+        self.memdb(key).version(key)
+    }
+
+    /// The locked shards in the format of `Memdb::serialize`.
+    pub fn serialize(&self) -> Vec<u8> {
+        self.guards
+            .values()
+            .flat_map(|memdb| memdb.serialize())
+            .collect()
+    }
+}
+
+impl<G: DerefMut<Target = Memdb>> ShardGuards<G> {
+    fn memdb_mut(&mut self, key: &str) -> &mut Memdb {
+        self.guards
+            .get_mut(&shard_index(key, self.count))
+            .expect("the shard of the key is not locked")
+    }
+}
+
+impl Keyspace for ShardsReader<'_> {
+    fn read_entry(&mut self, key: &str) -> Result<Option<Entry>, String> {
+        Ok(self.memdb(key).get_entry(key))
+    }
+
+    fn stored_keys(&mut self) -> Result<Vec<String>, String> {
+        Ok(self.locked_keys())
+    }
+
+    // scan は shard ごとに進むので、一度に lock する必要があるのは一つの shard だけ
+    fn scan_partitions(&self) -> usize {
+        self.count
+    }
+
+    fn partition_range(
+        &mut self,
+        index: usize,
+        from: u64,
+        count: usize,
+    ) -> Result<Vec<(u64, String)>, String> {
+        Ok(self.scan_range(index, from, count))
+    }
+
+    fn read_with<R>(
+        &mut self,
+        key: &str,
+        f: impl FnOnce(Option<&Value>) -> R,
+    ) -> Result<R, String> {
+        Ok(f(self.memdb(key).get_value(key)))
+    }
+
+    fn write_entry(&mut self, _key: &str, _entry: Entry) -> Result<(), String> {
+        Err(String::from("ERR write command on a read-only path"))
+    }
+
+    fn remove(&mut self, _key: &str) -> Result<bool, String> {
+        Err(String::from("ERR write command on a read-only path"))
+    }
+}
+
+impl Keyspace for ShardsWriter<'_> {
+    fn read_entry(&mut self, key: &str) -> Result<Option<Entry>, String> {
+        self.memdb_mut(key).read_entry(key)
+    }
+
+    fn write_entry(&mut self, key: &str, entry: Entry) -> Result<(), String> {
+        self.memdb_mut(key).write_entry(key, entry)
+    }
+
+    fn remove(&mut self, key: &str) -> Result<bool, String> {
+        self.memdb_mut(key).remove(key)
+    }
+
+    fn stored_keys(&mut self) -> Result<Vec<String>, String> {
+        Ok(self.locked_keys())
+    }
+
+    fn scan_partitions(&self) -> usize {
+        self.count
+    }
+
+    fn partition_range(
+        &mut self,
+        index: usize,
+        from: u64,
+        count: usize,
+    ) -> Result<Vec<(u64, String)>, String> {
+        Ok(self.scan_range(index, from, count))
+    }
+
+    fn read_with<R>(
+        &mut self,
+        key: &str,
+        f: impl FnOnce(Option<&Value>) -> R,
+    ) -> Result<R, String> {
+        self.memdb_mut(key).read_with(key, f)
+    }
+
+    fn update_value<R>(
+        &mut self,
+        key: &str,
+        f: impl FnOnce(&mut Option<Value>) -> Result<R, String>,
+    ) -> Result<R, String> {
+        self.memdb_mut(key).update_value(key, f)
+    }
+}
+
+#[test]
+fn test_shards_split_keyspace() {
+    let mut memdb = Memdb::new();
+    (0..100).for_each(|i| memdb.set(format!("key{}", i), format!("{}", i)));
+
+    let shards = Shards::new(memdb.clone(), 4);
+
+    assert_eq!(shards.get_entry("key42"), Some(Entry::new(b"42".to_vec())));
+    assert!(shards
+        .shards
+        .iter()
+        .all(|s| !s.read().unwrap().inner().is_empty()));
+    assert_eq!(
+        Memdb::deserialize(&shards.read_all().serialize()),
+        Ok(memdb)
+    );
+}
+
+#[test]
+fn test_shards_lock_only_touched_shards() {
+    let shards = Shards::new(Memdb::new(), 4);
+    let mut writer = shards.write(vec!["a", "b", "a"]);
+    writer.write("a", b"1".to_vec()).unwrap();
+
+    let expected = shards.indexes(vec!["b", "a"]);
+    assert_eq!(writer.guards.keys().copied().collect::<Vec<_>>(), expected);
+
+    // shards that are not locked by the writer can still be read
+    let other = (0..100)
+        .map(|i| format!("key{}", i))
+        .find(|key| !expected.contains(&shard_index(key, 4)))
+        .unwrap();
+    assert_eq!(shards.get_entry(&other), None);
+}
+
+#[test]
+#[should_panic]
+fn test_shards_access_to_unlocked_shard() {
+    let shards = Shards::new(Memdb::new(), 4);
+    let mut writer = shards.write(vec!["a"]);
+
+    let other = (0..100)
+        .map(|i| format!("key{}", i))
+        .find(|key| shard_index(key, 4) != shard_index("a", 4))
+        .unwrap();
+    writer.write(&other, b"1".to_vec()).unwrap();
+}
+
+#[test]
+fn test_shards_snapshot() {
+    let mut memdb = Memdb::new();
+    memdb.set("a", "old");
+    memdb.set("b", "old");
+    let shards = Shards::new(memdb, 4);
+
+    let snapshot = shards.begin_snapshot();
+    let mut writer = shards.write(vec!["a", "b"]);
+    writer.write("a", b"new".to_vec()).unwrap();
+    writer.remove("b").unwrap();
+    drop(writer);
+
+    assert!(shards.version("a") > snapshot);
+    let old = Some(Entry::new(b"old".to_vec()));
+    assert_eq!(shards.get_entry_at("a", snapshot), old);
+    assert_eq!(shards.get_entry_at("b", snapshot), old);
+    assert_eq!(shards.get_entry("b"), None);
+
+    shards.end_snapshot(snapshot);
+    assert!(shards
+        .shards
+        .iter()
+        .all(|s| s.read().unwrap().history_len() == 0));
+}
+
+#[test]
+fn test_shards_scan_one_shard_at_a_time() {
+    let mut memdb = Memdb::new();
+    (0..100).for_each(|i| memdb.set(format!("key{}", i), "v"));
+    let shards = Shards::new(memdb, 4);
+
+    let mut seen = vec![];
+    let mut cursor = 0;
+    loop {
+        let mut reader = shards.read_shard(crate::keyspace::scan_partition(cursor));
+        assert_eq!(reader.guards.len(), 1);
+
+        let (next, keys) = reader.scan(cursor, 10).unwrap();
+        seen.extend(keys);
+        cursor = next;
+        if cursor == 0 {
+            break;
+        }
+    }
+
+    seen.sort_unstable();
+    let mut expected = (0..100).map(|i| format!("key{}", i)).collect::<Vec<_>>();
+    expected.sort_unstable();
+    assert_eq!(seen, expected);
+}
+
+#[test]
+fn test_memdb_scan_index_follows_stored_keys() {
+    fn indexed(memdb: &Memdb) -> Vec<String> {
+        let mut keys = memdb
+            .scan_range(0, usize::MAX)
+            .into_iter()
+            .map(|(_, key)| key)
+            .collect::<Vec<_>>();
+        keys.sort_unstable();
+        keys
+    }
+    fn stored(memdb: &Memdb) -> Vec<String> {
+        let mut keys = memdb.stored_keys().cloned().collect::<Vec<_>>();
+        keys.sort_unstable();
+        keys
+    }
+
+    let mut memdb = Memdb::new();
+    (0..20).for_each(|i| memdb.set(format!("key{}", i), "v"));
+    memdb.del(vec!["key0", "key1"]);
+    assert_eq!(indexed(&memdb), stored(&memdb));
+
+    // snapshot から読める削除済みの key は、snapshot が終わるまで残る
+    let snapshot = memdb.begin_snapshot();
+    memdb.del(vec!["key2"]);
+    memdb.exec("append key20 x").unwrap();
+    assert!(indexed(&memdb).contains(&String::from("key2")));
+    assert_eq!(indexed(&memdb), stored(&memdb));
+
+    memdb.end_snapshot(snapshot);
+    assert!(!indexed(&memdb).contains(&String::from("key2")));
+    assert_eq!(indexed(&memdb), stored(&memdb));
+
+    let restored = Memdb::deserialize(&memdb.serialize()).unwrap();
+    assert_eq!(indexed(&restored), stored(&memdb));
+}
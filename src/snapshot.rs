@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+
+use crate::command::Command;
+use crate::expire;
+use crate::keyspace::{Entry, Keyspace};
+use crate::locks::TxId;
+use crate::shards::{Shards, ShardsWriter};
+use crate::transaction::{buffer_write, compact_command, write_commands, WriteSet};
+
+pub const CONFLICT_ERROR: &str =
+    "CONFLICT transaction was aborted because another client wrote the same key first";
+pub const READONLY_ERROR: &str = "READONLY You can't write in a read only transaction";
+
+/// A transaction started with `multi snapshot`.
+///
+/// Reads see the database as it was at `multi`, through the old values `Memdb` keeps
+/// for open snapshots, so no key locks are taken. Writes are buffered and checked at
+/// `exec`: if another client committed a write to one of the keys after the snapshot
+/// was taken, the first committer wins and this transaction is aborted.
+///
+/// A read-only snapshot transaction (`multi readonly`) rejects writes with `READONLY_ERROR`.
+#[derive(Debug, Default)]
+pub struct SnapshotTransaction {
+    id: TxId,
+    timestamp: u64,
+    readonly: bool,
+    write_cache: WriteSet,
+}
+
+struct SnapshotKeyspace<'a> {
+    transaction: &'a mut SnapshotTransaction,
+    shards: &'a Shards,
+    // 実行中の append か setrange。それだけで書かれた key は log にそのまま書く
+    command: Option<Command>,
+}
+
+impl SnapshotTransaction {
+    pub fn begin(id: TxId, shards: &Shards) -> SnapshotTransaction {
+        SnapshotTransaction {
+            id,
+            timestamp: shards.begin_snapshot(),
+            readonly: false,
+            write_cache: HashMap::new(),
+        }
+    }
+
+    pub fn begin_readonly(id: TxId, shards: &Shards) -> SnapshotTransaction {
+        SnapshotTransaction {
+            readonly: true,
+            ..SnapshotTransaction::begin(id, shards)
+        }
+    }
+
+    pub fn id(&self) -> TxId {
+        self.id
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    pub fn is_readonly(&self) -> bool {
+        self.readonly
+    }
+
+    pub fn exec_command(&mut self, command: Command, shards: &Shards) -> Result<String, String> {
+        SnapshotKeyspace {
+            transaction: self,
+            shards,
+            command: compact_command(&command),
+        }
+        .exec_command(command)
+    }
+
+    /// The writes `commit` will apply, as the commands to record in the log.
+    pub fn pending_writes(&self) -> Vec<Command> {
+        write_commands(&self.write_cache)
+    }
+
+    pub fn write_keys(&self) -> Vec<String> {
+        self.write_cache.keys().cloned().collect()
+    }
+
+    /// Fails with `CONFLICT_ERROR` when a key in the write set was committed by
+    /// someone else after the snapshot was taken. `db` must hold the shards of `write_keys`.
+    pub(crate) fn check_conflicts(&self, db: &ShardsWriter) -> Result<(), String> {
+        if self
+            .write_cache
+            .keys()
+            .any(|key| db.version(key) > self.timestamp)
+        {
+            return Err(String::from(CONFLICT_ERROR));
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn commit(&self, db: &mut ShardsWriter) -> Result<(), String> {
+        for (key, write) in &self.write_cache {
+            match &write.entry {
+                Some(entry) => db.write_entry(key, entry.clone())?,
+                None => {
+                    db.remove(key)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Releases the snapshot, whether the transaction was committed or aborted.
+    pub fn end(self, shards: &Shards) {
+        shards.end_snapshot(self.timestamp);
+    }
+}
+
+impl Keyspace for SnapshotKeyspace<'_> {
+    fn read_entry(&mut self, key: &str) -> Result<Option<Entry>, String> {
+        if let Some(write) = self.transaction.write_cache.get(key) {
+            return Ok(write
+                .entry
+                .clone()
+                .filter(|entry| !entry.is_expired(expire::now())));
+        }
+
+        Ok(self.shards.get_entry_at(key, self.transaction.timestamp))
+    }
+
+    fn write_entry(&mut self, key: &str, entry: Entry) -> Result<(), String> {
+        if self.transaction.readonly {
+            return Err(String::from(READONLY_ERROR));
+        }
+
+        let command = self.command.as_ref();
+        buffer_write(&mut self.transaction.write_cache, key, Some(entry), command);
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> Result<bool, String> {
+        if self.transaction.readonly {
+            return Err(String::from(READONLY_ERROR));
+        }
+
+        let existed = self.read_entry(key)?.is_some();
+
+        let command = self.command.as_ref();
+        buffer_write(&mut self.transaction.write_cache, key, None, command);
+        Ok(existed)
+    }
+
+    // snapshot の後に削除された key も history に残っているので含まれる
+    fn stored_keys(&mut self) -> Result<Vec<String>, String> {
+        let mut keys = self.shards.stored_keys();
+        keys.extend(self.transaction.write_keys());
+        keys.sort_unstable();
+        keys.dedup();
+
+        Ok(keys)
+    }
+
+    fn scan_partitions(&self) -> usize {
+        self.shards.count()
+    }
+
+    fn partition_range(
+        &mut self,
+        index: usize,
+        from: u64,
+        count: usize,
+    ) -> Result<Vec<(u64, String)>, String> {
+        let written = self.transaction.write_keys();
+        Ok(self.shards.scan_range(index, from, count, written))
+    }
+}
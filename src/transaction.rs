@@ -1,134 +1,417 @@
 use std::collections::HashMap;
-use std::sync::{Mutex, RwLock};
+use std::time::Duration;
 
 use crate::command::Command;
-use crate::locks::Locks;
-use crate::memdb::Memdb;
+use crate::expire::{self, Expire};
+use crate::keyspace::{Entry, Keyspace, Value};
+use crate::locks::{LockMode, LockWait, Locks, TxId};
+use crate::shards::Shards;
+
+/// Options given to `multi`, e.g. `multi timeout 500` or `multi nowait`.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default)]
+pub struct TransactionOptions {
+    pub lock_wait: LockWait,
+    /// `multi queued`: queue the commands and run them all at `exec`, like Redis does.
+    pub queued: bool,
+    /// `multi snapshot`: read from a snapshot without key locks, see `SnapshotTransaction`.
+    pub snapshot: bool,
+    /// `multi readonly`: a snapshot transaction that rejects writes and never touches the log.
+    pub readonly: bool,
+}
+
+pub const TXN_TOO_LARGE_ERROR: &str =
+    "TXNTOOLARGE transaction was aborted because it exceeded the size limit";
+pub const TXN_TIMEOUT_ERROR: &str =
+    "TXNTIMEOUT transaction was aborted because it exceeded the maximum duration";
+/// Limits on a single transaction, from `multi` to `exec`. `None` means unlimited.
+///
+/// A transaction exceeding a limit is aborted and its locks or snapshot are released.
+/// Size limits are checked as commands arrive; a transaction that runs too long is reaped
+/// by `Executor::reap_transactions` even if its client went idle.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct TransactionLimits {
+    pub max_commands: Option<usize>,
+    /// Total length of the commands sent inside the transaction.
+    pub max_bytes: Option<usize>,
+    pub max_duration: Option<Duration>,
+}
+
+/// Buffered writes of a transaction.
+pub(crate) type WriteSet = HashMap<String, Write>;
+
+/// The buffered write of a key, where `entry` is `None` for a deleted key.
+#[derive(Debug, Clone)]
+pub(crate) struct Write {
+    pub(crate) entry: Option<Entry>,
+    // entry を作った append と setrange。他の書き込みがあった key は None
+    commands: Option<Vec<Command>>,
+}
+
+/// Buffers a write of `key`. `command` is the `append` or `setrange` being run, if any.
+pub(crate) fn buffer_write(
+    writes: &mut WriteSet,
+    key: &str,
+    entry: Option<Entry>,
+    command: Option<&Command>,
+) {
+    let write = writes.entry(key.to_owned()).or_insert_with(|| Write {
+        entry: None,
+        commands: Some(vec![]),
+    });
+
+    write.entry = entry;
+    match (&mut write.commands, command) {
+        (Some(commands), Some(command)) => commands.push(command.clone()),
+        (commands, _) => *commands = None,
+    }
+}
+
+/// The command to pass to `buffer_write` while running `command`.
+pub(crate) fn compact_command(command: &Command) -> Option<Command> {
+    match command {
+        Command::Append { .. } | Command::SetRange { .. } => Some(command.clone()),
+        _ => None,
+    }
+}
+
+// 上の layer の書き込みを下の layer に重ねる
+fn merge_writes(below: &mut WriteSet, above: WriteSet) {
+    for (key, write) in above {
+        match below.get_mut(&key) {
+            Some(lower) => {
+                lower.commands = match (lower.commands.take(), write.commands) {
+                    (Some(mut commands), Some(more)) => {
+                        commands.extend(more);
+                        Some(commands)
+                    }
+                    _ => None,
+                };
+                lower.entry = write.entry;
+            }
+            None => {
+                below.insert(key, write);
+            }
+        }
+    }
+}
+
+/// Turns a write set into the commands that replay it. A key whose only writes were
+/// `append` and `setrange` is logged with those commands, as in normal mode, and any
+/// other key with its final value. A list is replaced as a whole by deleting the key
+/// and pushing every element again.
+pub(crate) fn write_commands(write_cache: &WriteSet) -> Vec<Command> {
+    write_cache
+        .iter()
+        .flat_map(|(key, write)| match (&write.commands, &write.entry) {
+            (Some(commands), _) => commands.clone(),
+            (
+                None,
+                Some(Entry {
+                    value: Value::String(value),
+                    deadline,
+                }),
+            ) => vec![Command::Set {
+                key: key.to_owned(),
+                value: value.to_owned(),
+                expire: deadline.map(|deadline| Expire::At(deadline as i64)),
+                condition: None,
+                get: false,
+            }],
+            (
+                None,
+                Some(Entry {
+                    value: Value::List(list),
+                    deadline,
+                }),
+            ) => {
+                let mut commands = vec![
+                    Command::Del {
+                        keys: vec![key.to_owned()],
+                    },
+                    Command::RPush {
+                        key: key.to_owned(),
+                        elements: list.iter().cloned().collect(),
+                    },
+                ];
+                if let Some(deadline) = deadline {
+                    commands.push(Command::Expire {
+                        key: key.to_owned(),
+                        expire: Expire::At(*deadline as i64),
+                    });
+                }
+                commands
+            }
+            (None, None) => vec![Command::Del {
+                keys: vec![key.to_owned()],
+            }],
+        })
+        .collect()
+}
 
 #[derive(Default, Debug)]
 pub struct Transaction {
-    read_cache: HashMap<String, Vec<u8>>,
-    write_cache: HashMap<String, Vec<u8>>,
+    id: TxId,
+    options: TransactionOptions,
+    aborted: bool,
+    read_cache: HashMap<String, Entry>,
+    // None は削除された key (tombstone) を表す
+    write_cache: WriteSet,
+    // savepoint 以降の書き込みは write_cache の上に積まれた layer に入る
+    savepoints: Vec<Savepoint>,
+    // 各keyに対して現在保持している lock の mode
+    locked: HashMap<String, LockMode>,
+}
+
+/// Writes made since `savepoint name`, layered on top of the ones before it.
+#[derive(Debug)]
+struct Savepoint {
+    name: String,
+    writes: WriteSet,
+}
+
+/// A transaction bound to the shared locks and database for the duration of one command.
+struct TransactionKeyspace<'a> {
+    transaction: &'a mut Transaction,
+    locks: &'a Locks,
+    shards: &'a Shards,
+    // 実行中の append か setrange。それだけで書かれた key は log にそのまま書く
+    command: Option<Command>,
 }
 
 impl Transaction {
-    pub fn new() -> Transaction {
+    pub fn new(id: TxId, options: TransactionOptions) -> Transaction {
         Transaction {
+            id,
+            options,
+            aborted: false,
             read_cache: HashMap::new(),
             write_cache: HashMap::new(),
+            savepoints: vec![],
+            locked: HashMap::new(),
         }
     }
 
-    // FIXME
-    // 基本的にMemdb::exec_commandと同じなのでTraitとしてまとめられるのではないかと思っている
     pub fn exec_command(
         &mut self,
         command: Command,
-        locks: &Mutex<Locks>,
-        memdb: &RwLock<Memdb>,
+        locks: &Locks,
+        shards: &Shards,
     ) -> Result<String, String> {
         match command {
-            Command::Set { key, value } => {
-                // FIXME 共通処理
-                if self.get(&key).is_none() {
-                    locks.lock().unwrap().write_lock(&key);
-                }
+            Command::Abort => {
+                self.clear_lock(locks);
 
-                self.write_set(key, value);
-                Ok(String::from("OK"))
+                Ok(String::from("Abort transaction"))
             }
-
-            Command::Get { key } => match self.get(&key) {
-                None => match memdb.read().unwrap().get(&key) {
-                    None => Ok("None".to_owned()),
-                    Some(v) => {
-                        locks.lock().unwrap().read_lock(&key);
-
-                        self.read_set(key, v.clone());
-                        Ok(String::from_utf8(v).unwrap())
-                    }
-                },
-                Some(v) => {
-                    self.read_set(key, v.clone());
-                    Ok(String::from_utf8(v).unwrap())
-                }
-            },
-            Command::Del { keys } => {
-                keys.iter().for_each(|key| {
-                    // FIXME 共通処理
-                    if self.get(&key).is_none() {
-                        locks.lock().unwrap().read_lock(&key);
-                    }
+            Command::Savepoint { name } => {
+                self.savepoints.push(Savepoint {
+                    name,
+                    writes: HashMap::new(),
                 });
 
-                let result = self.del(keys);
-                Ok(format!("{}", result))
+                Ok(String::from("OK"))
             }
-            Command::Exec => {
-                self.read_cache.keys().for_each(|k| {
-                    locks.lock().unwrap().read_unlock(k);
-                });
+            Command::RollbackTo { name } => {
+                // savepoint 自体は残し、それ以降の書き込みだけを捨てる
+                let i = self.savepoint_position(&name)?;
+                self.savepoints.truncate(i + 1);
+                self.savepoints[i].writes.clear();
 
-                let mut db = memdb.write().unwrap();
-
-                self.write_cache.iter().for_each(|(k, v)| {
-                    db.set(k, v);
-                    locks.lock().unwrap().write_unlock(k);
-                });
+                Ok(String::from("OK"))
+            }
+            Command::Release { name } => {
+                let i = self.savepoint_position(&name)?;
+                let released = self.savepoints.split_off(i);
 
-                self.read_cache = HashMap::new();
-                self.write_cache = HashMap::new();
+                let below = match self.savepoints.last_mut() {
+                    Some(savepoint) => &mut savepoint.writes,
+                    None => &mut self.write_cache,
+                };
+                released
+                    .into_iter()
+                    .for_each(|savepoint| merge_writes(below, savepoint.writes));
 
                 Ok(String::from("OK"))
             }
-            Command::Abort => {
-                self.clear_lock(locks);
-
-                Ok(String::from("Abort transaction"))
+            command => TransactionKeyspace {
+                transaction: self,
+                locks,
+                shards,
+                command: compact_command(&command),
             }
-            _ => Err(String::from("ERR unsupport transaction command")),
+            .exec_command(command),
         }
     }
 
-    fn read_set(&mut self, key: impl AsRef<str>, value: impl AsRef<[u8]>) {
-        self.read_cache
-            .insert(key.as_ref().to_owned(), value.as_ref().to_owned());
+    /// The writes `commit` will apply, as the commands to record in the log.
+    pub fn pending_writes(&self) -> Vec<Command> {
+        write_commands(&self.merged_writes())
     }
 
-    fn write_set(&mut self, key: impl AsRef<str>, value: impl AsRef<[u8]>) {
-        self.write_cache
-            .insert(key.as_ref().to_owned(), value.as_ref().to_owned());
+    pub fn write_keys(&self) -> Vec<String> {
+        self.merged_writes().into_keys().collect()
     }
 
-    fn get(&mut self, key: impl AsRef<str>) -> Option<Vec<u8>> {
-        if let Some(v) = self.read_cache.get(&key.as_ref().to_owned()) {
-            return Some(v.to_vec());
+    /// Applies the buffered writes to `db`, which must hold the shards of `write_keys`.
+    /// The key locks stay held until `clear_lock`.
+    pub(crate) fn commit(&self, db: &mut impl Keyspace) -> Result<(), String> {
+        for (key, write) in self.merged_writes() {
+            match write.entry {
+                Some(entry) => db.write_entry(&key, entry)?,
+                None => {
+                    db.remove(&key)?;
+                }
+            }
         }
 
-        if let Some(v) = self.write_cache.get(&key.as_ref().to_owned()) {
-            return Some(v.to_vec());
-        }
+        Ok(())
+    }
+
+    fn merged_writes(&self) -> WriteSet {
+        let mut writes = self.write_cache.clone();
+        self.savepoints
+            .iter()
+            .for_each(|savepoint| merge_writes(&mut writes, savepoint.writes.clone()));
+
+        writes
+    }
+
+    // 同じ名前の savepoint がある場合は新しい方を使う
+    fn savepoint_position(&self, name: &str) -> Result<usize, String> {
+        self.savepoints
+            .iter()
+            .rposition(|savepoint| savepoint.name == name)
+            .ok_or_else(|| format!("ERR no such savepoint '{}'", name))
+    }
+
+    fn buffered(&self, key: &str) -> Option<&Option<Entry>> {
+        self.savepoints
+            .iter()
+            .rev()
+            .find_map(|savepoint| savepoint.writes.get(key))
+            .or_else(|| self.write_cache.get(key))
+            .map(|write| &write.entry)
+    }
+
+    fn buffer(&mut self, key: &str, entry: Option<Entry>, command: Option<&Command>) {
+        let writes = match self.savepoints.last_mut() {
+            Some(savepoint) => &mut savepoint.writes,
+            None => &mut self.write_cache,
+        };
+
+        buffer_write(writes, key, entry, command);
+    }
 
-        None
+    pub fn id(&self) -> TxId {
+        self.id
     }
 
-    fn del(&mut self, keys: Vec<impl AsRef<str>>) -> usize {
-        keys.into_iter()
-            .map(|key| self.write_cache.remove(&key.as_ref().to_owned()))
-            .filter(|v| v.is_some())
-            .count()
+    /// True once a lock request failed and the transaction released everything it held.
+    pub fn is_aborted(&self) -> bool {
+        self.aborted
     }
 
-    pub fn clear_lock(&mut self, locks: &Mutex<Locks>) {
-        self.read_cache.keys().for_each(|k| {
-            locks.lock().unwrap().read_unlock(k);
-        });
+    /// Takes the lock `mode` needs on `key` unless a strong enough lock is already held,
+    /// upgrading a read lock when the transaction is about to write a key it has read.
+    /// When the lock can't be taken, every lock is released and the transaction is aborted.
+    fn lock(&mut self, locks: &Locks, key: &str, mode: LockMode) -> Result<(), String> {
+        match (self.locked.get(key), mode) {
+            (Some(LockMode::Write), _) | (Some(LockMode::Read), LockMode::Read) => return Ok(()),
+            _ => (),
+        }
+
+        let result = match mode {
+            LockMode::Read => locks.read_lock(self.id, key, self.options.lock_wait),
+            LockMode::Write => locks.write_lock(self.id, key, self.options.lock_wait),
+        };
 
-        self.write_cache.keys().for_each(|k| {
-            locks.lock().unwrap().write_unlock(k);
-        });
+        if result.is_err() {
+            self.clear_lock(locks);
+            self.aborted = true;
+            return result;
+        }
+
+        self.locked.insert(key.to_owned(), mode);
+        Ok(())
+    }
+
+    pub fn clear_lock(&mut self, locks: &Locks) {
+        // Locks::abort で既に開放されている場合もあるので、keyごとではなくまとめて開放する
+        if !self.locked.is_empty() {
+            locks.release_all(self.id);
+        }
 
         self.read_cache = HashMap::new();
         self.write_cache = HashMap::new();
+        self.savepoints = vec![];
+        self.locked = HashMap::new();
+    }
+}
+
+impl Keyspace for TransactionKeyspace<'_> {
+    // transaction の途中で期限が切れた key も見えなくなる
+    fn read_entry(&mut self, key: &str) -> Result<Option<Entry>, String> {
+        let tx = &mut *self.transaction;
+        tx.lock(self.locks, key, LockMode::Read)?;
+
+        let entry = match tx.buffered(key) {
+            Some(entry) => entry.clone(),
+            None => match tx.read_cache.get(key) {
+                Some(entry) => Some(entry.clone()),
+                None => {
+                    let entry = self.shards.get_entry(key);
+                    if let Some(entry) = &entry {
+                        tx.read_cache.insert(key.to_owned(), entry.clone());
+                    }
+                    entry
+                }
+            },
+        };
+
+        Ok(entry.filter(|entry| !entry.is_expired(expire::now())))
+    }
+
+    fn read_for_update(&mut self, key: &str) -> Result<Option<Entry>, String> {
+        self.transaction.lock(self.locks, key, LockMode::Write)?;
+        self.read_entry(key)
+    }
+
+    fn write_entry(&mut self, key: &str, entry: Entry) -> Result<(), String> {
+        let tx = &mut *self.transaction;
+        tx.lock(self.locks, key, LockMode::Write)?;
+
+        tx.buffer(key, Some(entry), self.command.as_ref());
+        Ok(())
+    }
+
+    // 他の transaction が後から作る key は含まれない
+    fn stored_keys(&mut self) -> Result<Vec<String>, String> {
+        let mut keys = self.shards.stored_keys();
+        keys.extend(self.transaction.write_keys());
+        keys.sort_unstable();
+        keys.dedup();
+
+        Ok(keys)
+    }
+
+    fn scan_partitions(&self) -> usize {
+        self.shards.count()
+    }
+
+    fn partition_range(
+        &mut self,
+        index: usize,
+        from: u64,
+        count: usize,
+    ) -> Result<Vec<(u64, String)>, String> {
+        let written = self.transaction.write_keys();
+        Ok(self.shards.scan_range(index, from, count, written))
+    }
+
+    fn remove(&mut self, key: &str) -> Result<bool, String> {
+        let existed = self.read_for_update(key)?.is_some();
+
+        self.transaction.buffer(key, None, self.command.as_ref());
+        Ok(existed)
     }
 }
@@ -1,4 +1,8 @@
-use tyozo::Memdb;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use tyozo::utils::fs_utils::open_or_create_file;
+use tyozo::{Backup, Executor, Locks, Memdb, TransactionLimits};
 
 #[test]
 fn test_tyozo() {
@@ -20,3 +24,1747 @@ fn test_tyozo() {
     let result = db.exec("setnx hoge value");
     assert!(result.is_err());
 }
+
+#[test]
+fn test_serialize_roundtrip() {
+    let mut db = Memdb::new();
+    db.set("key", "a longer value");
+    db.set("another key", "v");
+
+    let deserialized = Memdb::deserialize(&db.serialize()).unwrap();
+    assert_eq!(deserialized, db);
+}
+
+#[test]
+fn test_backup() {
+    let dir = temp_dir("backup");
+    let mut executor = executor(&dir);
+
+    executor.exec("set hoge value").unwrap();
+    executor.exec("set fuga \"hello world\"").unwrap();
+
+    let backup = Backup::decode(&executor.exec("backup").unwrap()).unwrap();
+    let log_length = std::fs::metadata(dir.join("tyozo.log")).unwrap().len();
+    assert_eq!(backup.wal_position, log_length);
+
+    // writes after the backup are only in the log
+    executor.exec("set after backup").unwrap();
+    executor.exec("del hoge").unwrap();
+
+    let backup_file = dir.join("backup");
+    std::fs::write(&backup_file, backup.to_bytes()).unwrap();
+
+    let mut seeded = Memdb::restore_backup(backup_file.to_str().unwrap(), None).unwrap();
+    assert_eq!(seeded.exec("get hoge"), Ok(String::from("value")));
+    assert_eq!(seeded.exec("get fuga"), Ok(String::from("hello world")));
+    assert_eq!(seeded.exec("get after"), Ok(String::from("None")));
+
+    let log_file = dir.join("tyozo.log");
+    let mut caught_up = Memdb::restore_backup(
+        backup_file.to_str().unwrap(),
+        Some(log_file.to_str().unwrap()),
+    )
+    .unwrap();
+    assert_eq!(caught_up.exec("get hoge"), Ok(String::from("None")));
+    assert_eq!(caught_up.exec("get fuga"), Ok(String::from("hello world")));
+    assert_eq!(caught_up.exec("get after"), Ok(String::from("backup")));
+}
+
+#[test]
+fn test_seed_persists_backup() {
+    let source = temp_dir("seed-source");
+    let mut executor = executor(&source);
+
+    executor.exec("set a 1").unwrap();
+    let backup = Backup::decode(&executor.exec("backup").unwrap()).unwrap();
+    executor.exec("set b 2").unwrap();
+
+    let backup_file = source.join("backup");
+    std::fs::write(&backup_file, backup.to_bytes()).unwrap();
+    let source_log = source.join("tyozo.log");
+
+    // replica は古い db file と log を持っている
+    let replica = temp_dir("seed-replica");
+    let db_path = replica.join("tyozo.db");
+    let log_path = replica.join("tyozo.log");
+    std::fs::write(&log_path, "set stale 1\nset a stale\n").unwrap();
+
+    Memdb::seed(
+        backup_file.to_str().unwrap(),
+        Some(source_log.to_str().unwrap()),
+        db_path.to_str().unwrap(),
+        log_path.to_str().unwrap(),
+    )
+    .unwrap();
+
+    let mut restarted =
+        Memdb::restore(db_path.to_str().unwrap(), log_path.to_str().unwrap()).unwrap();
+    assert_eq!(
+        restarted.exec("mget a b stale"),
+        Ok(String::from(r#"["1", "2", "None"]"#))
+    );
+
+    // backup の後に切り詰められた log では追いつけない
+    executor.exec("shutdown").unwrap();
+    let result = Memdb::restore_backup(
+        backup_file.to_str().unwrap(),
+        Some(source_log.to_str().unwrap()),
+    );
+    assert!(result.unwrap_err().to_string().contains("truncated"));
+
+    executor.exec("set long value").unwrap();
+    let result = Memdb::restore_backup(
+        backup_file.to_str().unwrap(),
+        Some(source_log.to_str().unwrap()),
+    );
+    assert!(result.unwrap_err().to_string().contains("truncated"));
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("tyozo-test-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn executor(dir: &Path) -> Executor {
+    let log_file = open_or_create_file(dir.join("tyozo.log").to_str().unwrap()).unwrap();
+    let db_file = open_or_create_file(dir.join("tyozo.db").to_str().unwrap()).unwrap();
+
+    Executor::new(log_file, db_file, Memdb::new(), Locks::new())
+}
+
+#[test]
+fn test_transaction_deadlock() {
+    let dir = temp_dir("deadlock");
+    let mut first = executor(&dir);
+    let mut second = first.clone();
+    let mut observer = first.clone();
+
+    first.exec("multi").unwrap();
+    first.exec("set a x").unwrap();
+
+    second.exec("multi").unwrap();
+    second.exec("set b y").unwrap();
+
+    let first = std::thread::spawn(move || {
+        let result = first.exec("set b y").map_err(|e| e.to_string());
+        first.exec("exec").unwrap();
+        (first, result)
+    });
+
+    // wait until the first transaction is parked on "b", as reported by the lock table
+    while !observer
+        .exec("locks waiters")
+        .unwrap()
+        .contains("for b blocked by")
+    {
+        std::thread::yield_now();
+    }
+
+    let result = second.exec("set a x");
+    assert!(result.unwrap_err().to_string().starts_with("DEADLOCK"));
+
+    let (mut first, result) = first.join().unwrap();
+    assert_eq!(result, Ok(String::from("OK")));
+
+    // the aborted transaction is gone, so the second client is back in normal mode
+    assert_eq!(second.exec("get b").unwrap(), "y");
+    assert_eq!(first.exec("get a").unwrap(), "x");
+}
+
+#[test]
+fn test_transaction_lock_timeout() {
+    let dir = temp_dir("lock-timeout");
+    let mut first = executor(&dir);
+    let mut second = first.clone();
+
+    first.exec("set key v").unwrap();
+    first.exec("multi").unwrap();
+    first.exec("set key x").unwrap();
+
+    second.exec("multi nowait").unwrap();
+    second.exec("set other y").unwrap();
+    let result = second.exec("set key y");
+    assert!(result.unwrap_err().to_string().starts_with("LOCKTIMEOUT"));
+
+    // the failed transaction released its locks and left transaction mode
+    first.exec("set other x").unwrap();
+    assert_eq!(second.exec("get other").unwrap(), "None");
+
+    second.exec("multi timeout 50").unwrap();
+    let result = second.exec("get key");
+    assert!(result.unwrap_err().to_string().starts_with("LOCKTIMEOUT"));
+
+    first.exec("exec").unwrap();
+    assert_eq!(second.exec("get key").unwrap(), "x");
+}
+
+#[test]
+fn test_transaction_lock_upgrade() {
+    let dir = temp_dir("lock-upgrade");
+    let mut executor = executor(&dir);
+    let mut other = executor.clone();
+
+    executor.exec("set key v").unwrap();
+
+    // read then write upgrades the read lock
+    executor.exec("multi").unwrap();
+    assert_eq!(executor.exec("get key").unwrap(), "v");
+    assert_eq!(executor.exec("set key x").unwrap(), "OK");
+    assert_eq!(executor.exec("get key").unwrap(), "x");
+
+    other.exec("multi nowait").unwrap();
+    assert!(other.exec("get key").is_err());
+
+    assert_eq!(executor.exec("exec").unwrap(), "OK");
+    assert_eq!(executor.exec("get key").unwrap(), "x");
+
+    // write then read keeps the write lock
+    executor.exec("multi").unwrap();
+    executor.exec("set key y").unwrap();
+    assert_eq!(executor.exec("get key").unwrap(), "y");
+
+    other.exec("multi nowait").unwrap();
+    assert!(other.exec("get key").is_err());
+
+    executor.exec("exec").unwrap();
+
+    // del then set
+    executor.exec("multi").unwrap();
+    executor.exec("del key").unwrap();
+    executor.exec("set key z").unwrap();
+    executor.exec("exec").unwrap();
+    assert_eq!(executor.exec("get key").unwrap(), "z");
+
+    // every lock was released, so another transaction can write the key
+    other.exec("multi nowait").unwrap();
+    assert_eq!(other.exec("set key w").unwrap(), "OK");
+    assert_eq!(other.exec("exec").unwrap(), "OK");
+    assert_eq!(executor.exec("get key").unwrap(), "w");
+}
+
+#[test]
+fn test_transaction_del_and_setnx() {
+    let dir = temp_dir("transaction-del");
+    let mut executor = executor(&dir);
+
+    executor.exec("set hoge value").unwrap();
+    executor.exec("set fuga value").unwrap();
+
+    executor.exec("multi").unwrap();
+    assert_eq!(executor.exec("del hoge fuga piyo").unwrap(), "2");
+    assert_eq!(executor.exec("get hoge").unwrap(), "None");
+    assert_eq!(executor.exec("del hoge").unwrap(), "0");
+
+    assert_eq!(executor.exec("setnx hoge new").unwrap(), "OK");
+    assert!(executor.exec("setnx hoge again").is_err());
+    assert!(executor.exec("setnx fuga value").is_ok());
+    assert_eq!(executor.exec("del fuga").unwrap(), "1");
+    assert_eq!(executor.exec("exec").unwrap(), "OK");
+
+    assert_eq!(executor.exec("get hoge").unwrap(), "new");
+    assert_eq!(executor.exec("get fuga").unwrap(), "None");
+
+    // committed writes are in the log, so recovery sees the deletion too
+    let db = Memdb::restore(
+        dir.join("tyozo.db").to_str().unwrap(),
+        dir.join("tyozo.log").to_str().unwrap(),
+    )
+    .unwrap();
+    assert_eq!(db.get("hoge"), Some(b"new".to_vec()));
+    assert_eq!(db.get("fuga"), None);
+}
+
+#[test]
+fn test_queued_transaction() {
+    let dir = temp_dir("queued-transaction");
+    let mut executor = executor(&dir);
+    let mut other = executor.clone();
+
+    executor.exec("set hoge value").unwrap();
+
+    executor.exec("multi queued").unwrap();
+    assert_eq!(executor.exec("set fuga value").unwrap(), "QUEUED");
+    assert_eq!(executor.exec("get hoge").unwrap(), "QUEUED");
+    assert_eq!(executor.exec("setnx hoge value").unwrap(), "QUEUED");
+    assert_eq!(executor.exec("del hoge").unwrap(), "QUEUED");
+
+    // nothing runs before exec
+    assert_eq!(other.exec("get fuga").unwrap(), "None");
+
+    assert_eq!(
+        executor.exec("exec").unwrap(),
+        r#"["OK", "value", "(error) ERR key is already exists", "1"]"#
+    );
+    assert_eq!(other.exec("get fuga").unwrap(), "value");
+    assert_eq!(other.exec("get hoge").unwrap(), "None");
+}
+
+#[test]
+fn test_queued_transaction_execabort() {
+    let dir = temp_dir("queued-transaction-execabort");
+    let mut executor = executor(&dir);
+
+    executor.exec("multi queued").unwrap();
+    executor.exec("set hoge value").unwrap();
+    assert!(executor.exec("set hoge").is_err());
+    assert!(executor.exec("multi queued").is_err());
+
+    let result = executor.exec("exec");
+    assert!(result.unwrap_err().to_string().starts_with("EXECABORT"));
+    assert_eq!(executor.exec("get hoge").unwrap(), "None");
+}
+
+#[test]
+fn test_watch() {
+    let dir = temp_dir("watch");
+    let mut executor = executor(&dir);
+    let mut other = executor.clone();
+
+    executor.exec("set counter 1").unwrap();
+
+    // unchanged watched key: exec applies the transaction
+    executor.exec("watch counter").unwrap();
+    executor.exec("multi").unwrap();
+    executor.exec("set counter 2").unwrap();
+    assert_eq!(executor.exec("exec").unwrap(), "OK");
+    assert_eq!(executor.exec("get counter").unwrap(), "2");
+
+    // modified by another client: exec replies None and applies nothing
+    executor.exec("watch counter").unwrap();
+    other.exec("set counter 10").unwrap();
+    executor.exec("multi").unwrap();
+    executor.exec("set counter 3").unwrap();
+    assert_eq!(executor.exec("exec").unwrap(), "None");
+    assert_eq!(executor.exec("get counter").unwrap(), "10");
+
+    // the transaction released its locks
+    other.exec("multi nowait").unwrap();
+    other.exec("set counter 11").unwrap();
+    other.exec("exec").unwrap();
+
+    // deletion counts as a modification, also for queued transactions
+    executor.exec("watch counter").unwrap();
+    other.exec("del counter").unwrap();
+    executor.exec("multi queued").unwrap();
+    executor.exec("set counter 4").unwrap();
+    assert_eq!(executor.exec("exec").unwrap(), "None");
+    assert_eq!(executor.exec("get counter").unwrap(), "None");
+
+    // unwatch forgets the watched keys
+    executor.exec("watch counter").unwrap();
+    other.exec("set counter 20").unwrap();
+    executor.exec("unwatch").unwrap();
+    executor.exec("multi").unwrap();
+    executor.exec("set counter 5").unwrap();
+    assert_eq!(executor.exec("exec").unwrap(), "OK");
+    assert_eq!(executor.exec("get counter").unwrap(), "5");
+
+    executor.exec("multi").unwrap();
+    assert!(executor.exec("watch counter").is_err());
+    executor.exec("abort").unwrap();
+}
+
+#[test]
+fn test_snapshot_transaction() {
+    let dir = temp_dir("snapshot-transaction");
+    let mut executor = executor(&dir);
+    let mut other = executor.clone();
+
+    executor.exec("set a 1").unwrap();
+    executor.exec("set b 1").unwrap();
+
+    executor.exec("multi snapshot").unwrap();
+    assert_eq!(executor.exec("get a").unwrap(), "1");
+
+    // writes committed after the snapshot are not visible, and nothing is locked
+    other.exec("set a 2").unwrap();
+    other.exec("set b 2").unwrap();
+    other.exec("set c 2").unwrap();
+    other.exec("multi nowait").unwrap();
+    other.exec("set a 3").unwrap();
+    other.exec("exec").unwrap();
+
+    assert_eq!(executor.exec("get a").unwrap(), "1");
+    assert_eq!(executor.exec("get b").unwrap(), "1");
+    assert_eq!(executor.exec("get c").unwrap(), "None");
+
+    executor.exec("set d 1").unwrap();
+    assert_eq!(executor.exec("get d").unwrap(), "1");
+    assert_eq!(executor.exec("exec").unwrap(), "OK");
+    assert_eq!(other.exec("get d").unwrap(), "1");
+}
+
+#[test]
+fn test_snapshot_transaction_first_committer_wins() {
+    let dir = temp_dir("snapshot-conflict");
+    let mut first = executor(&dir);
+    let mut second = first.clone();
+
+    first.exec("set key 0").unwrap();
+
+    first.exec("multi snapshot").unwrap();
+    second.exec("multi snapshot").unwrap();
+
+    first.exec("set key 1").unwrap();
+    second.exec("del key").unwrap();
+
+    assert_eq!(first.exec("exec").unwrap(), "OK");
+
+    let result = second.exec("exec");
+    assert!(result.unwrap_err().to_string().starts_with("CONFLICT"));
+    assert_eq!(second.exec("get key").unwrap(), "1");
+}
+
+#[test]
+fn test_snapshot_garbage_collection() {
+    let mut memdb = Memdb::new();
+    memdb.set("key", "0");
+
+    let old = memdb.begin_snapshot();
+    memdb.set("key", "1");
+    let new = memdb.begin_snapshot();
+    memdb.set("key", "2");
+    memdb.set("key", "3");
+    // "2" was written after every open snapshot, so none of them can read it
+    assert_eq!(memdb.history_len(), 2);
+
+    assert_eq!(memdb.get_at("key", old), Some(b"0".to_vec()));
+    assert_eq!(memdb.get_at("key", new), Some(b"1".to_vec()));
+
+    // only the value the newer snapshot reads is still needed
+    memdb.end_snapshot(old);
+    assert_eq!(memdb.history_len(), 1);
+    assert_eq!(memdb.get_at("key", new), Some(b"1".to_vec()));
+
+    memdb.end_snapshot(new);
+    assert_eq!(memdb.history_len(), 0);
+}
+
+#[test]
+fn test_snapshot_history_stays_bounded() {
+    let mut memdb = Memdb::new();
+    memdb.set("key", "old");
+
+    let long = memdb.begin_snapshot();
+    for i in 0..10_000 {
+        memdb.set("key", i.to_string());
+    }
+    assert_eq!(memdb.history_len(), 1);
+    assert_eq!(memdb.get_at("key", long), Some(b"old".to_vec()));
+
+    // a value between two snapshots is dropped once the one reading it ends,
+    // even though the older snapshot is still open
+    let middle = memdb.begin_snapshot();
+    memdb.set("key", "middle");
+    let last = memdb.begin_snapshot();
+    memdb.set("key", "last");
+    assert_eq!(memdb.history_len(), 3);
+
+    memdb.end_snapshot(middle);
+    assert_eq!(memdb.history_len(), 2);
+    assert_eq!(memdb.get_at("key", long), Some(b"old".to_vec()));
+    assert_eq!(memdb.get_at("key", last), Some(b"middle".to_vec()));
+}
+
+#[test]
+fn test_transaction_savepoint() {
+    let dir = temp_dir("savepoint");
+    let mut executor = executor(&dir);
+
+    assert!(executor.exec("savepoint sp").is_err());
+
+    executor.exec("multi").unwrap();
+    executor.exec("set a 1").unwrap();
+    executor.exec("savepoint sp1").unwrap();
+    executor.exec("set a 2").unwrap();
+    executor.exec("set b 2").unwrap();
+    executor.exec("savepoint sp2").unwrap();
+    executor.exec("del a").unwrap();
+    assert_eq!(executor.exec("get a").unwrap(), "None");
+
+    // rollback keeps the savepoint and discards only the writes after it
+    executor.exec("rollback to sp2").unwrap();
+    assert_eq!(executor.exec("get a").unwrap(), "2");
+    executor.exec("set c 3").unwrap();
+    executor.exec("rollback to sp1").unwrap();
+    assert_eq!(executor.exec("get a").unwrap(), "1");
+    assert_eq!(executor.exec("get b").unwrap(), "None");
+    assert_eq!(executor.exec("get c").unwrap(), "None");
+    assert!(executor.exec("rollback to sp2").is_err());
+
+    // release keeps the writes made after the savepoint
+    executor.exec("set b 4").unwrap();
+    executor.exec("release savepoint sp1").unwrap();
+    assert!(executor.exec("rollback to sp1").is_err());
+    assert_eq!(executor.exec("exec").unwrap(), "OK");
+
+    assert_eq!(executor.exec("get a").unwrap(), "1");
+    assert_eq!(executor.exec("get b").unwrap(), "4");
+    assert_eq!(executor.exec("get c").unwrap(), "None");
+}
+
+#[test]
+fn test_readonly_transaction() {
+    let dir = temp_dir("readonly-transaction");
+    let mut executor = executor(&dir);
+    let mut other = executor.clone();
+    let log_len = || std::fs::metadata(dir.join("tyozo.log")).unwrap().len();
+
+    executor.exec("set a 1").unwrap();
+
+    executor.exec("multi readonly").unwrap();
+    assert_eq!(executor.exec("get a").unwrap(), "1");
+    assert!(executor.exec("set a 2").is_err());
+    assert!(executor.exec("del a").is_err());
+
+    // no key is locked, and reads keep seeing the snapshot
+    other.exec("multi nowait").unwrap();
+    other.exec("set a 3").unwrap();
+    assert_eq!(other.exec("exec").unwrap(), "OK");
+    assert_eq!(executor.exec("get a").unwrap(), "1");
+
+    // nothing is written to the log
+    let before = log_len();
+    assert_eq!(executor.exec("exec").unwrap(), "OK");
+    assert_eq!(log_len(), before);
+
+    assert_eq!(executor.exec("get a").unwrap(), "3");
+}
+
+#[test]
+fn test_reads_skip_log() {
+    let dir = temp_dir("reads-skip-log");
+    let mut executor = executor(&dir);
+    let log = || std::fs::read_to_string(dir.join("tyozo.log")).unwrap();
+
+    executor.exec("set a 1").unwrap();
+    assert_eq!(executor.exec("get a").unwrap(), "1");
+    assert_eq!(executor.exec("get b").unwrap(), "None");
+    executor.exec("del a").unwrap();
+
+    assert_eq!(log(), "set a 1\ndel a\n");
+}
+
+#[test]
+fn test_stray_transaction_commands_skip_log() {
+    let dir = temp_dir("stray-transaction-commands");
+    let mut executor = executor(&dir);
+    let mut other = executor.clone();
+    let log = || std::fs::read_to_string(dir.join("tyozo.log")).unwrap();
+
+    executor.exec("set a 1").unwrap();
+
+    let err = executor.exec("exec").unwrap_err();
+    assert_eq!(err.to_string(), "ERR EXEC without MULTI");
+    let err = executor.exec("abort").unwrap_err();
+    assert_eq!(err.to_string(), "ERR ABORT without MULTI");
+    assert!(executor.exec("savepoint s").is_err());
+
+    // a transaction aborted by a lock error is back in normal mode when the client sends exec
+    other.exec("multi").unwrap();
+    other.exec("set a 2").unwrap();
+    executor.exec("multi nowait").unwrap();
+    assert!(executor.exec("get a").is_err());
+    assert!(executor.exec("exec").is_err());
+    other.exec("abort").unwrap();
+
+    executor.exec("multi queued").unwrap();
+    executor.exec("set a 3").unwrap();
+    assert!(executor.exec("savepoint s").is_err());
+    assert!(executor
+        .exec("exec")
+        .unwrap_err()
+        .to_string()
+        .starts_with("EXECABORT"));
+
+    assert_eq!(log(), "set a 1\n");
+    assert_eq!(executor.exec("get a").unwrap(), "1");
+}
+
+#[test]
+fn test_sharded_concurrent_writes() {
+    let dir = temp_dir("sharded-concurrent-writes");
+    let executor = executor(&dir);
+
+    let handles = (0..8)
+        .map(|t| {
+            let mut executor = executor.clone();
+            std::thread::spawn(move || {
+                for i in 0..50 {
+                    executor.exec(format!("set key{}-{} {}", t, i, i)).unwrap();
+                }
+
+                // a multi-key del and a transaction spanning several shards
+                executor
+                    .exec(format!("del key{}-0 key{}-1 key{}-2", t, t, t))
+                    .unwrap();
+                executor.exec("multi").unwrap();
+                executor.exec(format!("set key{}-0 a", t)).unwrap();
+                executor.exec(format!("set key{}-49 b", t)).unwrap();
+                executor.exec("exec").unwrap();
+            })
+        })
+        .collect::<Vec<_>>();
+    handles.into_iter().for_each(|h| h.join().unwrap());
+
+    let mut executor = executor;
+    for t in 0..8 {
+        assert_eq!(executor.exec(format!("get key{}-0", t)).unwrap(), "a");
+        assert_eq!(executor.exec(format!("get key{}-1", t)).unwrap(), "None");
+        assert_eq!(executor.exec(format!("get key{}-10", t)).unwrap(), "10");
+        assert_eq!(executor.exec(format!("get key{}-49", t)).unwrap(), "b");
+    }
+
+    // the log replays to the same state
+    let backup = Backup::decode(&executor.exec("backup").unwrap()).unwrap();
+    let replayed = Memdb::restore(
+        dir.join("empty.db").to_str().unwrap(),
+        dir.join("tyozo.log").to_str().unwrap(),
+    )
+    .unwrap();
+    assert_eq!(replayed, backup.memdb().unwrap());
+}
+
+#[test]
+fn test_transaction_size_limits() {
+    let dir = temp_dir("transaction-size-limits");
+    let mut executor = executor(&dir).with_limits(TransactionLimits {
+        max_commands: Some(2),
+        max_bytes: Some(20),
+        ..TransactionLimits::default()
+    });
+    let mut other = executor.clone();
+
+    executor.exec("multi").unwrap();
+    executor.exec("set a 1").unwrap();
+    executor.exec("get a").unwrap();
+    let err = executor.exec("set b 2").unwrap_err();
+    assert!(err.to_string().starts_with("TXNTOOLARGE"));
+
+    // the transaction was aborted and its locks released
+    assert_eq!(executor.exec("get a").unwrap(), "None");
+    other.exec("multi nowait").unwrap();
+    other.exec("set a 2").unwrap();
+    assert_eq!(other.exec("exec").unwrap(), "OK");
+
+    executor.exec("multi queued").unwrap();
+    executor.exec("set key a-long-value").unwrap();
+    assert!(executor.exec("set b 2").is_err());
+    assert!(executor.exec("exec").is_err());
+    assert_eq!(executor.exec("get key").unwrap(), "None");
+}
+
+#[test]
+fn test_reap_long_running_transaction() {
+    let dir = temp_dir("reap-transaction");
+    let mut executor = executor(&dir).with_limits(TransactionLimits {
+        max_duration: Some(Duration::from_millis(50)),
+        ..TransactionLimits::default()
+    });
+    let mut other = executor.clone();
+
+    executor.exec("multi").unwrap();
+    executor.exec("set a 1").unwrap();
+
+    executor.reap_transactions();
+    other.exec("multi nowait").unwrap();
+    other.exec("set a 2").unwrap_err();
+
+    // once the transaction ran too long, its locks are taken away while it is idle
+    std::thread::sleep(Duration::from_millis(60));
+    other.reap_transactions();
+    other.exec("multi nowait").unwrap();
+    other.exec("set a 2").unwrap();
+
+    let err = executor.exec("get a").unwrap_err();
+    assert!(err.to_string().starts_with("TXNTIMEOUT"));
+    assert_eq!(executor.exec("get a").unwrap(), "None");
+
+    assert_eq!(other.exec("exec").unwrap(), "OK");
+    assert_eq!(executor.exec("get a").unwrap(), "2");
+
+    // exec after the deadline fails even when nothing reaped the transaction
+    executor.exec("multi").unwrap();
+    executor.exec("set a 3").unwrap();
+    std::thread::sleep(Duration::from_millis(60));
+    assert!(executor.exec("exec").is_err());
+    assert_eq!(executor.exec("get a").unwrap(), "2");
+}
+
+#[test]
+fn test_reap_idle_snapshot_transaction() {
+    let dir = temp_dir("reap-snapshot");
+    let mut executor = executor(&dir).with_limits(TransactionLimits {
+        max_duration: Some(Duration::from_millis(50)),
+        ..TransactionLimits::default()
+    });
+    let mut reader = executor.clone();
+    let mut other = executor.clone();
+
+    executor.exec("set a 1").unwrap();
+    executor.exec("multi snapshot").unwrap();
+    executor.exec("get a").unwrap();
+    std::thread::sleep(Duration::from_millis(60));
+
+    // a second snapshot of the same state, which must keep its old values
+    reader.exec("multi readonly").unwrap();
+    other.reap_transactions();
+    assert!(other.exec("info").unwrap().contains("open_transactions:1"));
+
+    other.exec("set a 2").unwrap();
+    let err = executor.exec("get a").unwrap_err();
+    assert!(err.to_string().starts_with("TXNTIMEOUT"));
+    assert_eq!(executor.exec("get a").unwrap(), "2");
+    assert_eq!(reader.exec("get a").unwrap(), "1");
+    assert_eq!(reader.exec("exec").unwrap(), "OK");
+
+    // an idle read-only transaction is reaped as well
+    reader.exec("multi readonly").unwrap();
+    std::thread::sleep(Duration::from_millis(60));
+    other.reap_transactions();
+    assert!(other.exec("info").unwrap().contains("open_transactions:0"));
+    let err = reader.exec("exec").unwrap_err();
+    assert!(err.to_string().starts_with("TXNTIMEOUT"));
+    assert_eq!(reader.exec("get a").unwrap(), "2");
+}
+
+#[test]
+fn test_lock_introspection() {
+    let dir = temp_dir("lock-introspection");
+    let mut executor = executor(&dir);
+    let mut waiter = executor.clone();
+    let mut other = executor.clone();
+
+    executor.exec("multi").unwrap();
+    executor.exec("set apple 1").unwrap();
+    executor.exec("get banana").unwrap();
+
+    let blocked = std::thread::spawn(move || {
+        waiter.exec("multi").unwrap();
+        waiter.exec("get apple").unwrap()
+    });
+    while other.exec("locks waiters").unwrap() == "[]" {
+        std::thread::yield_now();
+    }
+
+    let list = other.exec("locks list").unwrap();
+    assert!(list.contains("apple write by [tx "));
+    assert!(list.contains("banana read(1) by [tx "));
+    assert!(list.contains("(conn 2) "));
+
+    let list = other.exec("locks list b*").unwrap();
+    assert!(list.contains("banana"));
+    assert!(!list.contains("apple"));
+
+    let waiters = other.exec("locks waiters").unwrap();
+    assert!(waiters.contains("(conn 2) waits "));
+    assert!(waiters.contains("ms for apple blocked by [tx "));
+
+    other.exec("multi nowait").unwrap();
+    assert!(other.exec("set apple 2").is_err());
+
+    let info = other.exec("info").unwrap();
+    assert!(info.contains("locked_keys:2"));
+    assert!(info.contains("blocked_transactions:1"));
+    assert!(info.contains("lock_waits:1"));
+    assert!(info.contains("lock_timeouts:1"));
+    assert!(info.contains("deadlocks:0"));
+
+    executor.exec("exec").unwrap();
+    assert_eq!(blocked.join().unwrap(), "1");
+    assert_eq!(other.exec("locks list banana").unwrap(), "[]");
+}
+
+#[test]
+fn test_key_expiration() {
+    let mut db = Memdb::new();
+
+    assert_eq!(db.exec("ttl session"), Ok(String::from("-2")));
+    db.exec("set session value ex 100").unwrap();
+    assert_eq!(db.exec("ttl session"), Ok(String::from("100")));
+    let pttl = db.exec("pttl session").unwrap().parse::<i64>().unwrap();
+    assert!(pttl > 99_000 && pttl <= 100_000);
+
+    assert_eq!(db.exec("persist session"), Ok(String::from("1")));
+    assert_eq!(db.exec("persist session"), Ok(String::from("0")));
+    assert_eq!(db.exec("ttl session"), Ok(String::from("-1")));
+
+    assert_eq!(db.exec("expire missing 10"), Ok(String::from("0")));
+    assert_eq!(db.exec("expire session 10"), Ok(String::from("1")));
+    assert_eq!(db.exec("ttl session"), Ok(String::from("10")));
+    // set replaces the value together with its expiration
+    db.exec("set session value").unwrap();
+    assert_eq!(db.exec("ttl session"), Ok(String::from("-1")));
+
+    // a deadline in the past deletes the key right away
+    assert_eq!(db.exec("expireat session 1"), Ok(String::from("1")));
+    assert_eq!(db.exec("get session"), Ok(String::from("None")));
+
+    db.exec("set session value px 20").unwrap();
+    db.exec("set other value px 20").unwrap();
+    std::thread::sleep(Duration::from_millis(30));
+    assert_eq!(db.exec("get session"), Ok(String::from("None")));
+    assert_eq!(db.exec("ttl session"), Ok(String::from("-2")));
+    assert_eq!(db.exec("del other"), Ok(String::from("0")));
+    assert_eq!(db.exec("setnx other new"), Ok(String::from("OK")));
+    assert_eq!(db.exec("ttl other"), Ok(String::from("-1")));
+}
+
+#[test]
+fn test_expiration_in_transactions() {
+    let dir = temp_dir("expiration-in-transactions");
+    let mut executor = executor(&dir);
+
+    executor.exec("set a 1").unwrap();
+    executor.exec("multi").unwrap();
+    assert_eq!(executor.exec("expire a 100").unwrap(), "1");
+    assert_eq!(executor.exec("ttl a").unwrap(), "100");
+    executor.exec("set b 2 px 20").unwrap();
+    executor.exec("exec").unwrap();
+    assert_eq!(executor.exec("ttl a").unwrap(), "100");
+
+    executor.exec("multi snapshot").unwrap();
+    assert_eq!(executor.exec("persist a").unwrap(), "1");
+    executor.exec("exec").unwrap();
+    assert_eq!(executor.exec("ttl a").unwrap(), "-1");
+
+    executor.exec("multi queued").unwrap();
+    executor.exec("set c 3 ex 100").unwrap();
+    executor.exec("exec").unwrap();
+    assert_eq!(executor.exec("ttl c").unwrap(), "100");
+
+    std::thread::sleep(Duration::from_millis(30));
+    assert_eq!(executor.exec("get b").unwrap(), "None");
+    assert_eq!(executor.expire_keys(), 1);
+    assert_eq!(executor.expire_keys(), 0);
+}
+
+#[test]
+fn test_expiration_survives_restart() {
+    let dir = temp_dir("expiration-survives-restart");
+    let db_path = dir.join("tyozo.db");
+    let log_path = dir.join("tyozo.log");
+    let mut executor = executor(&dir);
+
+    executor.exec("set persisted value ex 100").unwrap();
+    executor.exec("set expiring value px 20").unwrap();
+    executor.exec("shutdown").unwrap();
+
+    executor.exec("set logged value ex 100").unwrap();
+    executor.exec("set key value").unwrap();
+    executor.exec("pexpire key 20").unwrap();
+
+    // the log holds deadlines, not relative times
+    let log = std::fs::read_to_string(&log_path).unwrap();
+    assert!(log.starts_with("set logged value pxat "));
+    assert!(log.contains("\npexpireat key "));
+
+    std::thread::sleep(Duration::from_millis(30));
+    let mut db = Memdb::restore(db_path.to_str().unwrap(), log_path.to_str().unwrap()).unwrap();
+
+    for key in &["persisted", "logged"] {
+        let pttl = db.exec(format!("pttl {}", key)).unwrap();
+        let pttl = pttl.parse::<i64>().unwrap();
+        assert!(pttl > 90_000 && pttl <= 100_000, "{} {}", key, pttl);
+    }
+    assert_eq!(db.exec("get expiring"), Ok(String::from("None")));
+    assert_eq!(db.exec("get key"), Ok(String::from("None")));
+}
+
+#[test]
+fn test_counters() {
+    let mut db = Memdb::new();
+
+    assert_eq!(db.exec("incr hits"), Ok(String::from("1")));
+    assert_eq!(db.exec("incrby hits 10"), Ok(String::from("11")));
+    assert_eq!(db.exec("decr hits"), Ok(String::from("10")));
+    assert_eq!(db.exec("decrby hits 15"), Ok(String::from("-5")));
+    assert_eq!(db.exec("get hits"), Ok(String::from("-5")));
+
+    db.exec("set name tyozo").unwrap();
+    assert_eq!(
+        db.exec("incr name"),
+        Err(String::from("ERR value is not an integer or out of range"))
+    );
+    db.exec("set big 9223372036854775807").unwrap();
+    assert_eq!(
+        db.exec("incr big"),
+        Err(String::from("ERR increment or decrement would overflow"))
+    );
+    assert_eq!(db.exec("get big"), Ok(String::from("9223372036854775807")));
+
+    assert_eq!(db.exec("incrbyfloat price 10.5"), Ok(String::from("10.5")));
+    assert_eq!(db.exec("incrbyfloat price -0.5"), Ok(String::from("10")));
+    assert_eq!(db.exec("incrby price 1"), Ok(String::from("11")));
+    assert_eq!(
+        db.exec("incrbyfloat name 1"),
+        Err(String::from("ERR value is not a valid float"))
+    );
+
+    // counters keep their expiration
+    db.exec("set session 1 ex 100").unwrap();
+    assert_eq!(db.exec("incr session"), Ok(String::from("2")));
+    assert_eq!(db.exec("ttl session"), Ok(String::from("100")));
+}
+
+#[test]
+fn test_plain_write_waits_for_transaction() {
+    let dir = temp_dir("plain-write-waits");
+    let mut transaction = executor(&dir);
+    let mut observer = transaction.clone();
+    let mut plain = transaction.clone();
+
+    transaction.exec("multi").unwrap();
+    transaction.exec("incr hits").unwrap();
+
+    let plain = std::thread::spawn(move || plain.exec("incr hits").unwrap());
+
+    // the plain incr queues on the key the transaction holds instead of running past it
+    while !observer
+        .exec("locks waiters")
+        .unwrap()
+        .contains("for hits blocked by")
+    {
+        std::thread::yield_now();
+    }
+
+    assert_eq!(transaction.exec("exec").unwrap(), "OK");
+    assert_eq!(plain.join().unwrap(), "2");
+    assert_eq!(transaction.exec("get hits").unwrap(), "2");
+}
+
+#[test]
+fn test_concurrent_counters() {
+    let dir = temp_dir("concurrent-counters");
+    let executor = executor(&dir);
+
+    let handles = (0..8)
+        .map(|t| {
+            let mut executor = executor.clone();
+            std::thread::spawn(move || {
+                for _ in 0..100 {
+                    executor.exec("incr hits").unwrap();
+                }
+
+                executor.exec("multi").unwrap();
+                executor.exec("incrby hits 1000").unwrap();
+                executor
+                    .exec(format!("incrbyfloat score{} 0.5", t))
+                    .unwrap();
+                executor.exec("exec").unwrap();
+            })
+        })
+        .collect::<Vec<_>>();
+    handles.into_iter().for_each(|h| h.join().unwrap());
+
+    let mut executor = executor;
+    assert_eq!(executor.exec("get hits").unwrap(), "8800");
+    assert_eq!(executor.exec("get score3").unwrap(), "0.5");
+
+    let replayed = Memdb::restore(
+        dir.join("empty.db").to_str().unwrap(),
+        dir.join("tyozo.log").to_str().unwrap(),
+    )
+    .unwrap();
+    assert_eq!(replayed.get("hits"), Some(b"8800".to_vec()));
+}
+
+#[test]
+fn test_string_commands() {
+    let mut db = Memdb::new();
+
+    assert_eq!(db.exec("append greeting hello"), Ok(String::from("5")));
+    assert_eq!(
+        db.exec("append greeting \" world\""),
+        Ok(String::from("11"))
+    );
+    assert_eq!(db.exec("strlen greeting"), Ok(String::from("11")));
+    assert_eq!(db.exec("strlen missing"), Ok(String::from("0")));
+
+    assert_eq!(db.exec("getrange greeting 0 4"), Ok(String::from("hello")));
+    assert_eq!(
+        db.exec("getrange greeting -5 -1"),
+        Ok(String::from("world"))
+    );
+    assert_eq!(
+        db.exec("getrange greeting 6 100"),
+        Ok(String::from("world"))
+    );
+    assert_eq!(db.exec("getrange greeting 5 2"), Ok(String::new()));
+    assert_eq!(db.exec("getrange missing 0 -1"), Ok(String::new()));
+
+    assert_eq!(db.exec("setrange greeting 6 tyozo"), Ok(String::from("11")));
+    assert_eq!(db.exec("get greeting"), Ok(String::from("hello tyozo")));
+
+    // setrange past the end pads with zero bytes
+    assert_eq!(db.exec("setrange padded 3 abc"), Ok(String::from("6")));
+    assert_eq!(db.get("padded"), Some(b"\0\0\0abc".to_vec()));
+    assert_eq!(db.exec("setrange empty 3 \"\""), Ok(String::from("0")));
+    assert_eq!(db.exec("get empty"), Ok(String::from("None")));
+
+    // in-place changes keep the expiration
+    db.exec("set session abc ex 100").unwrap();
+    db.exec("append session def").unwrap();
+    assert_eq!(db.exec("ttl session"), Ok(String::from("100")));
+}
+
+#[test]
+fn test_string_commands_are_logged_compactly() {
+    let dir = temp_dir("string-commands-logged");
+    let log_path = dir.join("tyozo.log");
+    let mut executor = executor(&dir);
+
+    executor.exec("set doc 0123456789").unwrap();
+    executor.exec("multi snapshot").unwrap();
+    assert_eq!(executor.exec("get doc").unwrap(), "0123456789");
+
+    let mut writer = executor.clone();
+    writer.exec("append doc abc").unwrap();
+    writer.exec("setrange doc 2 xy").unwrap();
+
+    // the snapshot still reads the value from before the in-place changes
+    assert_eq!(executor.exec("get doc").unwrap(), "0123456789");
+    executor.exec("exec").unwrap();
+    assert_eq!(executor.exec("get doc").unwrap(), "01xy456789abc");
+
+    let log = std::fs::read_to_string(&log_path).unwrap();
+    assert!(
+        log.ends_with("append doc abc\nsetrange doc 2 xy\n"),
+        "{}",
+        log
+    );
+
+    let replayed = Memdb::restore(
+        dir.join("empty.db").to_str().unwrap(),
+        log_path.to_str().unwrap(),
+    )
+    .unwrap();
+    assert_eq!(replayed.get("doc"), Some(b"01xy456789abc".to_vec()));
+
+    // transaction でも append と setrange だけで書かれた key はそのまま記録する
+    executor.exec("multi").unwrap();
+    executor.exec("append doc z").unwrap();
+    executor.exec("savepoint sp").unwrap();
+    executor.exec("setrange doc 0 ab").unwrap();
+    executor.exec("release sp").unwrap();
+    executor.exec("append other x").unwrap();
+    executor.exec("exec").unwrap();
+    // restore で log は空になっているので、この transaction の分だけが残る
+    let committed = std::fs::read_to_string(&log_path).unwrap();
+    assert_eq!(committed.lines().count(), 3, "{}", committed);
+    assert!(
+        committed.contains("append doc z\nsetrange doc 0 ab\n"),
+        "{}",
+        committed
+    );
+    assert!(committed.contains("append other x\n"), "{}", committed);
+
+    // 他の書き込みがあった key は値全体を記録する
+    executor.exec("multi snapshot").unwrap();
+    executor.exec("set doc base").unwrap();
+    executor.exec("append doc x").unwrap();
+    executor.exec("exec").unwrap();
+    let log = std::fs::read_to_string(&log_path).unwrap();
+    assert!(log.ends_with("set doc basex\n"), "{}", log);
+
+    let replayed = Memdb::restore(
+        dir.join("empty.db").to_str().unwrap(),
+        log_path.to_str().unwrap(),
+    )
+    .unwrap();
+    assert_eq!(replayed.get("doc"), Some(b"basex".to_vec()));
+    assert_eq!(replayed.get("other"), Some(b"x".to_vec()));
+}
+
+#[test]
+fn test_transaction_logs_values_that_are_not_utf8() {
+    let dir = temp_dir("transaction-invalid-utf8");
+    let log_path = dir.join("tyozo.log");
+    let mut executor = executor(&dir);
+
+    // "é" の2 byte 目を書き換えると UTF-8 として読めない値になる
+    executor.exec("set k \"é\"").unwrap();
+    assert_eq!(executor.exec("setrange k 1 a").unwrap(), "2");
+
+    executor.exec("multi").unwrap();
+    executor.exec("rename k k2").unwrap();
+    executor.exec("exec").unwrap();
+
+    executor.exec("multi snapshot").unwrap();
+    executor.exec("copy k2 k3").unwrap();
+    executor.exec("exec").unwrap();
+
+    let log = std::fs::read_to_string(&log_path).unwrap();
+    assert!(log.contains("set k2 \"\\xc3a\"\n"), "{}", log);
+
+    let replayed = Memdb::restore(
+        dir.join("empty.db").to_str().unwrap(),
+        log_path.to_str().unwrap(),
+    )
+    .unwrap();
+    assert_eq!(replayed.get("k"), None);
+    assert_eq!(replayed.get("k2"), Some(vec![0xc3, b'a']));
+    assert_eq!(replayed.get("k3"), Some(vec![0xc3, b'a']));
+}
+
+#[test]
+fn test_multi_key_commands() {
+    let dir = temp_dir("multi-key-commands");
+    let mut executor = executor(&dir);
+    let mut other = executor.clone();
+
+    assert_eq!(executor.exec("mset a 1 b \"two words\"").unwrap(), "OK");
+    assert_eq!(
+        executor.exec("mget a b missing").unwrap(),
+        r#"["1", "two words", "None"]"#
+    );
+
+    // msetnx writes nothing when any of the keys exists
+    assert_eq!(executor.exec("msetnx c 3 a 10").unwrap(), "0");
+    assert_eq!(executor.exec("get c").unwrap(), "None");
+    assert_eq!(executor.exec("msetnx c 3 d 4").unwrap(), "1");
+    assert_eq!(executor.exec("mget a c d").unwrap(), r#"["1", "3", "4"]"#);
+
+    // each command is a single record in the log
+    let log = std::fs::read_to_string(dir.join("tyozo.log")).unwrap();
+    assert!(log.starts_with("mset a 1 b \"two words\"\n"), "{}", log);
+    assert!(log.ends_with("msetnx c 3 d 4\n"), "{}", log);
+
+    // inside a transaction every key is locked until exec
+    executor.exec("multi").unwrap();
+    assert_eq!(executor.exec("mset a 5 e 6").unwrap(), "OK");
+    assert_eq!(executor.exec("mget a e").unwrap(), r#"["5", "6"]"#);
+
+    other.exec("multi nowait").unwrap();
+    assert!(other.exec("mget e").is_err());
+    assert_eq!(other.exec("get a").unwrap(), "1");
+
+    executor.exec("exec").unwrap();
+    assert_eq!(other.exec("mget a e").unwrap(), r#"["5", "6"]"#);
+
+    let replayed = Memdb::restore(
+        dir.join("empty.db").to_str().unwrap(),
+        dir.join("tyozo.log").to_str().unwrap(),
+    )
+    .unwrap();
+    assert_eq!(replayed.get("b"), Some(b"two words".to_vec()));
+    assert_eq!(replayed.get("e"), Some(b"6".to_vec()));
+}
+
+#[test]
+fn test_conditional_writes() {
+    let mut db = Memdb::new();
+
+    assert_eq!(db.exec("set key v1 xx"), Ok(String::from("None")));
+    assert_eq!(db.exec("set key v1 nx"), Ok(String::from("OK")));
+    assert_eq!(db.exec("set key v2 nx"), Ok(String::from("None")));
+    assert_eq!(db.exec("set key v2 xx get"), Ok(String::from("v1")));
+    assert_eq!(db.exec("set other v nx get"), Ok(String::from("None")));
+    assert_eq!(db.exec("get other"), Ok(String::from("v")));
+
+    assert_eq!(db.exec("getset key v3"), Ok(String::from("v2")));
+    assert_eq!(db.exec("getdel key"), Ok(String::from("v3")));
+    assert_eq!(db.exec("getdel key"), Ok(String::from("None")));
+
+    db.exec("set version 1 ex 100").unwrap();
+    assert_eq!(db.exec("cas version 2 3"), Ok(String::from("0")));
+    assert_eq!(db.exec("cas version 1 2"), Ok(String::from("1")));
+    assert_eq!(db.exec("get version"), Ok(String::from("2")));
+    assert_eq!(db.exec("ttl version"), Ok(String::from("100")));
+    assert_eq!(db.exec("cas missing 1 2"), Ok(String::from("0")));
+}
+
+#[test]
+fn test_concurrent_cas() {
+    let dir = temp_dir("concurrent-cas");
+    let mut executor = executor(&dir);
+    executor.exec("set counter 0").unwrap();
+
+    let handles = (0..8)
+        .map(|t| {
+            let mut executor = executor.clone();
+            std::thread::spawn(move || {
+                // only one client takes the lock
+                let owner = executor.exec(format!("set owner {} nx", t)).unwrap() == "OK";
+
+                for _ in 0..50 {
+                    loop {
+                        let current = executor.exec("get counter").unwrap();
+                        let next = current.parse::<u64>().unwrap() + 1;
+                        let swapped = executor
+                            .exec(format!("cas counter {} {}", current, next))
+                            .unwrap();
+                        if swapped == "1" {
+                            break;
+                        }
+                    }
+                }
+                owner
+            })
+        })
+        .collect::<Vec<_>>();
+    let owners = handles
+        .into_iter()
+        .map(|h| h.join().unwrap())
+        .filter(|owner| *owner)
+        .count();
+
+    assert_eq!(owners, 1);
+    assert_eq!(executor.exec("get counter").unwrap(), "400");
+}
+
+#[test]
+fn test_key_management() {
+    let mut db = Memdb::new();
+    db.exec("mset a 1 b 2").unwrap();
+    db.exec("set session s px 1").unwrap();
+    std::thread::sleep(Duration::from_millis(5));
+
+    assert_eq!(db.exec("exists a b a missing"), Ok(String::from("3")));
+    assert_eq!(db.exec("type a"), Ok(String::from("string")));
+    assert_eq!(db.exec("type session"), Ok(String::from("none")));
+    assert_eq!(db.exec("dbsize"), Ok(String::from("2")));
+
+    db.exec("expire a 100").unwrap();
+    assert_eq!(db.exec("rename a c"), Ok(String::from("OK")));
+    assert_eq!(db.exec("mget a c"), Ok(String::from(r#"["None", "1"]"#)));
+    assert_eq!(db.exec("ttl c"), Ok(String::from("100")));
+    assert_eq!(db.exec("rename a c"), Err(String::from("ERR no such key")));
+
+    assert_eq!(db.exec("renamenx c b"), Ok(String::from("0")));
+    assert_eq!(db.exec("renamenx c d"), Ok(String::from("1")));
+
+    assert_eq!(db.exec("copy d b"), Ok(String::from("0")));
+    assert_eq!(db.exec("copy d b replace"), Ok(String::from("1")));
+    assert_eq!(db.exec("copy d e"), Ok(String::from("1")));
+    assert_eq!(
+        db.exec("mget b d e"),
+        Ok(String::from(r#"["1", "1", "1"]"#))
+    );
+
+    assert_eq!(db.exec("flushdb"), Ok(String::from("OK")));
+    assert_eq!(db.exec("dbsize"), Ok(String::from("0")));
+}
+
+#[test]
+fn test_key_management_in_transactions() {
+    let dir = temp_dir("key-management-transactions");
+    let mut executor = executor(&dir);
+    let mut other = executor.clone();
+
+    executor.exec("mset a 1 b 2").unwrap();
+
+    // rename holds write locks on both keys until exec
+    executor.exec("multi").unwrap();
+    assert_eq!(executor.exec("rename a c").unwrap(), "OK");
+    assert_eq!(executor.exec("dbsize").unwrap(), "2");
+
+    other.exec("multi nowait").unwrap();
+    assert!(other.exec("get c").is_err());
+    other.exec("multi nowait").unwrap();
+    assert!(other.exec("get a").is_err());
+    assert_eq!(other.exec("exists a c").unwrap(), "1");
+
+    executor.exec("exec").unwrap();
+    assert_eq!(other.exec("mget a b c").unwrap(), r#"["None", "2", "1"]"#);
+
+    executor.exec("multi queued").unwrap();
+    executor.exec("set d 4").unwrap();
+    executor.exec("dbsize").unwrap();
+    assert_eq!(executor.exec("exec").unwrap(), r#"["OK", "3"]"#);
+
+    executor.exec("multi").unwrap();
+    assert_eq!(executor.exec("flushdb").unwrap(), "OK");
+    assert_eq!(executor.exec("dbsize").unwrap(), "0");
+    assert_eq!(other.exec("dbsize").unwrap(), "3");
+    executor.exec("exec").unwrap();
+    assert_eq!(other.exec("dbsize").unwrap(), "0");
+}
+
+#[test]
+fn test_rename_waits_for_transaction_holding_destination() {
+    let dir = temp_dir("rename-waits-for-transaction");
+    let mut transaction = executor(&dir);
+    let mut observer = transaction.clone();
+    let mut plain = transaction.clone();
+
+    transaction.exec("set src moved").unwrap();
+    transaction.exec("multi").unwrap();
+    transaction.exec("set dst held").unwrap();
+
+    let plain = std::thread::spawn(move || {
+        let renamed = plain.exec("rename src dst").unwrap();
+        (plain, renamed)
+    });
+
+    // rename locks both keys before touching the shards, so it queues on "dst"
+    while !observer
+        .exec("locks waiters")
+        .unwrap()
+        .contains("for dst blocked by")
+    {
+        std::thread::yield_now();
+    }
+    assert_eq!(observer.exec("get src").unwrap(), "moved");
+
+    assert_eq!(transaction.exec("exec").unwrap(), "OK");
+    let (mut plain, renamed) = plain.join().unwrap();
+    assert_eq!(renamed, "OK");
+    assert_eq!(plain.exec("mget src dst").unwrap(), r#"["None", "moved"]"#);
+}
+
+#[test]
+fn test_flushdb_waits_for_transaction_holding_a_key() {
+    let dir = temp_dir("flushdb-waits-for-transaction");
+    let mut transaction = executor(&dir);
+    let mut observer = transaction.clone();
+    let mut plain = transaction.clone();
+
+    transaction.exec("mset hits 1 other 1").unwrap();
+    transaction.exec("multi").unwrap();
+    transaction.exec("incr hits").unwrap();
+
+    let plain = std::thread::spawn(move || {
+        let flushed = plain.exec("flushdb").unwrap();
+        (plain, flushed)
+    });
+
+    while !observer
+        .exec("locks waiters")
+        .unwrap()
+        .contains("for hits blocked by")
+    {
+        std::thread::yield_now();
+    }
+
+    // the commit is not undone by a flushdb that ran while the key was locked
+    assert_eq!(transaction.exec("exec").unwrap(), "OK");
+    let (mut plain, flushed) = plain.join().unwrap();
+    assert_eq!(flushed, "OK");
+    assert_eq!(plain.exec("dbsize").unwrap(), "0");
+}
+
+#[test]
+fn test_flushdb_survives_restart() {
+    let dir = temp_dir("flushdb-survives-restart");
+    let db_path = dir.join("tyozo.db");
+    let log_path = dir.join("tyozo.log");
+    let mut executor = executor(&dir);
+
+    executor.exec("mset a 1 b 2").unwrap();
+    executor.exec("shutdown").unwrap();
+    executor.exec("flushdb").unwrap();
+    executor.exec("set c 3").unwrap();
+
+    let mut db = Memdb::restore(db_path.to_str().unwrap(), log_path.to_str().unwrap()).unwrap();
+    assert_eq!(
+        db.exec("mget a b c"),
+        Ok(String::from(r#"["None", "None", "3"]"#))
+    );
+    assert_eq!(db.exec("dbsize"), Ok(String::from("1")));
+}
+
+#[test]
+fn test_keys() {
+    let mut db = Memdb::new();
+    db.exec("mset user:1 a user:2 b user:10 c item:1 d")
+        .unwrap();
+    db.exec("set user:3 e px 1").unwrap();
+    std::thread::sleep(Duration::from_millis(5));
+
+    assert_eq!(
+        db.exec("keys user:*"),
+        Ok(String::from(r#"["user:1", "user:10", "user:2"]"#))
+    );
+    assert_eq!(
+        db.exec("keys user:?"),
+        Ok(String::from(r#"["user:1", "user:2"]"#))
+    );
+    assert_eq!(db.exec("keys nothing*"), Ok(String::from("[]")));
+}
+
+#[test]
+fn test_scan_while_keyspace_changes() {
+    let dir = temp_dir("scan");
+    let mut executor = executor(&dir);
+
+    for i in 0..200 {
+        executor.exec(format!("set stable:{} v", i)).unwrap();
+    }
+
+    let mut seen = std::collections::HashSet::new();
+    let mut cursor = String::from("0");
+    let mut calls = 0;
+    loop {
+        let reply = executor
+            .exec(format!("scan {} match stable:* count 15", cursor))
+            .unwrap();
+        let items = reply
+            .trim_matches(|c| c == '[' || c == ']')
+            .split(", ")
+            .map(|item| item.trim_matches('"').to_owned())
+            .collect::<Vec<_>>();
+
+        cursor = items[0].clone();
+        seen.extend(items[1..].iter().cloned());
+
+        // the keyspace grows and shrinks between the calls
+        for i in 0..50 {
+            executor
+                .exec(format!("set grow:{}:{} v", calls, i))
+                .unwrap();
+        }
+        executor.exec(format!("del grow:{}:0", calls)).unwrap();
+
+        calls += 1;
+        if cursor == "0" {
+            break;
+        }
+    }
+
+    assert!(calls > 10, "{}", calls);
+    for i in 0..200 {
+        assert!(seen.contains(&format!("stable:{}", i)), "stable:{}", i);
+    }
+    assert_eq!(seen.len(), 200);
+}
+
+#[test]
+fn test_list_commands() {
+    let dir = temp_dir("list-commands");
+    let mut executor = executor(&dir);
+
+    assert_eq!(executor.exec("rpush list b c d").unwrap(), "3");
+    assert_eq!(executor.exec("lpush list a").unwrap(), "4");
+    assert_eq!(
+        executor.exec("lrange list 0 -1").unwrap(),
+        r#"["a", "b", "c", "d"]"#
+    );
+    assert_eq!(executor.exec("llen list").unwrap(), "4");
+    assert_eq!(executor.exec("lindex list -1").unwrap(), "d");
+    assert_eq!(executor.exec("lindex list 4").unwrap(), "None");
+    assert_eq!(executor.exec("lset list 1 x").unwrap(), "OK");
+    assert_eq!(
+        executor.exec("lset list 4 x").unwrap_err().to_string(),
+        "ERR index out of range"
+    );
+    assert_eq!(executor.exec("lpop list").unwrap(), "a");
+    assert_eq!(executor.exec("rpop list 2").unwrap(), r#"["d", "c"]"#);
+    assert_eq!(executor.exec("type list").unwrap(), "list");
+
+    executor.exec("rpush list y x y x").unwrap();
+    assert_eq!(executor.exec("lrem list -1 x").unwrap(), "1");
+    assert_eq!(
+        executor.exec("lrange list 0 -1").unwrap(),
+        r#"["x", "y", "x", "y"]"#
+    );
+    assert_eq!(executor.exec("ltrim list 1 2").unwrap(), "OK");
+    assert_eq!(executor.exec("lrange list 0 -1").unwrap(), r#"["y", "x"]"#);
+
+    // 空になったリストはキーごと削除される
+    assert_eq!(executor.exec("lrem list 0 x").unwrap(), "1");
+    assert_eq!(executor.exec("rpop list").unwrap(), "y");
+    assert_eq!(executor.exec("exists list").unwrap(), "0");
+    assert_eq!(executor.exec("lpop list").unwrap(), "None");
+    assert_eq!(executor.exec("llen list").unwrap(), "0");
+
+    let wrongtype = "WRONGTYPE Operation against a key holding the wrong kind of value";
+    executor.exec("set string 1").unwrap();
+    executor.exec("rpush list a").unwrap();
+    assert_eq!(
+        executor.exec("lpush string a").unwrap_err().to_string(),
+        wrongtype
+    );
+    assert_eq!(
+        executor.exec("llen string").unwrap_err().to_string(),
+        wrongtype
+    );
+    assert_eq!(
+        executor.exec("get list").unwrap_err().to_string(),
+        wrongtype
+    );
+    assert_eq!(
+        executor.exec("incr list").unwrap_err().to_string(),
+        wrongtype
+    );
+    assert_eq!(
+        executor.exec("append list a").unwrap_err().to_string(),
+        wrongtype
+    );
+    assert_eq!(executor.exec("get string").unwrap(), "1");
+    assert_eq!(executor.exec("lrange list 0 -1").unwrap(), r#"["a"]"#);
+
+    // set は型に関係なく値を上書きする
+    assert_eq!(executor.exec("set list 2").unwrap(), "OK");
+    assert_eq!(executor.exec("get list").unwrap(), "2");
+}
+
+#[test]
+fn test_lists_survive_restart() {
+    let dir = temp_dir("lists-survive-restart");
+    let db_path = dir.join("tyozo.db");
+    let log_path = dir.join("tyozo.log");
+    let mut executor = executor(&dir);
+
+    executor.exec("rpush saved a b").unwrap();
+    executor.exec("set string 1").unwrap();
+    executor.exec("shutdown").unwrap();
+
+    executor.exec("rpush saved c").unwrap();
+    executor.exec("rpush logged \"x y\" z").unwrap();
+    executor.exec("lpop logged").unwrap();
+
+    executor.exec("multi").unwrap();
+    executor.exec("rpush committed 1 2 3").unwrap();
+    executor.exec("lrem committed 1 2").unwrap();
+    executor.exec("expire committed 100").unwrap();
+    executor.exec("exec").unwrap();
+
+    let mut db = Memdb::restore(db_path.to_str().unwrap(), log_path.to_str().unwrap()).unwrap();
+    assert_eq!(
+        db.exec("lrange saved 0 -1"),
+        Ok(String::from(r#"["a", "b", "c"]"#))
+    );
+    assert_eq!(db.exec("lrange logged 0 -1"), Ok(String::from(r#"["z"]"#)));
+    assert_eq!(
+        db.exec("lrange committed 0 -1"),
+        Ok(String::from(r#"["1", "3"]"#))
+    );
+    assert!(db.exec("ttl committed").unwrap().parse::<i64>().unwrap() > 0);
+    assert_eq!(db.exec("get string"), Ok(String::from("1")));
+}
+
+// `count` 個の client が blocking pop で待ち始めるまで待つ
+fn wait_until_blocked(executor: &mut Executor, count: usize) {
+    let expected = format!("blocked_clients:{}", count);
+    while !executor.exec("info").unwrap().contains(&expected) {
+        std::thread::yield_now();
+    }
+}
+
+#[test]
+fn test_blocking_pop_without_waiting() {
+    let dir = temp_dir("blocking-pop-without-waiting");
+    let mut executor = executor(&dir);
+
+    executor.exec("rpush b 1 2").unwrap();
+    assert_eq!(executor.exec("blpop a b 0").unwrap(), r#"["b", "1"]"#);
+    assert_eq!(executor.exec("brpop a b 0").unwrap(), r#"["b", "2"]"#);
+
+    let started = std::time::Instant::now();
+    assert_eq!(executor.exec("blpop a b 0.1").unwrap(), "None");
+    assert!(started.elapsed() >= Duration::from_millis(100));
+    assert!(executor.exec("info").unwrap().contains("blocked_clients:0"));
+    assert_eq!(
+        executor
+            .exec("blpop a 10000000000000000000")
+            .unwrap_err()
+            .to_string(),
+        "ERR timeout is out of range"
+    );
+
+    executor.exec("set string 1").unwrap();
+    let logged = std::fs::read(dir.join("tyozo.log")).unwrap().len();
+    assert_eq!(
+        executor.exec("blpop a string 0").unwrap_err().to_string(),
+        "WRONGTYPE Operation against a key holding the wrong kind of value"
+    );
+    assert_eq!(std::fs::read(dir.join("tyozo.log")).unwrap().len(), logged);
+
+    // transactionの中では待たずに結果を返す
+    executor.exec("multi").unwrap();
+    assert_eq!(executor.exec("blpop a 0").unwrap(), "None");
+    executor.exec("exec").unwrap();
+}
+
+#[test]
+fn test_blocking_pops_are_served_in_fifo_order() {
+    let dir = temp_dir("blocking-pop-fifo");
+    let mut executor = executor(&dir);
+
+    let mut waiters = vec![];
+    for i in 0..3 {
+        let mut waiter = executor.clone();
+        waiters.push(std::thread::spawn(move || {
+            waiter.exec("blpop queue 0").unwrap()
+        }));
+        wait_until_blocked(&mut executor, i + 1);
+    }
+
+    // 1度の push で複数の要素が追加されても、待ち始めた順に1つずつ渡される
+    executor.exec("rpush queue a b").unwrap();
+    assert_eq!(waiters.remove(0).join().unwrap(), r#"["queue", "a"]"#);
+    assert_eq!(waiters.remove(0).join().unwrap(), r#"["queue", "b"]"#);
+    wait_until_blocked(&mut executor, 1);
+
+    executor.exec("lpush queue c").unwrap();
+    assert_eq!(waiters.remove(0).join().unwrap(), r#"["queue", "c"]"#);
+    assert_eq!(executor.exec("exists queue").unwrap(), "0");
+}
+
+#[test]
+fn test_blocking_pop_times_out_behind_transaction_lock() {
+    let dir = temp_dir("blocking-pop-lock-timeout");
+    let mut executor = executor(&dir);
+    let mut transaction = executor.clone();
+
+    transaction.exec("multi").unwrap();
+    transaction.exec("get q").unwrap();
+
+    // the lock on "q" is held past the timeout, which ends the pop like an empty list would
+    let started = Instant::now();
+    assert_eq!(executor.exec("blpop q 0.1").unwrap(), "None");
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert!(executor.exec("info").unwrap().contains("blocked_clients:0"));
+
+    transaction.exec("abort").unwrap();
+    executor.exec("rpush q 1").unwrap();
+    assert_eq!(executor.exec("blpop q 0.1").unwrap(), r#"["q", "1"]"#);
+}
+
+#[test]
+fn test_blocking_pop_woken_by_committed_transaction() {
+    let dir = temp_dir("blocking-pop-transaction");
+    let mut executor = executor(&dir);
+    let mut other = executor.clone();
+
+    let mut waiter = executor.clone();
+    let blocked = std::thread::spawn(move || waiter.exec("brpop jobs 0").unwrap());
+    wait_until_blocked(&mut other, 1);
+
+    executor.exec("multi").unwrap();
+    executor.exec("rpush jobs 1").unwrap();
+    std::thread::sleep(Duration::from_millis(50));
+    assert!(other.exec("info").unwrap().contains("blocked_clients:1"));
+    executor.exec("exec").unwrap();
+    assert_eq!(blocked.join().unwrap(), r#"["jobs", "1"]"#);
+
+    let mut waiter = executor.clone();
+    let blocked = std::thread::spawn(move || waiter.exec("blpop jobs 0").unwrap());
+    wait_until_blocked(&mut other, 1);
+
+    executor.exec("multi queued").unwrap();
+    executor.exec("rpush jobs 2").unwrap();
+    executor.exec("exec").unwrap();
+    assert_eq!(blocked.join().unwrap(), r#"["jobs", "2"]"#);
+
+    let mut waiter = executor.clone();
+    let blocked = std::thread::spawn(move || waiter.exec("blpop jobs 0").unwrap());
+    wait_until_blocked(&mut other, 1);
+
+    executor.exec("multi snapshot").unwrap();
+    executor.exec("rpush jobs 3").unwrap();
+    executor.exec("exec").unwrap();
+    assert_eq!(blocked.join().unwrap(), r#"["jobs", "3"]"#);
+}
+
+#[test]
+fn test_blmove() {
+    let dir = temp_dir("blmove");
+    let mut executor = executor(&dir);
+
+    let mut waiter = executor.clone();
+    let blocked =
+        std::thread::spawn(move || waiter.exec("blmove jobs working right left 0").unwrap());
+    wait_until_blocked(&mut executor, 1);
+
+    // 移された要素を待っている client も起こされる
+    let mut next = executor.clone();
+    let chained = std::thread::spawn(move || next.exec("blpop working 0").unwrap());
+    wait_until_blocked(&mut executor, 2);
+
+    executor.exec("rpush jobs a b").unwrap();
+    assert_eq!(blocked.join().unwrap(), "b");
+    assert_eq!(chained.join().unwrap(), r#"["working", "b"]"#);
+
+    assert_eq!(executor.exec("blmove jobs jobs left right 0").unwrap(), "a");
+    executor.exec("rpush jobs c").unwrap();
+    assert_eq!(executor.exec("blmove jobs jobs left right 0").unwrap(), "a");
+    assert_eq!(executor.exec("lrange jobs 0 -1").unwrap(), r#"["c", "a"]"#);
+
+    executor.exec("set string 1").unwrap();
+    assert!(executor.exec("blmove jobs string left left 0").is_err());
+    assert_eq!(executor.exec("llen jobs").unwrap(), "2");
+
+    let mut replayed = Memdb::restore(
+        dir.join("empty.db").to_str().unwrap(),
+        dir.join("tyozo.log").to_str().unwrap(),
+    )
+    .unwrap();
+    assert_eq!(
+        replayed.exec("lrange jobs 0 -1"),
+        Ok(String::from(r#"["c", "a"]"#))
+    );
+    assert_eq!(replayed.exec("exists working"), Ok(String::from("0")));
+}
+
+// executor に送るために、引数を escape して double quote で囲む
+fn quoted(arg: &str) -> String {
+    let escaped = arg
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r");
+    format!("\"{}\"", escaped)
+}
+
+#[test]
+fn test_log_replays_arbitrary_values() {
+    let dir = temp_dir("log-arbitrary-values");
+    let mut executor = executor(&dir);
+
+    let values = [
+        "#tag",
+        "_id",
+        "/tmp/x",
+        "@me",
+        "{job}",
+        "日本語",
+        "say \"hi\"",
+        "back\\slash",
+        "two\nlines",
+        "cr\r",
+        "",
+    ];
+    for (i, value) in values.iter().enumerate() {
+        executor
+            .exec(format!("set {} {}", quoted(value), quoted(value)))
+            .unwrap();
+        executor
+            .exec(format!("rpush list{} {}", i, quoted(value)))
+            .unwrap();
+    }
+
+    let mut replayed = Memdb::restore(
+        dir.join("empty.db").to_str().unwrap(),
+        dir.join("tyozo.log").to_str().unwrap(),
+    )
+    .unwrap();
+    for (i, value) in values.iter().enumerate() {
+        assert_eq!(replayed.get(value), Some(value.as_bytes().to_vec()));
+        assert_eq!(
+            replayed.exec(format!("lindex list{} 0", i)),
+            Ok(value.to_string())
+        );
+    }
+}
+
+#[test]
+fn test_restore_rejects_unreadable_log() {
+    let dir = temp_dir("restore-unreadable-log");
+    let log_path = dir.join("tyozo.log");
+    let db_path = dir.join("tyozo.db");
+
+    // 書き込み途中で止まった最後の行は捨てられる
+    std::fs::write(&log_path, "set a 1\nset b \"unterminated").unwrap();
+    let db = Memdb::restore(db_path.to_str().unwrap(), log_path.to_str().unwrap()).unwrap();
+    assert_eq!(db.get("a"), Some(b"1".to_vec()));
+    assert_eq!(db.get("b"), None);
+
+    std::fs::write(&log_path, "set a 1\nset b \"unterminated\nset c 3\n").unwrap();
+    let result = Memdb::restore(db_path.to_str().unwrap(), log_path.to_str().unwrap());
+    assert!(result.unwrap_err().to_string().contains("line 2"));
+    assert!(!std::fs::read(&log_path).unwrap().is_empty());
+}